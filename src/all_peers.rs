@@ -3,7 +3,7 @@
 macro_rules! list_of_all_specifier_classes {
     ($your_macro:ident) => {
         $your_macro!($crate::ws_client_peer::WsClientClass);
-        #[cfg(feature = "ssl")]
+        #[cfg(any(feature = "ssl", feature = "rustls"))]
         $your_macro!($crate::ws_client_peer::WsClientSecureClass);
         $your_macro!($crate::ws_server_peer::WsTcpServerClass);
         $your_macro!($crate::ws_server_peer::WsInetdServerClass);
@@ -13,12 +13,15 @@ macro_rules! list_of_all_specifier_classes {
         $your_macro!($crate::ws_lowlevel_peer::WsLlClientClass);
         $your_macro!($crate::ws_lowlevel_peer::WsLlServerClass);
 
-        #[cfg(feature = "ssl")]
-        $your_macro!($crate::ssl_peer::WssListenClass);
+        #[cfg(any(feature = "ssl", feature = "rustls"))]
+        $your_macro!($crate::tls_peer::WssListenClass);
 
         $your_macro!($crate::http_peer::HttpRequestClass);
         $your_macro!($crate::http_peer::HttpClass);
         $your_macro!($crate::http_peer::HttpPostSseClass);
+        $your_macro!($crate::sse_client_peer::SseClientClass);
+        $your_macro!($crate::webhook_peer::WebhookClass);
+        $your_macro!($crate::longpoll_peer::HttpLongPollClass);
         
 
         #[cfg(all(unix, feature = "unix_stdio"))]
@@ -31,12 +34,12 @@ macro_rules! list_of_all_specifier_classes {
         $your_macro!($crate::net_peer::TcpConnectClass);
         $your_macro!($crate::net_peer::TcpListenClass);
 
-        #[cfg(feature = "ssl")]
-        $your_macro!($crate::ssl_peer::TlsConnectClass);
-        #[cfg(feature = "ssl")]
-        $your_macro!($crate::ssl_peer::TlsAcceptClass);
-        #[cfg(feature = "ssl")]
-        $your_macro!($crate::ssl_peer::TlsListenClass);
+        #[cfg(any(feature = "ssl", feature = "rustls"))]
+        $your_macro!($crate::tls_peer::TlsConnectClass);
+        #[cfg(any(feature = "ssl", feature = "rustls"))]
+        $your_macro!($crate::tls_peer::TlsAcceptClass);
+        #[cfg(any(feature = "ssl", feature = "rustls"))]
+        $your_macro!($crate::tls_peer::TlsListenClass);
 
         #[cfg(feature = "tokio-process")]
         $your_macro!($crate::process_peer::ShCClass);
@@ -57,11 +60,14 @@ macro_rules! list_of_all_specifier_classes {
 
         $your_macro!($crate::net_peer::UdpConnectClass);
         $your_macro!($crate::net_peer::UdpListenClass);
+        $your_macro!($crate::net_peer::UdpServerClass);
 
         #[cfg(all(unix, feature = "unix_stdio"))]
         $your_macro!($crate::stdio_peer::OpenAsyncClass);
         #[cfg(all(unix, feature = "unix_stdio"))]
         $your_macro!($crate::stdio_peer::OpenFdAsyncClass);
+        #[cfg(unix)]
+        $your_macro!($crate::serial_peer::SerialClass);
 
         $your_macro!($crate::stdio_threaded_peer::ThreadedStdioClass);
         $your_macro!($crate::stdio_threaded_peer::StdioClass);
@@ -71,6 +77,8 @@ macro_rules! list_of_all_specifier_classes {
         #[cfg(unix)]
         $your_macro!($crate::unix_peer::UnixListenClass);
         #[cfg(unix)]
+        $your_macro!($crate::listen_fd_peer::ListenFdClass);
+        #[cfg(unix)]
         $your_macro!($crate::unix_peer::UnixDgramClass);
         #[cfg(unix)]
         $your_macro!($crate::unix_peer::AbstractConnectClass);
@@ -101,6 +109,11 @@ macro_rules! list_of_all_specifier_classes {
         $your_macro!($crate::unix_peer::unix_seqpacket_peer::SeqpacketListenClass);
 
         $your_macro!($crate::jsonrpc_peer::JsonRpcClass);
+        $your_macro!($crate::socketio_peer::SocketIoClass);
+        $your_macro!($crate::graphql_peer::GraphQlClass);
+        $your_macro!($crate::stomp_peer::StompClass);
+        $your_macro!($crate::exec_channel_peer::K8sExecClass);
+        $your_macro!($crate::exec_channel_peer::TtydClass);
         $your_macro!($crate::timestamp_peer::TimestampClass);
 
         $your_macro!($crate::socks5_peer::SocksProxyClass);
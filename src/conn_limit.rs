@@ -0,0 +1,135 @@
+//! `--conncap-per-ip` and `--conn-rate-per-ip`: limit connections from a single client address
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::Options;
+
+/// HTTP status for WebSocket clients over `--conncap`
+pub const STATUS_SERVICE_UNAVAILABLE: u16 = 503;
+/// HTTP status for WebSocket clients over per-IP limits
+pub const STATUS_TOO_MANY_REQUESTS: u16 = 429;
+
+/// How often to forget addresses without connections and with a full token bucket
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+struct PerIp {
+    conns: usize,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+struct State {
+    per_ip: HashMap<IpAddr, PerIp>,
+    last_prune: Instant,
+}
+
+pub struct ConnLimiter {
+    max_conns: Option<usize>,
+    /// New connections per second and bucket size
+    rate: Option<(f64, f64)>,
+    state: RefCell<State>,
+}
+
+/// Holds a connection slot of a client until dropped
+pub struct ConnSlot {
+    limiter: Rc<ConnLimiter>,
+    ip: IpAddr,
+}
+
+impl ConnLimiter {
+    pub fn new(opts: &Options) -> ConnLimiter {
+        ConnLimiter {
+            max_conns: opts.max_parallel_conns_per_ip,
+            rate: opts.conn_rate_per_ip.map(|r| {
+                let burst = opts.conn_burst_per_ip.map_or(r.ceil().max(1.0), f64::from);
+                (r, burst)
+            }),
+            state: RefCell::new(State {
+                per_ip: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.max_conns.is_some() || self.rate.is_some()
+    }
+
+    fn refill(&self, s: &mut PerIp, now: Instant) {
+        if let Some((rate, burst)) = self.rate {
+            let elapsed = now.duration_since(s.last_refill).as_secs_f64();
+            s.tokens = (s.tokens + elapsed * rate).min(burst);
+        }
+        s.last_refill = now;
+    }
+
+    /// Take a slot for new connection from `ip`. `Err` is the reason to reject it.
+    pub fn acquire(self: &Rc<Self>, ip: IpAddr) -> Result<ConnSlot, &'static str> {
+        let now = Instant::now();
+        let mut state = self.state.borrow_mut();
+        if now.duration_since(state.last_prune) > PRUNE_INTERVAL {
+            state.last_prune = now;
+            let full = self.rate.map_or(0.0, |(_, burst)| burst);
+            state.per_ip.retain(|_, s| {
+                self.refill(s, now);
+                s.conns > 0 || s.tokens < full
+            });
+        }
+        let s = state.per_ip.entry(ip).or_insert_with(|| PerIp {
+            conns: 0,
+            tokens: self.rate.map_or(0.0, |(_, burst)| burst),
+            last_refill: now,
+        });
+        if let Some(max) = self.max_conns {
+            if s.conns >= max {
+                return Err("too many connections from this address (--conncap-per-ip)");
+            }
+        }
+        if self.rate.is_some() {
+            self.refill(s, now);
+            if s.tokens < 1.0 {
+                return Err("connecting too often (--conn-rate-per-ip)");
+            }
+            s.tokens -= 1.0;
+        }
+        s.conns += 1;
+        Ok(ConnSlot {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnSlot {
+    fn drop(&mut self) {
+        if let Some(s) = self.limiter.state.borrow_mut().per_ip.get_mut(&self.ip) {
+            s.conns -= 1;
+        }
+    }
+}
+
+#[test]
+fn test_conn_limiter() {
+    let opts = Options {
+        max_parallel_conns_per_ip: Some(2),
+        conn_rate_per_ip: Some(0.001),
+        conn_burst_per_ip: Some(3),
+        ..Default::default()
+    };
+    let l = Rc::new(ConnLimiter::new(&opts));
+    let a: IpAddr = "192.0.2.1".parse().unwrap();
+    let b: IpAddr = "192.0.2.2".parse().unwrap();
+    let s1 = l.acquire(a).unwrap();
+    let _s2 = l.acquire(a).unwrap();
+    assert!(l.acquire(a).is_err());
+    let _s3 = l.acquire(b).unwrap();
+    drop(s1);
+    let _s4 = l.acquire(a).unwrap();
+    drop(_s4);
+    // Burst of 3 is spent
+    assert!(l.acquire(a).is_err());
+}
@@ -0,0 +1,450 @@
+#[cfg(all(unix, feature = "signal_handler"))]
+extern crate tokio_signal;
+
+use futures::future::ok;
+use futures::{Async, Stream};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::{BoxedNewPeerFuture, Peer};
+use super::util::QueuedWriter;
+use super::{ConstructParams, Options, PeerConstructor, Specifier};
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use serde_json::{json, Value};
+
+/// Session error for `--remote-exit-code` when remote command reported by `k8s-exec:` overlay has failed
+#[derive(Debug)]
+pub struct RemoteExitCode(pub i32);
+
+impl std::fmt::Display for RemoteExitCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Remote command exited with code {}", self.0)
+    }
+}
+impl std::error::Error for RemoteExitCode {}
+
+/// Extract exit code from session error caused by `RemoteExitCode`
+pub fn remote_exit_code(e: &(dyn std::error::Error + 'static)) -> Option<i32> {
+    let e = e.downcast_ref::<IoError>()?.get_ref()?;
+    e.downcast_ref::<RemoteExitCode>().map(|x| x.0)
+}
+
+/// Set subprotocol (unless overridden by `--protocol`) and binary mode for inner WebSocket
+fn inner_cp(mut cp: ConstructParams, protocol: &str) -> ConstructParams {
+    let mut opts = (*cp.program_options).clone();
+    if opts.websocket_protocol.is_none() {
+        opts.websocket_protocol = Some(protocol.to_owned());
+    }
+    opts.websocket_text_mode = false;
+    cp.program_options = Rc::new(opts);
+    cp
+}
+
+#[derive(Debug)]
+pub struct K8sExec<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for K8sExec<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let opts = cp.program_options.clone();
+        let inner = self.0.construct(inner_cp(cp, "v4.channel.k8s.io"));
+        inner.map(move |p, _| exec_channel_peer(p, Flavor::K8s, &opts))
+    }
+    specifier_boilerplate!(noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = K8sExecClass,
+    target = K8sExec,
+    prefixes = ["k8s-exec:", "kubectl-exec:"],
+    arg_handling = subspec,
+    overlay = true,
+    MessageOriented,
+    MulticonnectnessDependsOnInnerType,
+    help = r#"
+[A] Speak Kubernetes `v4.channel.k8s.io` exec/attach protocol over inner WebSocket connection.
+
+Each WebSocket message is prefixed by a channel byte. Written data is sent to stdin channel,
+stdout channel is returned as messages, stderr channel is printed to websocat's stderr.
+Status from error channel is logged and, with `--remote-exit-code`, becomes websocat's exit code.
+Local terminal size is sent on resize channel initially and when it changes (SIGWINCH).
+
+Requests `v4.channel.k8s.io` subprotocol (unless `--protocol` is specified) and uses binary messages.
+There is no way to signal end of input in this protocol version, so websocat waits for the
+remote command to finish instead.
+
+Example:
+
+    websocat -b -H "Authorization: Bearer $TOKEN" - 'k8s-exec:wss://127.0.0.1:6443/api/v1/namespaces/default/pods/mypod/exec?command=sh&stdin=true&stdout=true&stderr=true'
+"#
+);
+
+#[derive(Debug)]
+pub struct Ttyd<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for Ttyd<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let opts = cp.program_options.clone();
+        let inner = self.0.construct(inner_cp(cp, "tty"));
+        inner.map(move |p, _| exec_channel_peer(p, Flavor::Ttyd, &opts))
+    }
+    specifier_boilerplate!(noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = TtydClass,
+    target = Ttyd,
+    prefixes = ["ttyd:"],
+    arg_handling = subspec,
+    overlay = true,
+    MessageOriented,
+    MulticonnectnessDependsOnInnerType,
+    help = r#"
+[A] Speak ttyd protocol over inner WebSocket connection.
+
+Sends initial terminal size, then prefixes written data with input command byte
+and returns terminal output. Local terminal size changes (SIGWINCH) are forwarded.
+Window title and preferences messages are ignored.
+
+Requests `tty` subprotocol (unless `--protocol` is specified) and uses binary messages.
+
+Example:
+
+    stty raw -echo; websocat -b - ttyd:ws://127.0.0.1:7681/ws; stty sane
+"#
+);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Flavor {
+    K8s,
+    Ttyd,
+}
+
+enum Channel {
+    Stdout,
+    Stderr,
+    Status,
+    Unknown,
+}
+
+impl Flavor {
+    fn stdin_prefix(self) -> u8 {
+        match self {
+            Flavor::K8s => 0,
+            Flavor::Ttyd => b'0',
+        }
+    }
+
+    fn channel(self, c: u8) -> Channel {
+        match (self, c) {
+            (Flavor::K8s, 1) => Channel::Stdout,
+            (Flavor::K8s, 2) => Channel::Stderr,
+            (Flavor::K8s, 3) => Channel::Status,
+            (Flavor::Ttyd, b'0') => Channel::Stdout,
+            _ => Channel::Unknown,
+        }
+    }
+
+    fn resize_message(self, cols: u16, rows: u16) -> Vec<u8> {
+        match self {
+            Flavor::K8s => {
+                let mut v = vec![4];
+                v.extend(json!({"Width": cols, "Height": rows}).to_string().into_bytes());
+                v
+            }
+            Flavor::Ttyd => {
+                let mut v = vec![b'1'];
+                v.extend(json!({"columns": cols, "rows": rows}).to_string().into_bytes());
+                v
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn terminal_size() -> Option<(u16, u16)> {
+    extern crate libc;
+    for fd in &[1, 0, 2] {
+        let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(*fd, libc::TIOCGWINSZ, &mut ws) } == 0 && ws.ws_col > 0 {
+            return Some((ws.ws_col, ws.ws_row));
+        }
+    }
+    None
+}
+
+#[cfg(not(unix))]
+fn terminal_size() -> Option<(u16, u16)> {
+    None
+}
+
+#[cfg(all(unix, feature = "signal_handler"))]
+fn resize_signals() -> Option<Box<dyn Stream<Item = (), Error = IoError>>> {
+    use futures::Future;
+    extern crate libc;
+    let s = tokio_signal::unix::Signal::new(libc::SIGWINCH).flatten_stream().map(|_| ());
+    Some(Box::new(s))
+}
+
+#[cfg(not(all(unix, feature = "signal_handler")))]
+fn resize_signals() -> Option<Box<dyn Stream<Item = (), Error = IoError>>> {
+    None
+}
+
+/// Interpret Kubernetes `Status` object from error channel as an exit code
+fn status_exit_code(status: &Value) -> i32 {
+    if status["status"] == "Success" {
+        return 0;
+    }
+    if status["reason"] == "NonZeroExitCode" {
+        if let Some(causes) = status["details"]["causes"].as_array() {
+            for c in causes {
+                if c["reason"] == "ExitCode" {
+                    if let Some(code) = c["message"].as_str().and_then(|x| x.parse().ok()) {
+                        return code;
+                    }
+                }
+            }
+        }
+    }
+    1
+}
+
+fn exec_channel_peer(inner_peer: Peer, flavor: Flavor, opts: &Options) -> BoxedNewPeerFuture {
+    let (r, w, hup) = (inner_peer.0, inner_peer.1, inner_peer.2);
+    let mut w = QueuedWriter::new(w);
+    let size = terminal_size();
+    match flavor {
+        Flavor::K8s => {
+            if let Some((cols, rows)) = size {
+                w.queue.push_back(flavor.resize_message(cols, rows));
+            }
+        }
+        Flavor::Ttyd => {
+            let (cols, rows) = size.unwrap_or((80, 24));
+            let init = json!({"AuthToken": "", "columns": cols, "rows": rows});
+            w.queue.push_back(init.to_string().into_bytes());
+        }
+    }
+    let shared = Rc::new(RefCell::new(w));
+    let rd = ExecChannelReader {
+        r,
+        shared: shared.clone(),
+        buf: vec![0; opts.buffer_size],
+        flavor,
+        resizes: if size.is_some() { resize_signals() } else { None },
+        exit_code: None,
+        report_exit_code: opts.remote_exit_code,
+    };
+    let wr = ExecChannelWriter {
+        shared,
+        prefix: flavor.stdin_prefix(),
+    };
+    Box::new(ok(Peer::new(rd, wr, hup))) as BoxedNewPeerFuture
+}
+
+struct ExecChannelReader {
+    r: Box<dyn AsyncRead>,
+    shared: Rc<RefCell<QueuedWriter>>,
+    buf: Vec<u8>,
+    flavor: Flavor,
+    resizes: Option<Box<dyn Stream<Item = (), Error = IoError>>>,
+    /// Nonzero code from status channel, to be reported when the connection closes
+    exit_code: Option<i32>,
+    report_exit_code: bool,
+}
+
+impl Read for ExecChannelReader {
+    fn read(&mut self, b: &mut [u8]) -> Result<usize, IoError> {
+        loop {
+            self.shared.borrow_mut().flush_ignoring_wouldblock()?;
+            if let Some(ref mut s) = self.resizes {
+                match s.poll() {
+                    Ok(Async::Ready(Some(()))) => {
+                        if let Some((cols, rows)) = terminal_size() {
+                            debug!("Terminal resized to {}x{}", cols, rows);
+                            let m = self.flavor.resize_message(cols, rows);
+                            self.shared.borrow_mut().queue.push_back(m);
+                        }
+                        continue;
+                    }
+                    Ok(Async::Ready(None)) => self.resizes = None,
+                    Ok(Async::NotReady) => (),
+                    Err(e) => {
+                        warn!("Failed to watch for terminal size changes: {}", e);
+                        self.resizes = None;
+                    }
+                }
+            }
+            let rr = self.r.read(&mut self.buf[..]);
+            let closed = match rr {
+                Ok(0) => true,
+                Err(ref e) => e.kind() == std::io::ErrorKind::BrokenPipe,
+                Ok(_) => false,
+            };
+            if closed && self.report_exit_code {
+                if let Some(code) = self.exit_code {
+                    return Err(IoError::other(RemoteExitCode(code)));
+                }
+            }
+            let n = rr?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let payload = &self.buf[1..n];
+            match self.flavor.channel(self.buf[0]) {
+                Channel::Stdout => {
+                    if payload.is_empty() {
+                        continue;
+                    }
+                    let l = payload.len().min(b.len());
+                    if l < payload.len() {
+                        warn!("Message is too big for the buffer, truncating it");
+                    }
+                    b[..l].copy_from_slice(&payload[..l]);
+                    return Ok(l);
+                }
+                Channel::Stderr => {
+                    let mut e = std::io::stderr();
+                    e.write_all(payload)?;
+                    e.flush()?;
+                }
+                Channel::Status => {
+                    if payload.is_empty() {
+                        continue;
+                    }
+                    let status: Value = match serde_json::from_slice(payload) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            warn!("Malformed status message: {}", e);
+                            continue;
+                        }
+                    };
+                    let code = status_exit_code(&status);
+                    if code == 0 {
+                        info!("Remote command succeeded");
+                    } else if status["reason"] == "NonZeroExitCode" {
+                        info!("Remote command exited with code {}", code);
+                    } else {
+                        error!("Remote command failed: {}", status["message"].as_str().unwrap_or(""));
+                    }
+                    if code != 0 {
+                        self.exit_code = Some(code);
+                    }
+                }
+                Channel::Unknown => {
+                    debug!("Ignoring message on channel {}", self.buf[0]);
+                }
+            }
+        }
+    }
+}
+impl AsyncRead for ExecChannelReader {}
+
+struct ExecChannelWriter {
+    shared: Rc<RefCell<QueuedWriter>>,
+    prefix: u8,
+}
+
+impl Write for ExecChannelWriter {
+    fn write(&mut self, b: &[u8]) -> Result<usize, IoError> {
+        let mut w = self.shared.borrow_mut();
+        if !w.queue.is_empty() {
+            w.flush_queue()?;
+        }
+        if b.is_empty() {
+            return Ok(0);
+        }
+        let mut msg = Vec::with_capacity(b.len() + 1);
+        msg.push(self.prefix);
+        msg.extend_from_slice(b);
+        w.queue.push_back(msg);
+        w.flush_ignoring_wouldblock()?;
+        Ok(b.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.shared.borrow_mut().flush_queue()
+    }
+}
+impl AsyncWrite for ExecChannelWriter {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        match self.shared.borrow_mut().flush_queue() {
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            x => x?,
+        }
+        // Remote side closes the connection when the command finishes
+        Ok(Async::Ready(()))
+    }
+}
+
+#[test]
+fn test_status_exit_code() {
+    let s = json!({"metadata":{},"status":"Success"});
+    assert_eq!(status_exit_code(&s), 0);
+    let s = json!({"metadata":{},"status":"Failure","message":"command terminated with non-zero exit code",
+        "reason":"NonZeroExitCode","details":{"causes":[{"reason":"ExitCode","message":"42"}]}});
+    assert_eq!(status_exit_code(&s), 42);
+    let s = json!({"status":"Failure","message":"container not found","reason":"NotFound"});
+    assert_eq!(status_exit_code(&s), 1);
+}
+
+#[cfg(test)]
+struct TestMessages(std::collections::VecDeque<Vec<u8>>);
+#[cfg(test)]
+impl Read for TestMessages {
+    fn read(&mut self, b: &mut [u8]) -> Result<usize, IoError> {
+        let m = self.0.pop_front().unwrap_or_default();
+        b[..m.len()].copy_from_slice(&m);
+        Ok(m.len())
+    }
+}
+#[cfg(test)]
+impl AsyncRead for TestMessages {}
+
+#[cfg(test)]
+fn test_reader(flavor: Flavor, msgs: &[&[u8]]) -> ExecChannelReader {
+    ExecChannelReader {
+        r: Box::new(TestMessages(msgs.iter().map(|x| x.to_vec()).collect())),
+        shared: Rc::new(RefCell::new(QueuedWriter::new(Box::new(std::io::sink())))),
+        buf: vec![0; 1024],
+        flavor,
+        resizes: None,
+        exit_code: None,
+        report_exit_code: true,
+    }
+}
+
+#[test]
+fn test_channel_demux() {
+    let next = |rd: &mut ExecChannelReader| {
+        let mut b = [0u8; 1024];
+        rd.read(&mut b).map(|n| b[..n].to_vec())
+    };
+    let mut status = vec![3];
+    status.extend(json!({"status":"Failure","reason":"NonZeroExitCode",
+        "details":{"causes":[{"reason":"ExitCode","message":"3"}]}}).to_string().into_bytes());
+    let mut rd = test_reader(Flavor::K8s, &[b"\x01out1", b"\x02err", b"\x01", &status, b"\x09x", b"\x01out2"]);
+    assert_eq!(next(&mut rd).unwrap(), b"out1");
+    assert_eq!(next(&mut rd).unwrap(), b"out2");
+    let e = next(&mut rd).unwrap_err();
+    assert_eq!(e.get_ref().unwrap().downcast_ref::<RemoteExitCode>().unwrap().0, 3);
+
+    let mut rd = test_reader(Flavor::K8s, &[b"\x01out", b"\x03{\"status\":\"Success\"}"]);
+    assert_eq!(next(&mut rd).unwrap(), b"out");
+    assert!(next(&mut rd).unwrap().is_empty());
+
+    let mut rd = test_reader(Flavor::Ttyd, &[b"1title", b"0out", b"2{}"]);
+    assert_eq!(next(&mut rd).unwrap(), b"out");
+    assert!(next(&mut rd).unwrap().is_empty());
+}
+
+#[test]
+fn test_resize_message() {
+    let m = Flavor::K8s.resize_message(120, 40);
+    assert_eq!(m[0], 4);
+    assert_eq!(serde_json::from_slice::<Value>(&m[1..]).unwrap(), json!({"Width": 120, "Height": 40}));
+    let m = Flavor::Ttyd.resize_message(120, 40);
+    assert_eq!(m[0], b'1');
+    assert_eq!(serde_json::from_slice::<Value>(&m[1..]).unwrap(), json!({"columns": 120, "rows": 40}));
+}
@@ -0,0 +1,313 @@
+use futures::future::{err, loop_fn, Future, Loop};
+use futures::task::Task;
+use futures::Async;
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use super::{box_up_err, simple_err2, wouldblock, BoxedNewPeerFuture, Peer};
+use super::util::QueuedWriter;
+use super::{ConstructParams, Options, PeerConstructor, Specifier};
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use serde_json::{json, Value};
+
+#[derive(Debug)]
+pub struct GraphQl<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for GraphQl<T> {
+    fn construct(&self, mut cp: ConstructParams) -> PeerConstructor {
+        if cp.program_options.websocket_protocol.is_none() {
+            let mut opts = (*cp.program_options).clone();
+            opts.websocket_protocol = Some("graphql-transport-ws".to_owned());
+            cp.program_options = Rc::new(opts);
+        }
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p, _| graphql_peer(p, cp.program_options.clone()))
+    }
+    specifier_boilerplate!(noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = GraphQlClass,
+    target = GraphQl,
+    prefixes = ["graphql:", "graphql-ws:"],
+    arg_handling = subspec,
+    overlay = true,
+    MessageOriented,
+    MulticonnectnessDependsOnInnerType,
+    help = r#"
+[A] Speak `graphql-transport-ws` protocol (GraphQL over WebSocket) over inner WebSocket connection.
+
+Requests `graphql-transport-ws` subprotocol (unless `--protocol` is specified),
+sends `connection_init` (with `--graphql-init-payload`, if any) and waits for `connection_ack`.
+
+Each written message becomes a `subscribe` operation with a unique id. Message can be
+either a JSON object like `{"query":"...","variables":{}}` or just a GraphQL query text.
+Payloads of `next` messages are returned as is, `error` messages are returned as `{"errors":[...]}`.
+Pings are answered automatically. After input is finished, it waits for all operations to complete.
+
+Example:
+
+    websocat -t - graphql:ws://127.0.0.1:4000/graphql
+
+    > subscription { messageAdded { text } }
+    < {"data":{"messageAdded":{"text":"hello"}}}
+"#
+);
+
+struct Shared {
+    w: QueuedWriter,
+    next_id: u64,
+    /// Subscriptions that are not completed yet
+    active: HashSet<String>,
+    input_finished: bool,
+    reader_task: Option<Task>,
+}
+
+/// Interpret user's message as `subscribe` payload
+fn subscribe_payload(msg: &str) -> Value {
+    let msg = msg.trim();
+    if msg.starts_with('{') {
+        if let Ok(v @ Value::Object(_)) = serde_json::from_str(msg) {
+            if v.get("query").is_some() {
+                return v;
+            }
+        }
+    }
+    json!({ "query": msg })
+}
+
+fn subscribe_message(id: &str, msg: &str) -> Vec<u8> {
+    let subscribe = json!({
+        "id": id,
+        "type": "subscribe",
+        "payload": subscribe_payload(msg),
+    });
+    subscribe.to_string().into_bytes()
+}
+
+/// Message received from GraphQL server after `connection_ack`
+#[derive(Debug, PartialEq)]
+enum ServerMessage {
+    /// Data to be returned to user, with id of operation if it has failed
+    Payload(Vec<u8>, Option<String>),
+    Complete(String),
+    Ping,
+    Ignore,
+}
+
+fn parse_server_message(buf: &[u8]) -> ServerMessage {
+    let msg: Value = match serde_json::from_slice(buf) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Ignoring malformed GraphQL message: {}", e);
+            return ServerMessage::Ignore;
+        }
+    };
+    let id = msg["id"].as_str().unwrap_or("").to_owned();
+    match msg["type"].as_str().unwrap_or("") {
+        "next" => ServerMessage::Payload(msg["payload"].to_string().into_bytes(), None),
+        "error" => {
+            warn!("GraphQL operation {} failed", id);
+            let reply = json!({ "errors": msg["payload"] });
+            ServerMessage::Payload(reply.to_string().into_bytes(), Some(id))
+        }
+        "complete" => {
+            debug!("GraphQL operation {} completed", id);
+            ServerMessage::Complete(id)
+        }
+        "ping" => {
+            debug!("GraphQL ping");
+            ServerMessage::Ping
+        }
+        "pong" => ServerMessage::Ignore,
+        t => {
+            warn!("Ignoring GraphQL message of type {:?}", t);
+            ServerMessage::Ignore
+        }
+    }
+}
+
+pub fn graphql_peer(inner_peer: Peer, opts: Rc<Options>) -> BoxedNewPeerFuture {
+    let (r, w, hup) = (inner_peer.0, inner_peer.1, inner_peer.2);
+    let mut init = json!({"type": "connection_init"});
+    if let Some(ref p) = opts.graphql_init_payload {
+        match serde_json::from_str::<Value>(p) {
+            Ok(x) => init["payload"] = x,
+            Err(e) => return Box::new(err(box_up_err(e))) as BoxedNewPeerFuture,
+        }
+    }
+    let bufsz = opts.buffer_size;
+
+    info!("Sending GraphQL connection_init");
+    let f = tokio_io::io::write_all(w, init.to_string().into_bytes())
+        .and_then(|(w, _)| tokio_io::io::flush(w))
+        .map_err(box_up_err)
+        .and_then(move |w| {
+            loop_fn((r, w, vec![0; bufsz]), |(r, w, buf)| {
+                tokio_io::io::read(r, buf).map_err(box_up_err).and_then(|(r, buf, n)| {
+                    if n == 0 {
+                        return Box::new(err(simple_err2("Connection closed before connection_ack")))
+                            as Box<dyn Future<Item = _, Error = _>>;
+                    }
+                    let t = serde_json::from_slice::<Value>(&buf[..n])
+                        .ok()
+                        .and_then(|x| x["type"].as_str().map(|x| x.to_owned()));
+                    match t.as_ref().map(|x| &x[..]) {
+                        Some("connection_ack") => {
+                            info!("GraphQL connection acknowledged");
+                            Box::new(futures::future::ok(Loop::Break((r, w, buf))))
+                        }
+                        Some("ping") => Box::new(
+                            tokio_io::io::write_all(w, br#"{"type":"pong"}"#.to_vec())
+                                .and_then(|(w, _)| tokio_io::io::flush(w))
+                                .map_err(box_up_err)
+                                .map(|w| Loop::Continue((r, w, buf))),
+                        ),
+                        _ => {
+                            warn!("Unexpected message before connection_ack: {}", String::from_utf8_lossy(&buf[..n]));
+                            Box::new(futures::future::ok(Loop::Continue((r, w, buf))))
+                        }
+                    }
+                })
+            })
+        })
+        .map(move |(r, w, buf)| {
+            let shared = Rc::new(RefCell::new(Shared {
+                w: QueuedWriter::new(w),
+                next_id: 1,
+                active: HashSet::new(),
+                input_finished: false,
+                reader_task: None,
+            }));
+            Peer::new(
+                GraphQlReader { r, shared: shared.clone(), buf },
+                GraphQlWriter(shared),
+                hup,
+            )
+        });
+    Box::new(f) as BoxedNewPeerFuture
+}
+
+struct GraphQlReader {
+    r: Box<dyn AsyncRead>,
+    shared: Rc<RefCell<Shared>>,
+    buf: Vec<u8>,
+}
+
+impl Read for GraphQlReader {
+    fn read(&mut self, b: &mut [u8]) -> Result<usize, IoError> {
+        loop {
+            {
+                let mut sh = self.shared.borrow_mut();
+                sh.w.flush_ignoring_wouldblock()?;
+                if sh.input_finished && sh.active.is_empty() {
+                    info!("All GraphQL operations completed");
+                    return Ok(0);
+                }
+            }
+            let n = match self.r.read(&mut self.buf[..]) {
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    self.shared.borrow_mut().reader_task = Some(futures::task::current());
+                    return wouldblock();
+                }
+                x => x?,
+            };
+            if n == 0 {
+                return Ok(0);
+            }
+            let reply = match parse_server_message(&self.buf[..n]) {
+                ServerMessage::Payload(reply, finished) => {
+                    if let Some(id) = finished {
+                        self.shared.borrow_mut().active.remove(&id);
+                    }
+                    reply
+                }
+                ServerMessage::Complete(id) => {
+                    self.shared.borrow_mut().active.remove(&id);
+                    continue;
+                }
+                ServerMessage::Ping => {
+                    let mut sh = self.shared.borrow_mut();
+                    sh.w.queue.push_back(br#"{"type":"pong"}"#.to_vec());
+                    continue;
+                }
+                ServerMessage::Ignore => continue,
+            };
+            if reply.len() > b.len() {
+                warn!("GraphQL message is too big for the buffer, truncating it");
+            }
+            let l = reply.len().min(b.len());
+            b[..l].copy_from_slice(&reply[..l]);
+            return Ok(l);
+        }
+    }
+}
+impl AsyncRead for GraphQlReader {}
+
+struct GraphQlWriter(Rc<RefCell<Shared>>);
+
+impl Write for GraphQlWriter {
+    fn write(&mut self, b: &[u8]) -> Result<usize, IoError> {
+        let mut sh = self.0.borrow_mut();
+        if !sh.w.queue.is_empty() {
+            sh.w.flush_queue()?;
+        }
+        let msg = String::from_utf8_lossy(b);
+        if msg.trim().is_empty() {
+            return Ok(b.len());
+        }
+        let id = format!("{}", sh.next_id);
+        sh.next_id += 1;
+        debug!("GraphQL subscribe {}", id);
+        let subscribe = subscribe_message(&id, &msg);
+        sh.active.insert(id);
+        sh.w.queue.push_back(subscribe);
+        sh.w.flush_ignoring_wouldblock()?;
+        Ok(b.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.0.borrow_mut().w.flush_queue()
+    }
+}
+impl AsyncWrite for GraphQlWriter {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        let mut sh = self.0.borrow_mut();
+        match sh.w.flush_queue() {
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            x => x?,
+        }
+        sh.input_finished = true;
+        if let Some(t) = sh.reader_task.take() {
+            t.notify();
+        }
+        // Underlying connection gets closed when reading finishes, after all operations complete
+        Ok(Async::Ready(()))
+    }
+}
+
+#[test]
+fn test_graphql_messages() {
+    let m: Value = serde_json::from_slice(&subscribe_message("3", "subscription { a }\n")).unwrap();
+    assert_eq!(m, json!({"id": "3", "type": "subscribe", "payload": {"query": "subscription { a }"}}));
+    let m: Value = serde_json::from_slice(&subscribe_message("4", r#"{"query":"q","variables":{"x":1}}"#)).unwrap();
+    assert_eq!(m["payload"], json!({"query": "q", "variables": {"x": 1}}));
+    let m: Value = serde_json::from_slice(&subscribe_message("5", r#"{"x":1}"#)).unwrap();
+    assert_eq!(m["payload"], json!({"query": r#"{"x":1}"#}));
+
+    assert_eq!(
+        parse_server_message(br#"{"id":"1","type":"next","payload":{"data":{"a":1}}}"#),
+        ServerMessage::Payload(br#"{"data":{"a":1}}"#.to_vec(), None)
+    );
+    assert_eq!(
+        parse_server_message(br#"{"id":"2","type":"error","payload":[{"message":"no"}]}"#),
+        ServerMessage::Payload(br#"{"errors":[{"message":"no"}]}"#.to_vec(), Some("2".to_owned()))
+    );
+    assert_eq!(parse_server_message(br#"{"id":"1","type":"complete"}"#), ServerMessage::Complete("1".to_owned()));
+    assert_eq!(parse_server_message(br#"{"type":"ping"}"#), ServerMessage::Ping);
+    assert_eq!(parse_server_message(br#"{"type":"pong"}"#), ServerMessage::Ignore);
+    assert_eq!(parse_server_message(b"garbage"), ServerMessage::Ignore);
+}
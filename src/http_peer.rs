@@ -147,7 +147,7 @@ enum HttpHeaderEndDetectionState {
     FoundHeaderEnd,
 }
 
-struct WaitForHttpHead<R : AsyncRead>
+pub(crate) struct WaitForHttpHead<R : AsyncRead>
 {
     buf: Option<Vec<u8>>,
     offset : usize,
@@ -155,10 +155,10 @@ struct WaitForHttpHead<R : AsyncRead>
     io : Option<R>,
 }
 
-struct WaitForHttpHeadResult {
-    buf: Vec<u8>,
+pub(crate) struct WaitForHttpHeadResult {
+    pub(crate) buf: Vec<u8>,
     // Before the offset is header, after the offset is debt
-    offset: usize,
+    pub(crate) offset: usize,
 }
 
 impl<R:AsyncRead> WaitForHttpHead<R> {
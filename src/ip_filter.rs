@@ -0,0 +1,125 @@
+//! `--allow-ip` and `--deny-ip`: restrict which clients may connect to listeners
+
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+
+use super::{ConstructParams, Options};
+
+#[derive(Clone)]
+pub struct IpFilter {
+    opts: Rc<Options>,
+    #[cfg(feature = "prometheus_peer")]
+    rejected: Option<prometheus::IntCounter>,
+}
+
+impl IpFilter {
+    pub fn new(cp: &ConstructParams) -> IpFilter {
+        IpFilter {
+            opts: cp.program_options.clone(),
+            #[cfg(feature = "prometheus_peer")]
+            rejected: crate::prometheus_peer::rejected_connections_counter(cp),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.opts.allow_ip.is_empty() || !self.opts.deny_ip.is_empty()
+    }
+
+    /// Proxy from `--x-forwarded-for-trusted`, which gets checked by `X-Forwarded-For` header later
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.opts.x_forwarded_for_trusted.iter().any(|n| n.contains(ip))
+    }
+
+    fn allows(&self, ip: IpAddr) -> bool {
+        let o = &self.opts;
+        if o.deny_ip.iter().any(|n| n.contains(ip)) {
+            return false;
+        }
+        o.allow_ip.is_empty() || o.allow_ip.iter().any(|n| n.contains(ip))
+    }
+
+    /// Check client address, logging and counting rejections.
+    /// Unknown address is rejected if any filtering is configured.
+    pub fn check(&self, client: Option<IpAddr>) -> bool {
+        if !self.is_active() {
+            return true;
+        }
+        if client.is_some_and(|ip| self.allows(ip)) {
+            return true;
+        }
+        match client {
+            Some(ip) => warn!("Rejecting connection from {}: not permitted by --allow-ip/--deny-ip", ip),
+            None => warn!("Rejecting connection with unknown client address due to --allow-ip/--deny-ip"),
+        }
+        self.count_rejection();
+        false
+    }
+
+    /// Count connection rejected here or elsewhere, e.g. due to `--proxy-protocol-trusted`
+    pub fn count_rejection(&self) {
+        #[cfg(feature = "prometheus_peer")]
+        {
+            if let Some(ref c) = self.rejected {
+                c.inc();
+            }
+        }
+    }
+
+    /// Check right after accept. Connections from trusted proxies are let through to be checked by the WebSocket server.
+    pub fn check_accepted(&self, client: Option<SocketAddr>) -> bool {
+        match client {
+            Some(a) if self.is_trusted_proxy(a.ip()) => true,
+            _ => self.check(client.map(|a| a.ip())),
+        }
+    }
+
+    /// Actual client address behind a trusted proxy: the rightmost `X-Forwarded-For` entry
+    /// that is not a trusted proxy itself. `None` if there is no usable entry.
+    pub fn forwarded_client(&self, xff: &str) -> Option<IpAddr> {
+        for entry in xff.rsplit(',') {
+            let entry = entry.trim();
+            let ip: IpAddr = match entry.parse() {
+                Ok(x) => x,
+                // Some proxies append port
+                Err(_) => match entry.parse::<SocketAddr>() {
+                    Ok(x) => x.ip(),
+                    Err(_) => return None,
+                },
+            };
+            if !self.is_trusted_proxy(ip) {
+                return Some(ip);
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn test_ip_filter() {
+    let opts = Options {
+        allow_ip: vec!["10.0.0.0/8".parse().unwrap(), "2001:db8::/32".parse().unwrap()],
+        deny_ip: vec!["10.1.0.0/16".parse().unwrap()],
+        x_forwarded_for_trusted: vec!["192.168.1.1".parse().unwrap(), "10.9.9.9".parse().unwrap()],
+        ..Default::default()
+    };
+    let f = IpFilter {
+        opts: Rc::new(opts),
+        #[cfg(feature = "prometheus_peer")]
+        rejected: None,
+    };
+    let ip = |s: &str| s.parse::<IpAddr>().ok();
+    assert!(f.check(ip("10.2.3.4")));
+    assert!(f.check(ip("::ffff:10.2.3.4")));
+    assert!(f.check(ip("2001:db8::5")));
+    assert!(!f.check(ip("10.1.2.3")));
+    assert!(!f.check(ip("127.0.0.1")));
+    assert!(!f.check(None));
+    assert!(f.check_accepted("192.168.1.1:4000".parse().ok()));
+    assert!(!f.check_accepted("192.168.1.2:4000".parse().ok()));
+
+    assert_eq!(f.forwarded_client("203.0.113.5, 10.9.9.9"), ip("203.0.113.5"));
+    assert_eq!(f.forwarded_client("10.2.2.2, 203.0.113.5"), ip("203.0.113.5"));
+    assert_eq!(f.forwarded_client("[2001:db8::7]:555"), ip("2001:db8::7"));
+    assert_eq!(f.forwarded_client("10.9.9.9"), None);
+    assert_eq!(f.forwarded_client("unknown"), None);
+}
@@ -24,6 +24,7 @@ extern crate websocket;
 extern crate websocket_base;
 extern crate http_bytes;
 extern crate anymap;
+extern crate serde_json;
 pub use http_bytes::http;
 
 extern crate tk_listen;
@@ -126,8 +127,32 @@ pub struct LeftSpecToRightSpec {
     uri: Option<String>,
     /// Address:port of connecting client, if it is TCP
     client_addr: Option<String>,
+    /// Local address:port the TCP client connected to
+    server_addr: Option<String>,
     /// All incoming HTTP headers
     headers: Vec<(String, String)>,
+    /// Subject of verified TLS client certificate
+    tls_client_subject: Option<String>,
+    /// Subject alternative names of verified TLS client certificate
+    tls_client_san: Option<String>,
+    /// SHA-256 fingerprint of verified TLS client certificate
+    tls_client_fingerprint: Option<String>,
+    /// Server name the TLS client asked for (SNI)
+    tls_server_name: Option<String>,
+    /// Negotiated TLS protocol version
+    tls_version: Option<String>,
+    /// Negotiated TLS cipher suite
+    tls_cipher: Option<String>,
+    /// Negotiated ALPN protocol
+    tls_alpn: Option<String>,
+    /// User ID of the process connected to UNIX socket
+    peer_uid: Option<u32>,
+    /// Group ID of the process connected to UNIX socket
+    peer_gid: Option<u32>,
+    /// Process ID of the process connected to UNIX socket, where supported
+    peer_pid: Option<i32>,
+    /// HTTP status to reject WebSocket upgrade with, for connections over the limits
+    reject_status: Option<u16>,
 }
 
 pub type L2rWriter = Rc<RefCell<LeftSpecToRightSpec>>;
@@ -175,6 +200,13 @@ pub mod ws_peer;
 pub mod ws_server_peer;
 pub mod ws_lowlevel_peer;
 pub mod http_peer;
+pub mod sse_client_peer;
+pub mod webhook_peer;
+pub mod longpoll_peer;
+pub mod socketio_peer;
+pub mod graphql_peer;
+pub mod stomp_peer;
+pub mod exec_channel_peer;
 
 #[cfg(feature = "tokio-process")]
 pub mod process_peer;
@@ -185,6 +217,10 @@ pub mod windows_np_peer;
 
 #[cfg(unix)]
 pub mod unix_peer;
+#[cfg(unix)]
+pub mod listen_fd_peer;
+#[cfg(unix)]
+pub mod serial_peer;
 
 pub mod broadcast_reuse_peer;
 pub mod jsonrpc_peer;
@@ -196,8 +232,20 @@ pub mod primitive_reuse_peer;
 pub mod reconnect_peer;
 
 pub mod socks5_peer;
-#[cfg(feature = "ssl")]
+pub mod proxy_protocol;
+pub mod ip_filter;
+pub mod conn_limit;
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+pub mod tls_common;
+#[cfg(all(feature = "ssl", not(feature = "rustls")))]
+pub mod ssl_peer;
+#[cfg(all(feature = "ssl", not(feature = "rustls"), not(any(windows, target_os = "macos", target_os = "ios"))))]
+pub mod ssl_openssl;
+#[cfg(feature = "rustls")]
+#[path = "rustls_peer.rs"]
 pub mod ssl_peer;
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+pub mod tls_peer;
 
 #[cfg(feature = "crypto_peer")]
 pub mod crypto_peer;
@@ -339,8 +339,12 @@ impl WebsocatConfiguration2 {
             if !self.exec_used() {
                 on_warning("-e (--set-environment) is meaningless without a exec: or sh-c: or cmd: address");
             }
-            if !self.contains_class("TcpListenClass") && !self.contains_class("WsServerClass") {
-                on_warning("-e (--set-environment) is currently meaningless without a websocket server and/or TCP listener");
+            if !self.contains_class("TcpListenClass")
+                && !self.contains_class("ListenFdClass")
+                && !self.contains_class("WsServerClass")
+                && !self.contains_class("UdpServerClass")
+            {
+                on_warning("-e (--set-environment) is currently meaningless without a websocket server, TCP listener or UDP server");
             }
         }
 
@@ -354,6 +358,7 @@ impl WebsocatConfiguration2 {
         if !self.opts.oneshot && self.s1.is_multiconnect() {
             if self.s1.contains("TcpListenClass")
                 || self.s1.contains("UnixListenClass")
+                || self.s1.contains("ListenFdClass")
                 || self.s1.contains("SeqpacketListenClass")
             {
                 if !self.opts.unidirectional
@@ -373,7 +378,7 @@ impl WebsocatConfiguration2 {
         secure: bool,
     ) -> Result<()> {
         let url = if secure {
-            #[cfg(not(feature = "ssl"))]
+            #[cfg(not(any(feature = "ssl", feature = "rustls")))]
             {
                 Err("SSL support not compiled in")?;
             }
@@ -428,8 +433,8 @@ impl WebsocatConfiguration2 {
         s.overlays
             .push(SpecifierNode{cls: Rc::new(super::ws_client_peer::WsConnectClass)});
         if secure {
-            #[cfg(feature = "ssl")]
-            s.overlays.push(SpecifierNode{cls: Rc::new(super::ssl_peer::TlsConnectClass)});
+            #[cfg(any(feature = "ssl", feature = "rustls"))]
+            s.overlays.push(SpecifierNode{cls: Rc::new(super::tls_peer::TlsConnectClass)});
         }
         s.overlays.push(SpecifierNode{cls: Rc::new(super::socks5_peer::SocksProxyClass)});
 
@@ -484,16 +489,55 @@ impl WebsocatConfiguration2 {
         Ok(())
     }
 
-    #[cfg(feature = "ssl")]
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
     fn l_ssl(&mut self, _on_warning: &OnWarning) -> Result<()> {
-        if self.opts.pkcs12_der.is_some() &&  !self.contains_class("TlsAcceptClass") {
-            Err("--pkcs12-der makes no sense without an TLS connections acceptor")?;
+        if self.opts.tls_cert.is_some() != self.opts.tls_key.is_some() {
+            Err("--tls-cert and --tls-key should be specified together")?;
+        }
+        if self.opts.client_tls_cert.is_some() != self.opts.client_tls_key.is_some() {
+            Err("--client-tls-cert and --client-tls-key should be specified together")?;
+        }
+        if self.opts.pkcs12_der.is_some() && self.opts.tls_cert.is_some() {
+            Err("Specify either --pkcs12-der or --tls-cert, not both")?;
+        }
+        if self.opts.client_pkcs12_der.is_some() && self.opts.client_tls_cert.is_some() {
+            Err("Specify either --client-pkcs12-der or --client-tls-cert, not both")?;
+        }
+        #[cfg(feature = "rustls")]
+        {
+            if self.opts.pkcs12_der.is_some() || self.opts.client_pkcs12_der.is_some() {
+                Err("PKCS#12 archives are not supported by this rustls-based websocat build. Use PEM files with --tls-cert/--tls-key or --client-tls-cert/--client-tls-key")?;
+            }
+        }
+        let server_identity = self.opts.pkcs12_der.is_some() || self.opts.tls_cert.is_some();
+        let client_identity = self.opts.client_pkcs12_der.is_some() || self.opts.client_tls_cert.is_some();
+        if server_identity &&  !self.contains_class("TlsAcceptClass") {
+            Err("--pkcs12-der, --tls-cert or --tls-self-signed makes no sense without an TLS connections acceptor")?;
+        }
+        if !server_identity &&  self.contains_class("TlsAcceptClass") {
+            Err("You need to specify server key and certificate using the --pkcs12-der, --tls-cert/--tls-key or --tls-self-signed options to use the TLS connections acceptor")?;
+        }
+        if !self.opts.tls_sni_cert.is_empty() && !self.contains_class("TlsAcceptClass") {
+            Err("--tls-sni-cert makes no sense without an TLS connections acceptor")?;
         }
-        if self.opts.pkcs12_der.is_none() &&  self.contains_class("TlsAcceptClass") {
-            Err("You need to specify server key and certificate using the --pkcs12-der option to use the TLS connections acceptor")?;
+        if self.opts.tls_client_ca.is_some() && !self.contains_class("TlsAcceptClass") {
+            Err("--tls-client-ca makes no sense without an TLS connections acceptor")?;
         }
-        if self.opts.client_pkcs12_der.is_some() && !self.contains_class("WsClientSecureClass") && !self.contains_class("TlsConnectClass") {
-            Err("--client-pkcs12-der makes no sense without wss:// or ssl: connectors")?;
+        if self.opts.tls_client_cert_optional && self.opts.tls_client_ca.is_none() {
+            Err("--tls-client-cert-optional requires --tls-client-ca")?;
+        }
+        if client_identity && !self.contains_class("WsClientSecureClass") && !self.contains_class("TlsConnectClass") {
+            Err("--client-pkcs12-der or --client-tls-cert makes no sense without wss:// or ssl: connectors")?;
+        }
+        let client_trust = !self.opts.tls_ca.is_empty() || self.opts.tls_no_system_ca || !self.opts.tls_pin.is_empty();
+        if client_trust && !self.contains_class("WsClientSecureClass") && !self.contains_class("TlsConnectClass") {
+            Err("--tls-ca, --tls-no-system-ca or --tls-pin makes no sense without wss:// or ssl: connectors")?;
+        }
+        if self.opts.tls_no_system_ca && self.opts.tls_ca.is_empty() && self.opts.tls_pin.is_empty() {
+            Err("--tls-no-system-ca without --tls-ca would leave no trusted certificates")?;
+        }
+        if self.opts.tls_insecure && (!self.opts.tls_ca.is_empty() || !self.opts.tls_pin.is_empty()) {
+            _on_warning("--insecure disables certificate checks, so --tls-ca and --tls-pin have no effect");
         }
         #[cfg(target_os = "macos")]
         {
@@ -647,6 +691,67 @@ impl WebsocatConfiguration2 {
         Ok(())
     }
 
+    fn l_proxy_protocol(&mut self, on_warning: &OnWarning) -> Result<()> {
+        if !self.opts.proxy_protocol_trusted.is_empty() && !self.opts.proxy_protocol {
+            return Err("--proxy-protocol-trusted requires --proxy-protocol")?;
+        }
+        if self.opts.proxy_protocol
+            && !self.s1.contains("TcpListenClass")
+            && !self.s1.contains("ListenFdClass")
+        {
+            on_warning("--proxy-protocol is meaningless without a TCP listener on the left side");
+        }
+        if self.opts.proxy_protocol_send.is_some() && !self.s2.contains("TcpConnectClass") {
+            on_warning("--proxy-protocol-send is meaningless without `tcp:` on the right side");
+        }
+        Ok(())
+    }
+
+    fn l_ip_filter(&mut self, on_warning: &OnWarning) -> Result<()> {
+        let o = &self.opts;
+        if o.allow_ip.is_empty() && o.deny_ip.is_empty() {
+            if !o.x_forwarded_for_trusted.is_empty() {
+                on_warning("--x-forwarded-for-trusted is meaningless without --allow-ip or --deny-ip");
+            }
+            return Ok(());
+        }
+        if !self.s1.contains("TcpListenClass") && !self.s1.contains("ListenFdClass") {
+            on_warning("--allow-ip and --deny-ip only work with a TCP listener on the left side");
+        }
+        if !o.x_forwarded_for_trusted.is_empty() && !self.s1.contains("WsServerClass") {
+            on_warning("--x-forwarded-for-trusted needs a WebSocket server on the left side, connections from trusted proxies are not checked otherwise");
+        }
+        Ok(())
+    }
+
+    fn l_unix_perms(&mut self, on_warning: &OnWarning) -> Result<()> {
+        let o = &self.opts;
+        let unix_listener = self.s1.contains("UnixListenClass")
+            || self.s1.contains("AbstractListenClass")
+            || self.s1.contains("SeqpacketListenClass")
+            || self.s1.contains("ListenFdClass");
+        if (o.unix_socket_mode.is_some() || o.unix_socket_owner.is_some() || o.unix_socket_group.is_some())
+            && !self.s1.contains("UnixListenClass")
+            && !self.s1.contains("SeqpacketListenClass")
+        {
+            on_warning("--chmod and --chown are meaningless without `unix-l:` or `seqpacket-l:` on the left side");
+        }
+        if (!o.unix_allow_uid.is_empty() || !o.unix_allow_gid.is_empty()) && !unix_listener {
+            on_warning("--unix-allow-uid and --unix-allow-gid only work with a UNIX socket listener on the left side");
+        }
+        Ok(())
+    }
+
+    fn l_serial(&mut self, on_warning: &OnWarning) -> Result<()> {
+        if self.opts.serial_control_prefix.is_some()
+            && !self.s1.contains("SerialClass")
+            && !self.s2.contains("SerialClass")
+        {
+            on_warning("--serial-control-prefix is meaningless without `serial:`");
+        }
+        Ok(())
+    }
+
     fn l_autoreconn_reuse(&mut self, _on_warning: &OnWarning) -> Result<()> {
         if self.s1.autotoreconn_misuse() || self.s2.autotoreconn_misuse() {
             _on_warning("Warning: `autoreconnect:reuse:` is a bad overlay combination. Maybe you want `reuse:autoreconnect:");
@@ -669,7 +774,7 @@ impl WebsocatConfiguration2 {
         self.l_environ(&on_warning)?;
         self.l_closebug(&on_warning)?;
         self.l_socks5(&on_warning)?;
-        #[cfg(feature = "ssl")]
+        #[cfg(any(feature = "ssl", feature = "rustls"))]
         self.l_ssl(&on_warning)?;
         self.l_ping(&on_warning)?;
         self.l_proto(&on_warning)?;
@@ -678,6 +783,10 @@ impl WebsocatConfiguration2 {
         self.l_crypto(&on_warning)?;
         self.l_sizelimits(&on_warning)?;
         self.l_compress(&on_warning)?;
+        self.l_proxy_protocol(&on_warning)?;
+        self.l_ip_filter(&on_warning)?;
+        self.l_unix_perms(&on_warning)?;
+        self.l_serial(&on_warning)?;
         self.l_autoreconn_reuse(&on_warning)?;
 
         // TODO: UDP connect oneshot mode
@@ -0,0 +1,216 @@
+//! `listen-fd:` - accept connections on a listening socket inherited from parent process, e.g. systemd
+
+extern crate libc;
+extern crate tokio_reactor;
+extern crate tokio_uds;
+
+use std;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::str::FromStr;
+
+use super::ip_filter::IpFilter;
+use super::util::peer_err_sb;
+use super::{multi, ConstructParams, PeerConstructor, Specifier};
+use super::{BoxedNewPeerStream, L2rUser, Options, Result};
+
+use std::rc::Rc;
+
+/// First file descriptor passed by systemd, as in `sd_listen_fds(3)`
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+#[derive(Debug, Clone)]
+pub enum ListenFdSource {
+    /// Explicit file descriptor number
+    Fd(RawFd),
+    /// `LISTEN_FDS` socket, the first one or the one with the given `LISTEN_FDNAMES` entry
+    Systemd(Option<String>),
+}
+
+impl FromStr for ListenFdSource {
+    type Err = Box<dyn std::error::Error>;
+    fn from_str(s: &str) -> Result<ListenFdSource> {
+        if s.is_empty() || s == "systemd" {
+            return Ok(ListenFdSource::Systemd(None));
+        }
+        if let Some(name) = s.strip_prefix("systemd:") {
+            return Ok(ListenFdSource::Systemd(Some(name.to_owned())));
+        }
+        match s.parse() {
+            Ok(fd) => Ok(ListenFdSource::Fd(fd)),
+            Err(_) => Err("Expected a file descriptor number, `systemd` or `systemd:<name>`")?,
+        }
+    }
+}
+
+impl ListenFdSource {
+    fn fd(&self) -> Result<RawFd> {
+        let name = match self {
+            ListenFdSource::Fd(fd) => return Ok(*fd),
+            ListenFdSource::Systemd(name) => name,
+        };
+        let n: usize = match std::env::var("LISTEN_FDS") {
+            Ok(x) => x.parse()?,
+            Err(_) => Err("LISTEN_FDS is not set. Is websocat started by a systemd socket unit?")?,
+        };
+        if let Ok(pid) = std::env::var("LISTEN_PID") {
+            if pid.parse() != Ok(std::process::id()) {
+                Err("LISTEN_PID does not match, the sockets are meant for some other process")?;
+            }
+        }
+        if n == 0 {
+            Err("LISTEN_FDS is zero, there are no sockets to listen on")?;
+        }
+        let i = match name {
+            None => 0,
+            Some(name) => {
+                let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+                match names.split(':').take(n).position(|x| x == name) {
+                    Some(i) => i,
+                    None => Err(format!("There is no socket named `{}` in LISTEN_FDNAMES", name))?,
+                }
+            }
+        };
+        Ok(SD_LISTEN_FDS_START + i as RawFd)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ListenFd(pub ListenFdSource);
+impl Specifier for ListenFd {
+    fn construct(&self, p: ConstructParams) -> PeerConstructor {
+        let filter = IpFilter::new(&p);
+        match listen_fd_peer(&self.0, p.left_to_right, &p.program_options, filter) {
+            Ok(x) => multi(x),
+            Err(e) => multi(peer_err_sb(e)),
+        }
+    }
+    specifier_boilerplate!(noglobalstate multiconnect no_subspec);
+}
+specifier_class!(
+    name = ListenFdClass,
+    target = ListenFd,
+    prefixes = ["listen-fd:", "fd-listen:", "l-fd:", "fd-l:"],
+    arg_handling = parse,
+    overlay = false,
+    StreamOriented,
+    MultiConnect,
+    help = r#"
+Accept connections on already listening TCP or UNIX socket inherited from parent process. [A]
+
+Argument is either a file descriptor number, `systemd` for the first socket passed by systemd
+socket activation (`LISTEN_FDS`) or `systemd:<name>` for the socket with the given `FileDescriptorName=`
+(`LISTEN_FDNAMES`).
+
+This allows restarting websocat without refusing connections and listening on privileged ports
+without running websocat as root.
+
+Example: WebSocket server activated by systemd unit with `ListenStream=80`
+
+    websocat -E -b ws-u:listen-fd:systemd tcp:127.0.0.1:22
+
+Example: serve TLS on a socket passed as file descriptor 5
+
+    websocat -E -b --pkcs12-der=q.pkcs12 ws-u:tls-accept:listen-fd:5 tcp:127.0.0.1:22
+"#
+);
+
+/// `getsockopt(2)` integer option at `SOL_SOCKET` level
+fn socket_option(fd: RawFd, option: libc::c_int) -> std::io::Result<libc::c_int> {
+    let mut val: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let valp = &mut val as *mut _ as *mut libc::c_void;
+    let ret = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, option, valp, &mut len) };
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(val)
+}
+
+/// `getsockname(2)` address family of a listening stream socket
+fn listening_socket_family(fd: RawFd) -> Result<libc::c_int> {
+    if socket_option(fd, libc::SO_TYPE)? != libc::SOCK_STREAM {
+        Err("not a stream socket")?;
+    }
+    if socket_option(fd, libc::SO_ACCEPTCONN)? == 0 {
+        Err("not a listening socket")?;
+    }
+    let mut ss: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret = unsafe { libc::getsockname(fd, &mut ss as *mut _ as *mut libc::sockaddr, &mut len) };
+    if ret == -1 {
+        Err(std::io::Error::last_os_error())?;
+    }
+    Ok(libc::c_int::from(ss.ss_family))
+}
+
+pub fn listen_fd_peer(
+    source: &ListenFdSource,
+    l2r: L2rUser,
+    opts: &Rc<Options>,
+    filter: IpFilter,
+) -> Result<BoxedNewPeerStream> {
+    let fd = source.fd()?;
+    let family = listening_socket_family(fd).map_err(|e| format!("File descriptor {}: {}", fd, e))?;
+    // Inherited sockets lack FD_CLOEXEC, so `exec:` children would keep them open otherwise
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+    match family {
+        libc::AF_INET | libc::AF_INET6 => {
+            let l = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            l.set_nonblocking(true)?;
+            let addr = l.local_addr()?;
+            let l = tokio_tcp::TcpListener::from_std(l, &tokio_reactor::Handle::default())?;
+            debug!("Using inherited TCP listening socket {}", addr);
+            if opts.announce_listens {
+                println!("LISTEN proto=tcp,ip={},port={}", addr.ip(), addr.port());
+            }
+            Ok(super::net_peer::tcp_accept_peers(l, l2r, opts, filter))
+        }
+        libc::AF_UNIX => {
+            let l = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            l.set_nonblocking(true)?;
+            let l = tokio_uds::UnixListener::from_std(l, &tokio_reactor::Handle::default())?;
+            debug!("Using inherited UNIX listening socket");
+            if opts.announce_listens {
+                println!("LISTEN proto=unix,fd={}", fd);
+            }
+            Ok(super::unix_peer::unix_accept_peers(l, l2r, opts))
+        }
+        _ => Err(format!("File descriptor {} is not a TCP or UNIX socket", fd))?,
+    }
+}
+
+#[test]
+fn test_listen_fd_source() {
+    use std::os::unix::io::AsRawFd;
+
+    assert!(matches!("".parse(), Ok(ListenFdSource::Systemd(None))));
+    assert!(matches!("systemd".parse(), Ok(ListenFdSource::Systemd(None))));
+    assert!(matches!("systemd:web".parse(), Ok(ListenFdSource::Systemd(Some(ref x))) if x == "web"));
+    assert!(matches!("5".parse(), Ok(ListenFdSource::Fd(5))));
+    assert!("five".parse::<ListenFdSource>().is_err());
+
+    assert_eq!(ListenFdSource::Fd(7).fd().unwrap(), 7);
+    std::env::remove_var("LISTEN_FDS");
+    assert!(ListenFdSource::Systemd(None).fd().is_err());
+    std::env::set_var("LISTEN_FDS", "2");
+    std::env::set_var("LISTEN_PID", std::process::id().to_string());
+    std::env::set_var("LISTEN_FDNAMES", "admin:web");
+    assert_eq!(ListenFdSource::Systemd(None).fd().unwrap(), 3);
+    assert_eq!(ListenFdSource::Systemd(Some("web".to_owned())).fd().unwrap(), 4);
+    assert!(ListenFdSource::Systemd(Some("other".to_owned())).fd().is_err());
+    std::env::set_var("LISTEN_PID", "1");
+    assert!(ListenFdSource::Systemd(None).fd().is_err());
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    assert_eq!(listening_socket_family(l.as_raw_fd()).unwrap(), libc::AF_INET);
+    let u = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    assert!(listening_socket_family(u.as_raw_fd()).is_err());
+    let t = net2::TcpBuilder::new_v4().unwrap();
+    t.bind("127.0.0.1:0").unwrap();
+    assert!(listening_socket_family(t.as_raw_fd()).is_err());
+}
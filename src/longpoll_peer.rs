@@ -0,0 +1,556 @@
+extern crate http_bytes;
+
+use futures::future::{ok, Future};
+use futures::task::Task;
+use futures::unsync::mpsc;
+use futures::{Async, Poll, Stream};
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::http_peer::WaitForHttpHead;
+use super::{box_up_err, multi, wouldblock, BoxedNewPeerFuture, BoxedNewPeerStream, Peer};
+use super::{ConstructParams, L2rUser, Options, PeerConstructor, PeerOverlay, Specifier};
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use http_bytes::http;
+use http::StatusCode;
+
+#[derive(Debug)]
+pub struct HttpLongPoll<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for HttpLongPoll<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        use crate::PeerConstructor::{OverlayM, ServeMultipleTimes};
+        let l2r = cp.left_to_right.clone();
+        let opts = cp.program_options.clone();
+        match self.0.construct(cp) {
+            ServeMultipleTimes(s) => multi(longpoll_server(s, None, l2r, opts)),
+            OverlayM(s, mapper) => multi(longpoll_server(s, Some(mapper), l2r, opts)),
+            PeerConstructor::Error(e) => PeerConstructor::Error(e),
+            _ => PeerConstructor::Error(From::from(
+                "http-longpoll: should be used with a listening (multiconnect) specifier like tcp-l:",
+            )),
+        }
+    }
+    specifier_boilerplate!(noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = HttpLongPollClass,
+    target = HttpLongPoll,
+    prefixes = ["http-longpoll:"],
+    arg_handling = subspec,
+    overlay = true,
+    MessageOriented,
+    MulticonnectnessDependsOnInnerType,
+    help = r#"
+[A] Accept HTTP/1 requests implementing a long-polling transport,
+for clients that cannot use WebSockets. Each session is connected to a
+separate instance of the right side specifier.
+
+* `POST` without `session` query parameter opens a new session. Response body is the session token.
+* `GET /?session=TOKEN` returns next message, waiting up to `--longpoll-timeout-millis`
+  for it to appear. Returns 204 on timeout and 410 when the session is finished.
+* `POST /?session=TOKEN` sends request body as a message. Returns 503 if too many messages are queued.
+* `DELETE /?session=TOKEN` closes the session.
+
+Sessions without any requests for `--longpoll-idle-timeout-millis` are closed.
+
+Example:
+
+    websocat -E -b http-longpoll:tcp-l:127.0.0.1:8080 tcp:127.0.0.1:1234
+
+    T=$(curl -s -XPOST http://127.0.0.1:8080/)
+    curl -d hello "http://127.0.0.1:8080/?session=$T"
+    curl "http://127.0.0.1:8080/?session=$T"
+"#
+);
+
+/// Queue length at which writes to a session start to block and POSTs get 503
+const MAX_QUEUED_MESSAGES: usize = 64;
+
+#[derive(Default)]
+struct LpSession {
+    /// Messages POSTed by the client, to be read by the session
+    incoming: VecDeque<Vec<u8>>,
+    /// Messages written by the session, to be fetched by client's GETs
+    outgoing: VecDeque<Vec<u8>>,
+    reader_task: Option<Task>,
+    writer_task: Option<Task>,
+    /// Pending GET requests, keyed by waiter id
+    getter_tasks: HashMap<u64, Task>,
+    next_getter_id: u64,
+    /// Client has sent DELETE or stopped polling
+    client_closed: bool,
+    /// Right side has finished writing or is gone
+    server_closed: bool,
+    last_activity: Option<Instant>,
+}
+
+impl LpSession {
+    fn touch(&mut self) {
+        self.last_activity = Some(Instant::now());
+    }
+    fn wake_reader(&mut self) {
+        if let Some(t) = self.reader_task.take() {
+            t.notify();
+        }
+    }
+    fn wake_getters(&mut self) {
+        for (_, t) in self.getter_tasks.drain() {
+            t.notify();
+        }
+    }
+}
+
+type SessionHandle = Rc<RefCell<LpSession>>;
+
+struct Sessions {
+    map: HashMap<String, SessionHandle>,
+    new_sessions: mpsc::UnboundedSender<Peer>,
+    opts: Rc<Options>,
+}
+
+impl Sessions {
+    /// Forget sessions that are finished on both sides
+    fn sweep(&mut self) {
+        let idle = Duration::from_millis(self.opts.longpoll_idle_timeout_millis);
+        self.map.retain(|_, s| {
+            let s = s.borrow();
+            let expired = s.last_activity.map(|x| x.elapsed() > idle).unwrap_or(false);
+            !(s.server_closed && (s.client_closed || expired))
+        });
+    }
+}
+
+struct LongPollServer {
+    inner: BoxedNewPeerStream,
+    mapper: Option<PeerOverlay>,
+    l2r: L2rUser,
+    sessions: Rc<RefCell<Sessions>>,
+    rx: mpsc::UnboundedReceiver<Peer>,
+    inner_finished: bool,
+}
+
+impl Stream for LongPollServer {
+    type Item = Peer;
+    type Error = Box<dyn std::error::Error>;
+
+    fn poll(&mut self) -> Poll<Option<Peer>, Self::Error> {
+        while !self.inner_finished {
+            match self.inner.poll()? {
+                Async::Ready(Some(conn)) => {
+                    let sessions = self.sessions.clone();
+                    let conn: BoxedNewPeerFuture = match self.mapper {
+                        Some(ref mapper) => mapper(conn, self.l2r.clone()),
+                        None => Box::new(ok(conn)),
+                    };
+                    super::spawn_hack(
+                        conn.and_then(move |p| handle_request(p, sessions))
+                            .map_err(|e| info!("Long-polling request failed: {}", e)),
+                    );
+                }
+                Async::Ready(None) => self.inner_finished = true,
+                Async::NotReady => break,
+            }
+        }
+        match self.rx.poll() {
+            Ok(Async::Ready(Some(p))) => Ok(Async::Ready(Some(p))),
+            Ok(Async::NotReady) if !self.inner_finished => Ok(Async::NotReady),
+            _ => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+pub fn longpoll_server(
+    inner: BoxedNewPeerStream,
+    mapper: Option<PeerOverlay>,
+    l2r: L2rUser,
+    opts: Rc<Options>,
+) -> BoxedNewPeerStream {
+    let (tx, rx) = mpsc::unbounded();
+    let sessions = Rc::new(RefCell::new(Sessions {
+        map: HashMap::new(),
+        new_sessions: tx,
+        opts,
+    }));
+    Box::new(LongPollServer {
+        inner,
+        mapper,
+        l2r,
+        sessions,
+        rx,
+        inner_finished: false,
+    }) as BoxedNewPeerStream
+}
+
+enum GetOutcome {
+    Message(Vec<u8>),
+    Timeout,
+    Closed,
+}
+
+/// Resolves when there is a message for GET request or it is time to give up
+struct WaitForOutgoing {
+    s: SessionHandle,
+    id: u64,
+    timeout: tokio_timer::Delay,
+}
+
+impl WaitForOutgoing {
+    fn new(s: SessionHandle, timeout: Duration) -> WaitForOutgoing {
+        let id = {
+            let mut ss = s.borrow_mut();
+            ss.next_getter_id += 1;
+            ss.next_getter_id
+        };
+        WaitForOutgoing {
+            s,
+            id,
+            timeout: tokio_timer::Delay::new(Instant::now() + timeout),
+        }
+    }
+}
+
+impl Drop for WaitForOutgoing {
+    fn drop(&mut self) {
+        self.s.borrow_mut().getter_tasks.remove(&self.id);
+    }
+}
+
+impl Future for WaitForOutgoing {
+    type Item = GetOutcome;
+    type Error = Box<dyn std::error::Error>;
+
+    fn poll(&mut self) -> Poll<GetOutcome, Self::Error> {
+        let mut s = self.s.borrow_mut();
+        s.touch();
+        if let Some(m) = s.outgoing.pop_front() {
+            if let Some(t) = s.writer_task.take() {
+                t.notify();
+            }
+            return Ok(Async::Ready(GetOutcome::Message(m)));
+        }
+        if s.server_closed || s.client_closed {
+            return Ok(Async::Ready(GetOutcome::Closed));
+        }
+        s.getter_tasks.insert(self.id, futures::task::current());
+        match self.timeout.poll().map_err(box_up_err)? {
+            Async::Ready(()) => Ok(Async::Ready(GetOutcome::Timeout)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+fn get_session_token(uri: &http::Uri) -> Option<String> {
+    uri.query()?
+        .split('&')
+        .find(|x| x.starts_with("session="))
+        .map(|x| x["session=".len()..].to_owned())
+}
+
+fn write_response(w: Box<dyn AsyncWrite>, status: StatusCode, body: Vec<u8>) -> BoxedUnitFuture {
+    use crate::http::header::{CACHE_CONTROL, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE};
+    let mut reply = http::response::Builder::default();
+    reply.status(status);
+    reply.header("Server", "websocat");
+    reply.header(CACHE_CONTROL, "no-cache");
+    reply.header(CONNECTION, "close");
+    if !body.is_empty() {
+        reply.header(CONTENT_TYPE, "application/octet-stream");
+    }
+    reply.header(CONTENT_LENGTH, body.len());
+    let reply = reply.body(()).unwrap();
+    let mut reply = http_bytes::response_header_to_vec(&reply);
+    reply.extend_from_slice(&body);
+    debug!("Long-polling response {}", status);
+    Box::new(
+        tokio_io::io::write_all(w, reply)
+            .and_then(|(w, _)| tokio_io::io::shutdown(w))
+            .map(|_| ())
+            .map_err(box_up_err),
+    )
+}
+
+type BoxedUnitFuture = Box<dyn Future<Item = (), Error = Box<dyn std::error::Error>>>;
+
+fn handle_request(p: Peer, sessions: Rc<RefCell<Sessions>>) -> BoxedUnitFuture {
+    let (r, w) = (p.0, p.1);
+    let f = WaitForHttpHead::new(r).and_then(move |(res, r)| {
+        let request = match http_bytes::parse_request_header_easy(&res.buf[0..res.offset]) {
+            Ok(Some((request, b""))) => request,
+            _ => return write_response(w, StatusCode::BAD_REQUEST, vec![]),
+        };
+        debug!("Long-polling request: {} {}", request.method(), request.uri());
+        let method = request.method().clone();
+        let token = get_session_token(request.uri());
+
+        sessions.borrow_mut().sweep();
+        let s = match token {
+            None => {
+                if method != http::Method::POST {
+                    return write_response(w, StatusCode::BAD_REQUEST, b"Use POST to open a session".to_vec());
+                }
+                let token = hex::encode(rand::random::<[u8; 16]>());
+                let s: SessionHandle = Default::default();
+                s.borrow_mut().touch();
+                let mut ss = sessions.borrow_mut();
+                let idle = Duration::from_millis(ss.opts.longpoll_idle_timeout_millis);
+                let peer = Peer::new(
+                    SessionReader {
+                        s: s.clone(),
+                        idle,
+                        timer: None,
+                    },
+                    SessionWriter(s.clone()),
+                    None,
+                );
+                if ss.new_sessions.unbounded_send(peer).is_err() {
+                    return write_response(w, StatusCode::SERVICE_UNAVAILABLE, vec![]);
+                }
+                info!("New long-polling session");
+                ss.map.insert(token.clone(), s);
+                return write_response(w, StatusCode::OK, token.into_bytes());
+            }
+            Some(token) => match sessions.borrow().map.get(&token) {
+                Some(s) => s.clone(),
+                None => return write_response(w, StatusCode::NOT_FOUND, b"No such session".to_vec()),
+            },
+        };
+        s.borrow_mut().touch();
+
+        match method {
+            http::Method::GET => {
+                let timeout = Duration::from_millis(sessions.borrow().opts.longpoll_timeout_millis);
+                let s2 = s.clone();
+                let wfo = WaitForOutgoing::new(s, timeout);
+                Box::new(wfo.and_then(move |outcome| match outcome {
+                    GetOutcome::Message(m) => {
+                        // Put the message back if the client is gone before getting it
+                        Box::new(write_response(w, StatusCode::OK, m.clone()).map_err(move |e| {
+                            let mut s = s2.borrow_mut();
+                            s.outgoing.push_front(m);
+                            s.wake_getters();
+                            e
+                        }))
+                    }
+                    GetOutcome::Timeout => write_response(w, StatusCode::NO_CONTENT, vec![]),
+                    GetOutcome::Closed => write_response(w, StatusCode::GONE, vec![]),
+                })) as BoxedUnitFuture
+            }
+            http::Method::POST => {
+                let cl = request
+                    .headers()
+                    .get(http::header::CONTENT_LENGTH)
+                    .and_then(|x| x.to_str().ok())
+                    .and_then(|x| x.parse::<usize>().ok());
+                let cl = match cl {
+                    Some(x) => x,
+                    None => return write_response(w, StatusCode::LENGTH_REQUIRED, vec![]),
+                };
+                if cl > sessions.borrow().opts.buffer_size {
+                    return write_response(w, StatusCode::PAYLOAD_TOO_LARGE, vec![]);
+                }
+                let mut body = res.buf[res.offset..].to_vec();
+                body.truncate(cl);
+                let missing = cl - body.len();
+                Box::new(
+                    tokio_io::io::read_exact(r, vec![0; missing])
+                        .map_err(box_up_err)
+                        .and_then(move |(_r, rest)| {
+                            body.extend_from_slice(&rest);
+                            let mut s = s.borrow_mut();
+                            if s.client_closed {
+                                return write_response(w, StatusCode::GONE, vec![]);
+                            }
+                            if s.incoming.len() >= MAX_QUEUED_MESSAGES {
+                                warn!("Long-polling session is not reading messages fast enough");
+                                return write_response(w, StatusCode::SERVICE_UNAVAILABLE, vec![]);
+                            }
+                            s.incoming.push_back(body);
+                            s.wake_reader();
+                            write_response(w, StatusCode::NO_CONTENT, vec![])
+                        }),
+                ) as BoxedUnitFuture
+            }
+            http::Method::DELETE => {
+                info!("Long-polling session closed by client");
+                let mut s = s.borrow_mut();
+                s.client_closed = true;
+                s.wake_reader();
+                s.wake_getters();
+                write_response(w, StatusCode::NO_CONTENT, vec![])
+            }
+            _ => write_response(w, StatusCode::METHOD_NOT_ALLOWED, vec![]),
+        }
+    });
+    Box::new(f) as BoxedUnitFuture
+}
+
+struct SessionReader {
+    s: SessionHandle,
+    idle: Duration,
+    timer: Option<tokio_timer::Delay>,
+}
+
+impl Read for SessionReader {
+    fn read(&mut self, b: &mut [u8]) -> Result<usize, IoError> {
+        let mut s = self.s.borrow_mut();
+        if let Some(m) = s.incoming.pop_front() {
+            if m.len() > b.len() {
+                warn!("Long-polling message is too big for the buffer, truncating it");
+            }
+            let l = m.len().min(b.len());
+            b[..l].copy_from_slice(&m[..l]);
+            return Ok(l);
+        }
+        if s.client_closed {
+            return Ok(0);
+        }
+        let deadline = s.last_activity.unwrap_or_else(Instant::now) + self.idle;
+        if deadline <= Instant::now() {
+            info!("Long-polling session expired");
+            s.client_closed = true;
+            s.wake_getters();
+            return Ok(0);
+        }
+        let mut timer = self.timer.take().unwrap_or_else(|| tokio_timer::Delay::new(deadline));
+        timer.reset(deadline);
+        // Register for wakeup at the deadline
+        let _ = timer.poll();
+        self.timer = Some(timer);
+        s.reader_task = Some(futures::task::current());
+        wouldblock()
+    }
+}
+impl AsyncRead for SessionReader {}
+
+struct SessionWriter(SessionHandle);
+
+impl Write for SessionWriter {
+    fn write(&mut self, b: &[u8]) -> Result<usize, IoError> {
+        let mut s = self.0.borrow_mut();
+        if s.client_closed {
+            return Err(IoError::new(std::io::ErrorKind::BrokenPipe, "Long-polling session closed"));
+        }
+        if s.outgoing.len() >= MAX_QUEUED_MESSAGES {
+            s.writer_task = Some(futures::task::current());
+            return wouldblock();
+        }
+        s.outgoing.push_back(b.to_vec());
+        s.wake_getters();
+        Ok(b.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+impl AsyncWrite for SessionWriter {
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        let mut s = self.0.borrow_mut();
+        s.server_closed = true;
+        s.wake_getters();
+        Ok(Async::Ready(()))
+    }
+}
+/// Right side may fail or go away without shutdown, e.g. when it could not connect
+impl Drop for SessionWriter {
+    fn drop(&mut self) {
+        let mut s = self.0.borrow_mut();
+        s.server_closed = true;
+        s.wake_getters();
+    }
+}
+
+/// Response sink for requests in tests. `None` makes writes fail, like with a client that is gone.
+#[cfg(test)]
+struct TestWriter(Option<Rc<RefCell<Vec<u8>>>>);
+#[cfg(test)]
+impl Write for TestWriter {
+    fn write(&mut self, b: &[u8]) -> Result<usize, IoError> {
+        match self.0 {
+            Some(ref x) => x.borrow_mut().write(b),
+            None => Err(IoError::new(std::io::ErrorKind::BrokenPipe, "gone")),
+        }
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+#[cfg(test)]
+impl AsyncWrite for TestWriter {
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+fn request(
+    rt: &mut tokio::runtime::current_thread::Runtime,
+    sessions: &Rc<RefCell<Sessions>>,
+    req: &str,
+) -> (u16, Vec<u8>) {
+    let out = Rc::new(RefCell::new(vec![]));
+    let p = Peer::new(std::io::Cursor::new(req.as_bytes().to_vec()), TestWriter(Some(out.clone())), None);
+    let sessions = sessions.clone();
+    rt.block_on(futures::future::lazy(move || handle_request(p, sessions))).unwrap();
+    let out = out.borrow();
+    let status = String::from_utf8_lossy(&out[9..12]).parse().unwrap();
+    let body_start = out.windows(4).position(|x| x == b"\r\n\r\n").unwrap() + 4;
+    (status, out[body_start..].to_vec())
+}
+
+#[test]
+fn test_longpoll_sessions() {
+    let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+    let (tx, rx) = mpsc::unbounded();
+    let mut rx = rx.wait();
+    let opts = Options {
+        longpoll_timeout_millis: 50,
+        longpoll_idle_timeout_millis: 200,
+        ..Default::default()
+    };
+    let sessions = Rc::new(RefCell::new(Sessions {
+        map: HashMap::new(),
+        new_sessions: tx,
+        opts: Rc::new(opts),
+    }));
+
+    let (status, token) = request(&mut rt, &sessions, "POST / HTTP/1.1\r\n\r\n");
+    assert_eq!(status, 200);
+    let token = String::from_utf8(token).unwrap();
+    let Peer(mut r, mut w, _) = rx.next().unwrap().unwrap();
+    let get = format!("GET /?session={} HTTP/1.1\r\n\r\n", token);
+
+    assert_eq!(request(&mut rt, &sessions, "GET /?session=nosuch HTTP/1.1\r\n\r\n").0, 404);
+    assert_eq!(request(&mut rt, &sessions, &get), (204, vec![]));
+
+    w.write_all(b"first").unwrap();
+    w.write_all(b"second").unwrap();
+    assert_eq!(request(&mut rt, &sessions, &get), (200, b"first".to_vec()));
+    // Message stays queued when the response cannot be written
+    let p = Peer::new(std::io::Cursor::new(get.as_bytes().to_vec()), TestWriter(None), None);
+    let s2 = sessions.clone();
+    assert!(rt.block_on(futures::future::lazy(move || handle_request(p, s2))).is_err());
+    assert_eq!(request(&mut rt, &sessions, &get), (200, b"second".to_vec()));
+
+    let post = format!("POST /?session={} HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello", token);
+    assert_eq!(request(&mut rt, &sessions, &post).0, 204);
+    let mut buf = [0; 16];
+    assert_eq!(r.read(&mut buf).unwrap(), 5);
+    assert_eq!(&buf[..5], b"hello");
+    for _ in 0..MAX_QUEUED_MESSAGES {
+        assert_eq!(request(&mut rt, &sessions, &post).0, 204);
+    }
+    assert_eq!(request(&mut rt, &sessions, &post).0, 503);
+
+    // Right side is gone without shutdown
+    drop((r, w));
+    assert_eq!(request(&mut rt, &sessions, &get).0, 410);
+    std::thread::sleep(Duration::from_millis(250));
+    assert_eq!(request(&mut rt, &sessions, &get).0, 404);
+}
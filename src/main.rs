@@ -33,6 +33,8 @@ use std::path::PathBuf;
 use structopt::StructOpt;
 
 use websocat::options::StaticFile;
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+use websocat::options::{TlsPin, TlsSniCert};
 use websocat::socks5_peer::{SocksHostAddr, SocksSocketAddr};
 use websocat::{Options, SpecifierClass, WebsocatConfiguration1};
 
@@ -164,15 +166,108 @@ struct Opt {
     #[structopt(long="udp-reuseaddr")]
     udp_reuseaddr: bool,
 
+    /// [A] Forget `udp-server:` client (ending its session) after this number of seconds without packets in either direction
+    #[structopt(long="udp-idle-timeout", default_value = "60")]
+    udp_idle_timeout: u64,
+
+    /// [A] Maximum number of simultaneous `udp-server:` clients. Packets from new clients are dropped above that.
+    #[structopt(long="udp-max-clients", default_value = "1024")]
+    udp_max_clients: usize,
+
+    /// [A] Set TCP_NODELAY for TCP sockets, disabling Nagle's algorithm
+    #[structopt(long="tcp-nodelay")]
+    tcp_nodelay: bool,
+
+    /// [A] Enable TCP keepalive, sending probes after this number of idle seconds
+    #[structopt(long="tcp-keepalive")]
+    tcp_keepalive: Option<u64>,
+
+    /// [A] Seconds between TCP keepalive probes. Requires --tcp-keepalive.
+    #[structopt(long="tcp-keepalive-interval")]
+    tcp_keepalive_interval: Option<u64>,
+
+    /// [A] Number of unanswered TCP keepalive probes before dropping the connection. Requires --tcp-keepalive.
+    #[structopt(long="tcp-keepalive-count")]
+    tcp_keepalive_count: Option<u32>,
+
+    /// [A] Local IP address or IP:port to bind outgoing TCP connections to, for `tcp:`, `ws://` and `wss://`
+    #[structopt(long="tcp-bind", parse(try_from_str = "interpret_tcp_bind"))]
+    tcp_bind: Option<SocketAddr>,
+
+    /// [A] Set SO_BINDTODEVICE for TCP sockets, using only the specified network interface. Linux only.
+    #[structopt(long="tcp-bind-device")]
+    tcp_bind_device: Option<String>,
+
+    /// [A] Set SO_MARK for TCP sockets, e.g. for policy routing. Linux only.
+    #[structopt(long="tcp-mark")]
+    tcp_mark: Option<u32>,
+
+    /// [A] Maximum length of the queue of pending incoming TCP connections
+    #[structopt(long="tcp-listen-backlog", default_value = "1024")]
+    tcp_listen_backlog: i32,
+
+    /// [A] Set SO_REUSEPORT for listening TCP sockets, allowing multiple processes to listen on the same port
+    #[structopt(long="tcp-reuseport")]
+    tcp_reuseport: bool,
+
+    /// [A] Expect PROXY protocol v1 or v2 header on incoming TCP connections and take client address from it.
+    /// Applies to `tcp-l:`, `listen-fd:` and WebSocket or TLS listeners on top of them. Connections without the header are dropped.
+    #[structopt(long="proxy-protocol")]
+    proxy_protocol: bool,
+
+    /// [A] In --proxy-protocol mode, only accept connections from this IP address or network, like `10.0.0.0/8`.
+    /// Can be specified multiple times.
+    #[structopt(long="proxy-protocol-trusted")]
+    proxy_protocol_trusted: Vec<websocat::options::IpNetwork>,
+
+    /// [A] Send PROXY protocol header (`v1` or `v2`) with the original client address when connecting with `tcp:`
+    #[structopt(long="proxy-protocol-send")]
+    proxy_protocol_send: Option<websocat::options::ProxyProtocolVersion>,
+
+    /// [A] Only accept connections from this IP address or network, like `192.168.0.0/16`.
+    /// Checked by `tcp-l:` and `listen-fd:` right after accepting, before any TLS or WebSocket handshake.
+    /// With --proxy-protocol, address from the PROXY header is checked. Can be specified multiple times.
+    #[structopt(long="allow-ip")]
+    allow_ip: Vec<websocat::options::IpNetwork>,
+
+    /// [A] Reject connections from this IP address or network. Takes precedence over --allow-ip.
+    /// Can be specified multiple times.
+    #[structopt(long="deny-ip")]
+    deny_ip: Vec<websocat::options::IpNetwork>,
+
+    /// [A] Reverse proxy address or network allowed to set `X-Forwarded-For` request header.
+    /// Connections from it are checked against --allow-ip and --deny-ip by the client address from the header
+    /// once WebSocket request arrives instead of right after accepting. Can be specified multiple times.
+    #[structopt(long="x-forwarded-for-trusted")]
+    x_forwarded_for_trusted: Vec<websocat::options::IpNetwork>,
+
     #[structopt(
         long = "unlink",
         help = "[A] Unlink listening UNIX socket before binding to it"
     )]
     unlink_unix_socket: bool,
 
+    /// [A] Set file mode of listening UNIX socket to this octal value, like `660`
+    #[structopt(long="chmod", parse(try_from_str = "interpret_file_mode"))]
+    unix_socket_mode: Option<u32>,
+
+    /// [A] Set owner of listening UNIX socket: `user`, `user:group` or `:group`, as names or numeric IDs
+    #[structopt(long="chown", parse(try_from_str = "interpret_chown"))]
+    unix_socket_chown: Option<(Option<u32>, Option<u32>)>,
+
+    /// [A] Only accept UNIX socket connections from processes of this user (name or uid).
+    /// Connections are also accepted if group matches --unix-allow-gid. Can be specified multiple times.
+    #[structopt(long="unix-allow-uid", parse(try_from_str = "interpret_uid"))]
+    unix_allow_uid: Vec<u32>,
+
+    /// [A] Only accept UNIX socket connections from processes of this group (name or gid).
+    /// Only primary group of the connecting process is checked. Can be specified multiple times.
+    #[structopt(long="unix-allow-gid", parse(try_from_str = "interpret_gid"))]
+    unix_allow_gid: Vec<u32>,
+
     #[structopt(
         long = "accept-from-fd",
-        help = "[A] Do not call `socket(2)` in UNIX socket listener peer, start with `accept(2)` using specified file descriptor number as argument instead of filename"
+        help = "[A] Do not call `socket(2)` in UNIX socket listener peer, start with `accept(2)` using specified file descriptor number as argument instead of filename. See also `listen-fd:` specifier."
     )]
     unix_socket_accept_from_fd: bool,
 
@@ -322,7 +417,7 @@ struct Opt {
     #[structopt(
         short = "e",
         long = "set-environment",
-        help = "Set WEBSOCAT_* environment variables when doing exec:/cmd:/sh-c:\nCurrently it's WEBSOCAT_URI and WEBSOCAT_CLIENT for\nrequest URI and client address (if TCP)\nBeware of ShellShock or similar security problems."
+        help = "Set WEBSOCAT_* environment variables when doing exec:/cmd:/sh-c:\nCurrently it's WEBSOCAT_URI and WEBSOCAT_CLIENT for\nrequest URI and client address (if TCP),\nWEBSOCAT_TLS_CLIENT_SUBJECT, WEBSOCAT_TLS_CLIENT_SAN and WEBSOCAT_TLS_CLIENT_FINGERPRINT\nfor verified TLS client certificate (see --tls-client-ca),\nWEBSOCAT_TLS_SERVER_NAME for server name requested by TLS client (SNI),\nWEBSOCAT_TLS_VERSION, WEBSOCAT_TLS_CIPHER and WEBSOCAT_TLS_ALPN for negotiated TLS parameters\n(with `ssl` feature only if OpenSSL is used directly because of --tls-client-ca, --tls-sni-cert or --tls-keylog),\nWEBSOCAT_PEER_UID, WEBSOCAT_PEER_GID and WEBSOCAT_PEER_PID for process connected to UNIX socket\nBeware of ShellShock or similar security problems."
     )]
     exec_set_env: bool,
 
@@ -332,6 +427,11 @@ struct Opt {
     )]
     reuser_send_zero_msg_on_disconnect: bool,
 
+    /// [A] Make `serial:` interpret incoming messages starting with this prefix as commands
+    /// (`break`, `dtr=on`, `dtr=off`, `rts=on`, `rts=off`) instead of writing them to the device
+    #[structopt(long = "serial-control-prefix")]
+    serial_control_prefix: Option<String>,
+
     #[structopt(
         long = "exec-sighup-on-zero-msg",
         help = "[A] Make exec: or sh-c: or cmd: send SIGHUP on UNIX when facing incoming zero-length message."
@@ -389,22 +489,22 @@ struct Opt {
     )]
     tls_domain: Option<String>,
 
-    #[cfg(feature = "ssl")]
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
     #[structopt(
         long = "pkcs12-der",
-        help = "Pkcs12 archive needed to accept SSL connections, certificate and key.\nA command to output it: openssl pkcs12 -export -out output.pkcs12 -inkey key.pem -in cert.pem\nUse with -s (--server-mode) option or with manually specified TLS overlays.\nSee moreexamples.md for more info.",
-        parse(try_from_os_str = "websocat::ssl_peer::interpret_pkcs12")
+        help = "Pkcs12 archive needed to accept SSL connections, certificate and key.\nA command to output it: openssl pkcs12 -export -out output.pkcs12 -inkey key.pem -in cert.pem\nAlternatively, use PEM files directly with --tls-cert and --tls-key.\nUse with -s (--server-mode) option or with manually specified TLS overlays.\nSee moreexamples.md for more info.",
+        parse(from_os_str)
     )]
-    pkcs12_der: Option<Vec<u8>>,
+    pkcs12_der: Option<PathBuf>,
 
-    #[cfg(feature = "ssl")]
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
     #[structopt(
         long = "pkcs12-passwd",
         help = "Password for --pkcs12-der pkcs12 archive. Required on Mac."
     )]
     pkcs12_passwd: Option<String>,
 
-    #[cfg(feature = "ssl")]
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
     #[structopt(
         long = "client-pkcs12-der",
         help = "[A] Client identity TLS certificate",
@@ -412,14 +512,84 @@ struct Opt {
     )]
     client_pkcs12_der: Option<Vec<u8>>,
 
-    #[cfg(feature = "ssl")]
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
     #[structopt(
         long = "client-pkcs12-passwd",
         help = "[A] Password for --client-pkcs12-der pkcs12 archive. Required on Mac."
     )]
     client_pkcs12_passwd: Option<String>,
 
-    #[cfg(feature = "ssl")]
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[structopt(
+        long = "tls-cert",
+        help = "PEM file with certificate (optionally followed by intermediate certificates) to accept TLS connections.\nAlternative to --pkcs12-der. Requires --tls-key.",
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[structopt(
+        long = "tls-key",
+        help = "PEM file with private key for --tls-cert",
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[structopt(
+        long = "tls-client-ca",
+        help = "[A] PEM file with CA certificates to verify TLS clients' certificates against.\nMakes TLS acceptor require a valid client certificate (mutual TLS).",
+        parse(from_os_str)
+    )]
+    tls_client_ca: Option<PathBuf>,
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[structopt(
+        long = "tls-client-cert-optional",
+        help = "[A] With --tls-client-ca, also accept TLS clients that do not present a certificate"
+    )]
+    tls_client_cert_optional: bool,
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[structopt(
+        long = "tls-sni-cert",
+        help = "[A] Use this certificate and key for TLS clients asking for specific server name (SNI).\nFormat: name:cert.pem:key.pem. Name may be a wildcard like `*.example.com`.\nCan be specified multiple times. Certificate from --tls-cert or --pkcs12-der is used for other names.",
+        parse(try_from_str = "interpret_tls_sni_cert")
+    )]
+    tls_sni_cert: Vec<TlsSniCert>,
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[structopt(
+        long = "tls-self-signed",
+        help = "Generate a temporary self-signed certificate for these comma-separated host names and IP addresses\nand use it to accept TLS connections. Alternative to --tls-cert and --pkcs12-der, for testing.\nIts SHA-256 fingerprint is printed at startup to be used with --tls-pin on the client side."
+    )]
+    tls_self_signed: Option<String>,
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[structopt(
+        long = "tls-self-signed-out",
+        help = "[A] Also save the --tls-self-signed certificate to this PEM file, e.g. to use it with --tls-ca",
+        parse(from_os_str)
+    )]
+    tls_self_signed_out: Option<PathBuf>,
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[structopt(
+        long = "client-tls-cert",
+        help = "[A] PEM file with client identity certificate chain. Alternative to --client-pkcs12-der. Requires --client-tls-key.",
+        parse(try_from_os_str = "websocat::ssl_peer::interpret_pem")
+    )]
+    client_tls_cert: Option<Vec<u8>>,
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[structopt(
+        long = "client-tls-key",
+        help = "[A] PEM file with private key for --client-tls-cert",
+        parse(try_from_os_str = "websocat::ssl_peer::interpret_pem")
+    )]
+    client_tls_key: Option<Vec<u8>>,
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
     #[structopt(
         long = "insecure",
         short = "k",
@@ -427,10 +597,63 @@ struct Opt {
     )]
     tls_insecure: bool,
 
-    /// Maximum number of simultaneous connections for listening mode
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[structopt(
+        long = "tls-ca",
+        help = "[A] PEM file or a directory of PEM files with additional CA certificates to trust when connecting to TLS servers.\nCan be specified multiple times.",
+        parse(try_from_os_str = "websocat::ssl_peer::interpret_pem_bundle")
+    )]
+    tls_ca: Vec<Vec<u8>>,
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[structopt(
+        long = "tls-no-system-ca",
+        help = "[A] Do not trust system (or built-in) CA certificates when connecting to TLS servers, only ones from --tls-ca"
+    )]
+    tls_no_system_ca: bool,
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[structopt(
+        long = "tls-pin",
+        help = "[A] Accept only TLS server whose certificate has this SHA-256 fingerprint (hex, colons allowed),\nor whose public key has this SHA-256 hash if prefixed with `spki:`. Usual CA and hostname checks are skipped then.\nCan be specified multiple times. A command to get the fingerprint: openssl x509 -in cert.pem -noout -fingerprint -sha256",
+        parse(try_from_str = "interpret_tls_pin")
+    )]
+    tls_pin: Vec<TlsPin>,
+
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    #[structopt(
+        long = "tls-keylog",
+        help = "[A] Append TLS secrets to this file (NSS key log format) to be able to decrypt traffic, e.g. in Wireshark.\nSSLKEYLOGFILE environment variable is also honoured.",
+        parse(from_os_str)
+    )]
+    tls_keylog: Option<PathBuf>,
+
+    /// Maximum number of simultaneous connections for listening mode.
+    /// WebSocket clients over the limit get HTTP 503 reply, other connections are dropped.
     #[structopt(long = "conncap")]
     max_parallel_conns: Option<usize>,
 
+    /// [A] Maximum number of simultaneous connections from one client IP address for listening mode.
+    /// WebSocket clients over the limit get HTTP 429 reply, other connections are dropped.
+    #[structopt(long = "conncap-per-ip")]
+    max_parallel_conns_per_ip: Option<usize>,
+
+    /// [A] Maximum rate of new connections from one client IP address, per second. Fractions like 0.1 are allowed.
+    /// WebSocket clients over the limit get HTTP 429 reply, other connections are dropped.
+    #[structopt(long = "conn-rate-per-ip")]
+    conn_rate_per_ip: Option<f64>,
+
+    /// [A] Number of connections one client IP address may make in a quick succession before --conn-rate-per-ip kicks in.
+    /// Default is the rate rounded up.
+    #[structopt(long = "conn-burst-per-ip")]
+    conn_burst_per_ip: Option<u32>,
+
+    /// [A] Reuse IP addresses that `tcp:`, `udp:` or `wss://` host name resolved to for this number of seconds.
+    /// Host names are resolved again on connections after that. 0 means resolving on every connection.
+    /// Each lookup that misses the cache runs in a separate short-lived thread.
+    #[structopt(long = "dns-cache-ttl", default_value = "30")]
+    dns_cache_ttl: u64,
+
     /// Send WebSocket pings each this number of seconds
     #[structopt(long = "ping-interval")]
     ws_ping_interval: Option<u64>,
@@ -641,6 +864,101 @@ struct Opt {
     #[structopt(long = "--lengthprefixed-skip-write-direction")]
     pub lengthprefixed_skip_write_direction: bool,
 
+    /// [A] Emit events from `sse:` overlay as JSON objects with `event`, `data` and `id` fields instead of just data.
+    #[structopt(long = "--sse-json")]
+    pub sse_json: bool,
+
+    /// [A] Initial delay before `sse:` overlay reconnects. Server may override it with `retry:` field.
+    #[structopt(long = "--sse-retry-millis", default_value = "3000")]
+    pub sse_retry_millis: u64,
+
+    /// [A] Make `sse:` overlay finish when the event stream ends instead of reconnecting.
+    #[structopt(long = "--sse-no-reconnect")]
+    pub sse_no_reconnect: bool,
+
+    /// [A] URI template for `webhook:` overlay requests. `{msg}` and `{seq}` get substituted. Should be absolute to get `Host:` header.
+    #[structopt(long = "--webhook-uri")]
+    pub webhook_uri: Option<String>,
+
+    /// [A] HTTP method template for `webhook:` overlay. Default is POST.
+    #[structopt(long = "--webhook-method")]
+    pub webhook_method: Option<String>,
+
+    /// [A] Add header template (like `X-Seq: {seq}`) to `webhook:` requests. May be specified multiple times.
+    #[structopt(long = "--webhook-header")]
+    pub webhook_headers: Vec<String>,
+
+    /// [A] Content-Type template for `webhook:` request bodies. Default is application/octet-stream.
+    #[structopt(long = "--webhook-content-type")]
+    pub webhook_content_type: Option<String>,
+
+    /// [A] Maximum number of simultaneous requests `webhook:` overlay issues.
+    #[structopt(long = "--webhook-concurrency", default_value = "1")]
+    pub webhook_concurrency: usize,
+
+    /// [A] Number of retries of `webhook:` requests that failed or got 5xx response.
+    #[structopt(long = "--webhook-retries", default_value = "3")]
+    pub webhook_retries: usize,
+
+    /// [A] Delay before the first retry of a `webhook:` request. Doubles after each subsequent retry.
+    #[structopt(long = "--webhook-retry-delay-millis", default_value = "500")]
+    pub webhook_retry_delay_millis: u64,
+
+    /// [A] Time limit for one attempt of a `webhook:` request, including reading the response.
+    /// Timed out attempts are retried like failed ones.
+    #[structopt(long = "--webhook-timeout-millis", default_value = "30000")]
+    pub webhook_timeout_millis: u64,
+
+    /// [A] Wrap `webhook:` replies in JSON objects with `status` and `body` fields.
+    /// Requests that failed after all retries give `{"status":0,"error":"..."}`.
+    #[structopt(long = "--webhook-status")]
+    pub webhook_status: bool,
+
+    /// [A] Maximum time `http-longpoll:` GET request waits for a message before returning 204.
+    #[structopt(long = "--longpoll-timeout-millis", default_value = "25000")]
+    pub longpoll_timeout_millis: u64,
+
+    /// [A] Close `http-longpoll:` sessions that got no requests for this long.
+    #[structopt(long = "--longpoll-idle-timeout-millis", default_value = "60000")]
+    pub longpoll_idle_timeout_millis: u64,
+
+    /// [A] Connect `socketio:` overlay to this Socket.IO namespace instead of `/`. May be specified multiple times.
+    #[structopt(long = "--socketio-namespace")]
+    pub socketio_namespaces: Vec<String>,
+
+    /// [A] JSON payload to send in Socket.IO CONNECT packets, e.g. `{"token":"..."}`
+    #[structopt(long = "--socketio-auth")]
+    pub socketio_auth: Option<String>,
+
+    /// [A] Represent Socket.IO events in `socketio:` overlay as JSON arrays like `["event",arg1,arg2]`
+    #[structopt(long = "--socketio-json")]
+    pub socketio_json: bool,
+
+    /// [A] JSON payload for `connection_init` message of `graphql:` overlay, e.g. `{"authToken":"..."}`
+    #[structopt(long = "--graphql-init-payload")]
+    pub graphql_init_payload: Option<String>,
+
+    /// [A] `login` header for CONNECT frame of `stomp:` overlay
+    #[structopt(long = "--stomp-login")]
+    pub stomp_login: Option<String>,
+
+    /// [A] `passcode` header for CONNECT frame of `stomp:` overlay
+    #[structopt(long = "--stomp-passcode")]
+    pub stomp_passcode: Option<String>,
+
+    /// [A] `host` header (virtual host) for CONNECT frame of `stomp:` overlay. Default is `/`.
+    #[structopt(long = "--stomp-host")]
+    pub stomp_host: Option<String>,
+
+    /// [A] STOMP heart-beat setting for `stomp:` overlay, as `outgoing,incoming` milliseconds. `0,0` disables heart-beating.
+    #[structopt(long = "--stomp-heartbeat", default_value = "10000,10000")]
+    pub stomp_heartbeat: String,
+
+    /// [A] Exit with the code of remote command reported by `k8s-exec:` overlay.
+    /// Only for single-session mode.
+    #[structopt(long = "--remote-exit-code")]
+    pub remote_exit_code: bool,
+
     /// Set `User-Agent` request header to this value. Similar to setting it with `-H`.
     #[structopt(long = "--ua")]
     pub useragent: Option<String>,
@@ -703,6 +1021,102 @@ fn interpret_static_file(x: &str) -> Result<StaticFile> {
     })
 }
 
+fn interpret_tcp_bind(x: &str) -> Result<SocketAddr> {
+    if let Ok(a) = x.parse() {
+        return Ok(a);
+    }
+    match x.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, 0)),
+        Err(_) => Err("Argument to --tcp-bind must be an IP address, optionally with a port")?,
+    }
+}
+
+fn interpret_file_mode(x: &str) -> Result<u32> {
+    match u32::from_str_radix(x, 8) {
+        Ok(m) if m <= 0o7777 => Ok(m),
+        _ => Err("Argument to --chmod must be an octal file mode like 660")?,
+    }
+}
+
+/// User or group name or numeric ID
+fn lookup_id(x: &str, group: bool) -> Result<u32> {
+    if let Ok(id) = x.parse() {
+        return Ok(id);
+    }
+    #[cfg(unix)]
+    {
+        let name = std::ffi::CString::new(x)?;
+        unsafe {
+            if group {
+                let g = libc::getgrnam(name.as_ptr());
+                if !g.is_null() {
+                    return Ok((*g).gr_gid);
+                }
+            } else {
+                let p = libc::getpwnam(name.as_ptr());
+                if !p.is_null() {
+                    return Ok((*p).pw_uid);
+                }
+            }
+        }
+    }
+    Err(format!("No such {} `{}`", if group { "group" } else { "user" }, x))?
+}
+
+fn interpret_uid(x: &str) -> Result<u32> {
+    lookup_id(x, false)
+}
+
+fn interpret_gid(x: &str) -> Result<u32> {
+    lookup_id(x, true)
+}
+
+fn interpret_chown(x: &str) -> Result<(Option<u32>, Option<u32>)> {
+    let (user, group) = match x.find(':') {
+        Some(i) => (&x[..i], &x[i + 1..]),
+        None => (x, ""),
+    };
+    let uid = if user.is_empty() { None } else { Some(interpret_uid(user)?) };
+    let gid = if group.is_empty() { None } else { Some(interpret_gid(group)?) };
+    if uid.is_none() && gid.is_none() {
+        Err("Argument to --chown must be `user`, `user:group` or `:group`")?
+    }
+    Ok((uid, gid))
+}
+
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+fn interpret_tls_pin(x: &str) -> Result<TlsPin> {
+    let (spki, h) = if let Some(h) = x.strip_prefix("spki:") {
+        (true, h)
+    } else {
+        (false, x)
+    };
+    let sha256 = hex::decode(h.replace(':', ""))?;
+    if sha256.len() != 32 {
+        Err("Argument to --tls-pin must be a SHA-256 hash: 64 hex digits")?
+    }
+    Ok(TlsPin { spki, sha256 })
+}
+
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+fn interpret_tls_sni_cert(x: &str) -> Result<TlsSniCert> {
+    let mut i = x.splitn(3, ':');
+    let (name, cert_file, key_file) = match (i.next(), i.next(), i.next()) {
+        (Some(n), Some(c), Some(k)) if !n.is_empty() && !c.is_empty() && !k.is_empty() => (n, c, k),
+        _ => Err("Argument to --tls-sni-cert must be in form name:cert.pem:key.pem")?,
+    };
+    let read = |f: &str| {
+        websocat::ssl_peer::interpret_pem(f.as_ref()).map_err(|e| format!("{}: {}", f, e.to_string_lossy()))
+    };
+    Ok(TlsSniCert {
+        name: name.to_ascii_lowercase(),
+        cert: read(cert_file)?,
+        key: read(key_file)?,
+        cert_file: cert_file.into(),
+        key_file: key_file.into(),
+    })
+}
+
 fn interpret_socks_destination(x: &str) -> Result<SocksSocketAddr> {
     let colon = x.rfind(':');
     let colon = if let Some(colon) = colon {
@@ -764,14 +1178,15 @@ mod logging {
 
 }
 
-fn run() -> Result<()> {
+/// Returns process exit code
+fn run() -> Result<i32> {
     if std::env::args().nth(1).unwrap_or_default() == "--long-help" {
         help::longhelp();
-        return Ok(());
+        return Ok(0);
     }
     if ["-?", "-h", "--help"].contains(&std::env::args().nth(1).unwrap_or_default().as_str()) {
         help::shorthelp();
-        return Ok(());
+        return Ok(0);
     }
     let mut logging_already_set = false;
     if std::env::var("WEBSOCAT_EARLY_LOG").is_ok() {
@@ -786,26 +1201,26 @@ fn run() -> Result<()> {
     if let Some(h) = cmd.help {
         if &h == "long" || &h == "full" || &h == "all" {
             help::longhelp();
-            return Ok(());
+            return Ok(0);
         } else if &h == "doc" {
             help::dochelp();
-            return Ok(());
+            return Ok(0);
         }
 
         help::shorthelp();
-        return Ok(());
+        return Ok(0);
     }
 
     if cmd.just_generate_key {
         println!("{}", websocket_base::header::WebSocketKey::new().serialize());
-        return Ok(());
+        return Ok(0);
     }
 
     if let Some(key) = cmd.just_generate_accept {
         use std::str::FromStr;
         let k = websocket_base::header::WebSocketKey::from_str(&key)?;
         println!("{}", websocket_base::header::WebSocketAccept::new(&k).serialize());
-        return Ok(());
+        return Ok(0);
     }
 
     let mut recommend_explicit_text_or_bin = false;
@@ -860,12 +1275,32 @@ fn run() -> Result<()> {
             udp_join_multicast_iface_v4
             udp_join_multicast_iface_v6
             udp_reuseaddr
+            udp_idle_timeout
+            udp_max_clients
+            tcp_nodelay
+            tcp_keepalive
+            tcp_keepalive_interval
+            tcp_keepalive_count
+            tcp_bind
+            tcp_bind_device
+            tcp_mark
+            tcp_listen_backlog
+            tcp_reuseport
+            proxy_protocol
+            proxy_protocol_trusted
+            proxy_protocol_send
+            allow_ip
+            deny_ip
+            x_forwarded_for_trusted
             unidirectional
             unidirectional_reverse
             exit_on_eof
             oneshot
             unlink_unix_socket
             unix_socket_accept_from_fd
+            unix_socket_mode
+            unix_allow_uid
+            unix_allow_gid
             exec_args
             ws_c_uri
             linemode_strip_newlines
@@ -885,6 +1320,7 @@ fn run() -> Result<()> {
             exec_set_env
             reuser_send_zero_msg_on_disconnect
             process_zero_sighup
+            serial_control_prefix
             process_exit_sighup
             process_exit_on_disconnect
             socks_destination
@@ -893,6 +1329,10 @@ fn run() -> Result<()> {
             socks5_user_pass
             tls_domain
             max_parallel_conns
+            max_parallel_conns_per_ip
+            conn_rate_per_ip
+            conn_burst_per_ip
+            dns_cache_ttl
             ws_ping_interval
             ws_ping_timeout
             request_uri
@@ -932,15 +1372,110 @@ fn run() -> Result<()> {
             lengthprefixed_little_endian
             lengthprefixed_skip_read_direction
             lengthprefixed_skip_write_direction
+            sse_json
+            sse_retry_millis
+            sse_no_reconnect
+            webhook_uri
+            webhook_method
+            webhook_headers
+            webhook_content_type
+            webhook_concurrency
+            webhook_retries
+            webhook_retry_delay_millis
+            webhook_timeout_millis
+            webhook_status
+            longpoll_timeout_millis
+            longpoll_idle_timeout_millis
+            socketio_namespaces
+            socketio_auth
+            socketio_json
+            graphql_init_payload
+            stomp_login
+            stomp_passcode
+            stomp_host
+            stomp_heartbeat
+            remote_exit_code
         );
-        #[cfg(feature = "ssl")]
+        if opts.tcp_keepalive.is_none()
+            && (opts.tcp_keepalive_interval.is_some() || opts.tcp_keepalive_count.is_some())
+        {
+            Err("--tcp-keepalive-interval and --tcp-keepalive-count require --tcp-keepalive")?;
+        }
+        if let Some((uid, gid)) = cmd.unix_socket_chown {
+            opts.unix_socket_owner = uid;
+            opts.unix_socket_group = gid;
+        }
+        if opts.conn_burst_per_ip.is_some() && opts.conn_rate_per_ip.is_none() {
+            Err("--conn-burst-per-ip requires --conn-rate-per-ip")?;
+        }
+        if opts.conn_rate_per_ip.is_some_and(|r| !(r > 0.0 && r.is_finite())) {
+            Err("--conn-rate-per-ip should be a positive number")?;
+        }
+        #[cfg(any(feature = "ssl", feature = "rustls"))]
         {
             opts! {
-                pkcs12_der
                 pkcs12_passwd
                 client_pkcs12_der
                 client_pkcs12_passwd
+                client_tls_cert
+                client_tls_key
+                tls_client_cert_optional
+                tls_sni_cert
                 tls_insecure
+                tls_ca
+                tls_no_system_ca
+                tls_pin
+            }
+            // Server identity files are remembered to be able to reload them later
+            let read = |x: &Option<PathBuf>, f: fn(&std::ffi::OsStr) -> std::result::Result<Vec<u8>, std::ffi::OsString>| {
+                x.as_ref()
+                    .map(|x| f(x.as_os_str()).map_err(|e| format!("{}: {}", x.display(), e.to_string_lossy())))
+                    .transpose()
+            };
+            opts.pkcs12_der = read(&cmd.pkcs12_der, websocat::ssl_peer::interpret_pkcs12)?;
+            opts.tls_cert = read(&cmd.tls_cert, websocat::ssl_peer::interpret_pem)?;
+            opts.tls_key = read(&cmd.tls_key, websocat::ssl_peer::interpret_pem)?;
+            opts.tls_client_ca = read(&cmd.tls_client_ca, websocat::ssl_peer::interpret_pem)?;
+            opts.pkcs12_der_file = cmd.pkcs12_der;
+            opts.tls_cert_file = cmd.tls_cert;
+            opts.tls_key_file = cmd.tls_key;
+            opts.tls_client_ca_file = cmd.tls_client_ca;
+
+            if let Some(names) = cmd.tls_self_signed {
+                if opts.pkcs12_der.is_some() || opts.tls_cert.is_some() {
+                    Err("--tls-self-signed is incompatible with --pkcs12-der and --tls-cert")?;
+                }
+                let names: Vec<String> = names
+                    .split(',')
+                    .map(|x| x.trim().to_owned())
+                    .filter(|x| !x.is_empty())
+                    .collect();
+                if names.is_empty() {
+                    Err("--tls-self-signed requires at least one host name or IP address")?;
+                }
+                let generated = websocat::ssl_peer::self_signed_cert(&names)?;
+                if let Some(ref f) = cmd.tls_self_signed_out {
+                    std::fs::write(f, &generated.cert_pem).map_err(|e| format!("{}: {}", f.display(), e))?;
+                }
+                if !quiet {
+                    eprintln!(
+                        "Generated self-signed TLS certificate for {}\nSHA-256 fingerprint: {}",
+                        names.join(", "),
+                        websocat::tls_common::fingerprint_to_string(&generated.sha256),
+                    );
+                }
+                opts.tls_cert = Some(generated.cert_pem);
+                opts.tls_key = Some(generated.key_pem);
+            } else if cmd.tls_self_signed_out.is_some() {
+                Err("--tls-self-signed-out requires --tls-self-signed")?;
+            }
+
+            if let Some(f) = cmd.tls_keylog.or_else(|| std::env::var_os("SSLKEYLOGFILE").map(PathBuf::from)) {
+                let keylog = websocat::tls_common::TlsKeyLog::open(&f).map_err(|e| format!("{}: {}", f.display(), e))?;
+                if !quiet {
+                    eprintln!("websocat: logging TLS secrets to {}", f.display());
+                }
+                opts.tls_keylog = Some(std::sync::Arc::new(keylog));
             }
         }
         #[cfg(feature = "crypto_peer")]
@@ -1014,9 +1549,9 @@ fn run() -> Result<()> {
             if cmd.server_mode {
                 #[allow(unused)]
                 let mut secure = false;
-                #[cfg(feature = "ssl")]
+                #[cfg(any(feature = "ssl", feature = "rustls"))]
                 {
-                    if opts.pkcs12_der.is_some() {
+                    if opts.pkcs12_der.is_some() || opts.tls_cert.is_some() {
                         secure = true;
                     }
                 }
@@ -1103,32 +1638,49 @@ fn run() -> Result<()> {
     let websocat = websocat2.parse2()?;
     debug!("Done fourth phase of interpreting options.");
 
+    if websocat.opts.remote_exit_code && websocat.s1.is_multiconnect() {
+        Err("--remote-exit-code can only be used when serving a single session")?;
+    }
+
     if cmd.dumpspec {
         println!("{:?}", websocat.s1);
         println!("{:?}", websocat.s2);
         println!("{:?}", websocat.opts);
-        return Ok(());
+        return Ok(0);
     }
 
     let mut core = tokio::runtime::current_thread::Runtime::new()?;
 
-    let error_handler = std::rc::Rc::new(move |e| {
+    let remote_exit_code = std::rc::Rc::new(std::cell::Cell::new(None));
+    let remote_exit_code2 = remote_exit_code.clone();
+    let error_handler = std::rc::Rc::new(move |e: Box<dyn std::error::Error>| {
+        if let Some(code) = websocat::exec_channel_peer::remote_exit_code(&*e) {
+            remote_exit_code2.set(Some(code));
+            return;
+        }
         if !quiet {
             eprintln!("websocat: {}", e);
         }
     });
     let prog = websocat.serve(error_handler);
     debug!("Preparation done. Now actually starting.");
-    core.block_on(prog)
-        .map_err(|()| "error running".to_string())?;
-    Ok(())
+    let ret = core.block_on(prog);
+    if let Some(code) = remote_exit_code.get() {
+        return Ok(code);
+    }
+    ret.map_err(|()| "error running".to_string())?;
+    Ok(0)
 }
 
 fn main() {
     let r = run();
 
-    if let Err(e) = r {
-        eprintln!("websocat: {}", e);
-        ::std::process::exit(1);
+    match r {
+        Err(e) => {
+            eprintln!("websocat: {}", e);
+            ::std::process::exit(1);
+        }
+        Ok(0) => (),
+        Ok(code) => ::std::process::exit(code),
     }
 }
@@ -7,39 +7,152 @@ use futures::unsync::oneshot::{channel, Receiver, Sender};
 use std;
 use std::io::Result as IoResult;
 use std::io::{Read, Write};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use tokio_io::{AsyncRead, AsyncWrite};
 
 use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use tokio_tcp::{TcpListener, TcpStream};
 use tokio_udp::UdpSocket;
 
+use super::ip_filter::IpFilter;
 use super::L2rUser;
-use super::{box_up_err, peer_err_s, wouldblock, BoxedNewPeerFuture, BoxedNewPeerStream, Peer};
+use super::{box_up_err, peer_err_s, wouldblock, BoxedNewPeerFuture, BoxedNewPeerStream, HupToken, Peer};
 use super::{multi, once, ConstructParams, Options, PeerConstructor, Specifier};
 
+/// `host:port` to connect to. Host names are resolved on each connection attempt.
 #[derive(Debug, Clone)]
-pub struct TcpConnect(pub Vec<SocketAddr>);
+pub struct HostPort {
+    pub host: String,
+    pub port: u16,
+}
+
+impl std::str::FromStr for HostPort {
+    type Err = Box<dyn std::error::Error>;
+    fn from_str(s: &str) -> Result<HostPort, Self::Err> {
+        if let Ok(a) = s.parse::<SocketAddr>() {
+            return Ok(HostPort {
+                host: a.ip().to_string(),
+                port: a.port(),
+            });
+        }
+        let (host, port) = s.rsplit_once(':').ok_or("Expected host:port")?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            Err("Empty host name")?;
+        }
+        Ok(HostPort {
+            host: host.to_owned(),
+            port: port.parse()?,
+        })
+    }
+}
+
+struct DnsCacheEntry {
+    resolved_at: Instant,
+    addrs: Vec<SocketAddr>,
+}
+
+/// Recently resolved host names, see `--dns-cache-ttl`
+#[derive(Default, Clone)]
+pub struct DnsCache(Rc<RefCell<HashMap<(String, u16), DnsCacheEntry>>>);
+
+/// Resolve host name in a separate thread, as tokio 0.1 does not have asynchronous DNS resolver
+pub fn resolve(
+    hp: &HostPort,
+    cache: &DnsCache,
+    opts: &Options,
+) -> Box<dyn Future<Item = Vec<SocketAddr>, Error = Box<dyn std::error::Error>>> {
+    if let Ok(ip) = hp.host.parse::<IpAddr>() {
+        return Box::new(futures::future::ok(vec![SocketAddr::new(ip, hp.port)]));
+    }
+    let key = (hp.host.clone(), hp.port);
+    let ttl = Duration::from_secs(opts.dns_cache_ttl);
+    if let Some(e) = cache.0.borrow().get(&key) {
+        if e.resolved_at.elapsed() < ttl {
+            debug!("Using cached IP addresses of {}", hp.host);
+            return Box::new(futures::future::ok(e.addrs.clone()));
+        }
+    }
+    info!("Resolving hostname to IP addresses");
+    let (tx, rx) = futures::sync::oneshot::channel();
+    let (host, port) = key.clone();
+    std::thread::spawn(move || {
+        use std::net::ToSocketAddrs;
+        let _ = tx.send((host.as_str(), port).to_socket_addrs().map(|x| x.collect::<Vec<_>>()));
+    });
+    let cache = cache.clone();
+    Box::new(
+        rx.map_err(|_| crate::simple_err2("DNS resolver thread failed"))
+            .and_then(move |r| {
+                let addrs = r?;
+                if addrs.is_empty() {
+                    Err("Failed to resolve this hostname to IP")?;
+                }
+                for addr in &addrs {
+                    info!("Got IP: {}", addr);
+                }
+                if !ttl.is_zero() {
+                    let e = DnsCacheEntry {
+                        resolved_at: Instant::now(),
+                        addrs: addrs.clone(),
+                    };
+                    cache.0.borrow_mut().insert(key, e);
+                }
+                Ok(addrs)
+            }),
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct TcpConnect(pub HostPort);
 impl Specifier for TcpConnect {
-    fn construct(&self, _: ConstructParams) -> PeerConstructor {
-        // FIXME: connect to multiple things
-        once(tcp_connect_peer(&self.0[..]))
+    fn construct(&self, p: ConstructParams) -> PeerConstructor {
+        let cache = p.global(DnsCache::default).clone();
+        let addrs = resolve(&self.0, &cache, &p.program_options);
+        let opts = p.program_options.clone();
+        let header = opts.proxy_protocol_send.map(|v| {
+            let addrs = match p.left_to_right {
+                L2rUser::ReadFrom(ref x) => {
+                    let parse = |a: &Option<String>| a.as_ref().and_then(|a| a.parse().ok());
+                    match (parse(&x.client_addr), parse(&x.server_addr)) {
+                        (Some(c), Some(s)) => Some((c, s)),
+                        _ => None,
+                    }
+                }
+                L2rUser::FillIn(_) => None,
+            };
+            crate::proxy_protocol::encode(v, addrs)
+        });
+        once(Box::new(addrs.and_then(move |addrs| {
+            let peer = tcp_connect_peer(&addrs, &opts);
+            match header {
+                Some(h) => Box::new(peer.and_then(|p| send_proxy_header(p, h))),
+                None => peer,
+            }
+        })))
     }
-    specifier_boilerplate!(noglobalstate singleconnect no_subspec );
+    specifier_boilerplate!(globalstate singleconnect no_subspec );
 }
 specifier_class!(
     name = TcpConnectClass,
     target = TcpConnect,
     prefixes = ["tcp:", "tcp-connect:", "connect-tcp:", "tcp-c:", "c-tcp:"],
-    arg_handling = parseresolve,
+    arg_handling = parse_hostport,
     overlay = false,
     StreamOriented,
     SingleConnect,
     help = r#"
 Connect to specified TCP host and port. Argument is a socket address.
 
+Host name is resolved each time a connection is made. See also --dns-cache-ttl.
+
+On UNIX connection resets end the session even if the connection is not being read
+(e.g. with `-u`). This costs one extra file descriptor per connection.
+
 Example: simulate netcat netcat
 
     websocat - tcp:127.0.0.1:22
@@ -54,7 +167,8 @@ Example: redirect websocket connections to local SSH server over IPv6
 pub struct TcpListen(pub SocketAddr);
 impl Specifier for TcpListen {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
-        multi(tcp_listen_peer(&self.0, p.left_to_right, p.program_options.announce_listens))
+        let filter = IpFilter::new(&p);
+        multi(tcp_listen_peer(&self.0, p.left_to_right, &p.program_options, filter))
     }
     specifier_boilerplate!(noglobalstate multiconnect no_subspec );
 }
@@ -68,6 +182,9 @@ specifier_class!(
     MultiConnect,
     help = r#"
 Listen TCP port on specified address.
+
+On UNIX connection resets end the session even if the connection is not being read
+(e.g. with `-u`). This costs one extra file descriptor per connection.
     
 Example: echo server
 
@@ -80,18 +197,21 @@ Example: redirect TCP to a websocket
 );
 
 #[derive(Debug, Clone)]
-pub struct UdpConnect(pub SocketAddr);
+pub struct UdpConnect(pub HostPort);
 impl Specifier for UdpConnect {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
-        once(udp_connect_peer(&self.0, &p.program_options))
+        let cache = p.global(DnsCache::default).clone();
+        let opts = p.program_options;
+        let addrs = resolve(&self.0, &cache, &opts);
+        once(Box::new(addrs.and_then(move |addrs| udp_connect_peer(&addrs[0], &opts))))
     }
-    specifier_boilerplate!(noglobalstate singleconnect no_subspec );
+    specifier_boilerplate!(globalstate singleconnect no_subspec );
 }
 specifier_class!(
     name = UdpConnectClass,
     target = UdpConnect,
     prefixes = ["udp:", "udp-connect:", "connect-udp:", "udp-c:", "c-udp:"],
-    arg_handling = parse,
+    arg_handling = parse_hostport,
     overlay = false,
     MessageOriented,
     SingleConnect,
@@ -124,7 +244,36 @@ remote UDP socket.
 Note that it is not a multiconnect specifier like e.g. `tcp-listen`:
 entire lifecycle of the UDP socket is the same connection.
 
-File a feature request on Github if you want proper DNS-like request-reply UDP mode here.
+See `udp-server:` for a separate connection for each client.
+"#
+);
+
+#[derive(Debug, Clone)]
+pub struct UdpServer(pub SocketAddr);
+impl Specifier for UdpServer {
+    fn construct(&self, p: ConstructParams) -> PeerConstructor {
+        multi(udp_server_peer(&self.0, p.left_to_right, &p.program_options))
+    }
+    specifier_boilerplate!(noglobalstate multiconnect no_subspec );
+}
+specifier_class!(
+    name = UdpServerClass,
+    target = UdpServer,
+    prefixes = ["udp-server:", "server-udp:", "udp-s:"],
+    arg_handling = parse,
+    overlay = false,
+    MessageOriented,
+    MultiConnect,
+    help = r#"
+Bind an UDP socket to specified host:port and treat each remote UDP socket
+sending packets to it as a separate connection. Replies go to that remote socket.
+
+Connection ends after --udp-idle-timeout seconds without packets in either direction.
+Number of simultaneous clients is limited by --udp-max-clients.
+
+Example: UDP-over-WebSocket gateway, with a WebSocket connection per UDP client
+
+    websocat -b udp-server:0.0.0.0:5353 ws://example.com/dns
 "#
 );
 
@@ -193,7 +342,175 @@ impl Drop for MyTcpStream {
     }
 }
 
-pub fn tcp_race(addrs: &[SocketAddr]) -> Box<dyn Future<Item = TcpStream, Error = Box<dyn std::error::Error + Send + Sync>> + Send> {
+/// Resolves with an error when TCP connection gets reset or fails, without reading from it.
+/// Watches a duplicate of the socket, so that the actual reader does not lose its wakeups.
+#[cfg(unix)]
+fn tcp_hup_token(s: &TcpStream) -> Option<HupToken> {
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    let fd = unsafe { libc::dup(s.as_raw_fd()) };
+    if fd == -1 {
+        warn!("Failed to duplicate TCP socket for reset detection: {}", std::io::Error::last_os_error());
+        return None;
+    }
+    let dup = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    let dup = match TcpStream::from_std(dup, &tokio_reactor::Handle::default()) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to watch TCP socket for resets: {}", e);
+            return None;
+        }
+    };
+    // HUP readiness is always included, error readiness needs to be requested
+    let mask = mio::unix::UnixReady::error().into();
+    Some(Box::new(futures::future::poll_fn(move || {
+        futures::try_ready!(dup.poll_read_ready(mask));
+        // Borrow the descriptor as std socket just to read SO_ERROR
+        let s = std::mem::ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_fd(dup.as_raw_fd()) });
+        match s.take_error()? {
+            Some(e) => {
+                info!("TCP connection failed: {}", e);
+                Err(Box::new(e) as Box<dyn std::error::Error>)
+            }
+            // Orderly close in both directions or the error is already taken by reader.
+            // Leave it to the usual EOF handling, without polling again.
+            None => Ok(futures::Async::NotReady),
+        }
+    })))
+}
+
+#[cfg(not(unix))]
+fn tcp_hup_token(_s: &TcpStream) -> Option<HupToken> {
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+fn setsockopt(fd: std::os::unix::io::RawFd, level: libc::c_int, name: libc::c_int, value: &[u8]) -> IoResult<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value.as_ptr() as *const libc::c_void,
+            value.len() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn unsupported_option(name: &str) -> std::io::Error {
+    std::io::Error::other(format!("{} is not supported on this platform", name))
+}
+
+/// `--tcp-bind-device` and `--tcp-mark`, to be set before binding or connecting
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn apply_tcp_routing_options(b: &net2::TcpBuilder, opts: &Options) -> IoResult<()> {
+    use std::os::unix::io::AsRawFd;
+    if let Some(ref dev) = opts.tcp_bind_device {
+        setsockopt(b.as_raw_fd(), libc::SOL_SOCKET, libc::SO_BINDTODEVICE, dev.as_bytes())?;
+    }
+    if let Some(mark) = opts.tcp_mark {
+        setsockopt(b.as_raw_fd(), libc::SOL_SOCKET, libc::SO_MARK, &mark.to_ne_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn apply_tcp_routing_options(_b: &net2::TcpBuilder, opts: &Options) -> IoResult<()> {
+    if opts.tcp_bind_device.is_some() {
+        return Err(unsupported_option("--tcp-bind-device"));
+    }
+    if opts.tcp_mark.is_some() {
+        return Err(unsupported_option("--tcp-mark"));
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+fn apply_tcp_keepalive_probes(s: &TcpStream, opts: &Options) -> IoResult<()> {
+    use std::os::unix::io::AsRawFd;
+    if let Some(x) = opts.tcp_keepalive_interval {
+        let x = x as libc::c_int;
+        setsockopt(s.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, &x.to_ne_bytes())?;
+    }
+    if let Some(x) = opts.tcp_keepalive_count {
+        let x = x as libc::c_int;
+        setsockopt(s.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_KEEPCNT, &x.to_ne_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd")))]
+fn apply_tcp_keepalive_probes(_s: &TcpStream, opts: &Options) -> IoResult<()> {
+    if opts.tcp_keepalive_interval.is_some() {
+        return Err(unsupported_option("--tcp-keepalive-interval"));
+    }
+    if opts.tcp_keepalive_count.is_some() {
+        return Err(unsupported_option("--tcp-keepalive-count"));
+    }
+    Ok(())
+}
+
+/// Options for connected or accepted TCP socket
+fn apply_tcp_options(s: &TcpStream, opts: &Options) -> IoResult<()> {
+    if opts.tcp_nodelay {
+        s.set_nodelay(true)?;
+    }
+    if let Some(idle) = opts.tcp_keepalive {
+        s.set_keepalive(Some(Duration::from_secs(idle)))?;
+        apply_tcp_keepalive_probes(s, opts)?;
+    }
+    Ok(())
+}
+
+/// Not yet connected socket for `tcp_race`, with options that need to be set before connecting
+fn get_tcp_client_socket(addr: &SocketAddr, opts: &Options) -> IoResult<std::net::TcpStream> {
+    let b = match addr {
+        SocketAddr::V4(_) => net2::TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => net2::TcpBuilder::new_v6()?,
+    };
+    apply_tcp_routing_options(&b, opts)?;
+    if let Some(src) = opts.tcp_bind {
+        if src.is_ipv4() != addr.is_ipv4() {
+            return Err(std::io::Error::other(format!(
+                "Cannot connect to {} from --tcp-bind address {}",
+                addr, src
+            )));
+        }
+        if src.port() != 0 {
+            // Allow reconnecting from the same port while the old connection is in TIME_WAIT
+            b.reuse_address(true)?;
+        }
+        b.bind(src)?;
+    }
+    b.to_tcp_stream()
+}
+
+fn get_tcp_listener(addr: &SocketAddr, opts: &Options) -> IoResult<TcpListener> {
+    let b = match addr {
+        SocketAddr::V4(_) => net2::TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => net2::TcpBuilder::new_v6()?,
+    };
+    // like `TcpListener::bind` does
+    if cfg!(unix) {
+        b.reuse_address(true)?;
+    }
+    if opts.tcp_reuseport {
+        #[cfg(unix)]
+        net2::unix::UnixTcpBuilderExt::reuse_port(&b, true)?;
+        #[cfg(not(unix))]
+        return Err(unsupported_option("--tcp-reuseport"));
+    }
+    apply_tcp_routing_options(&b, opts)?;
+    b.bind(addr)?;
+    let l = b.listen(opts.tcp_listen_backlog)?;
+    TcpListener::from_std(l, &tokio_reactor::Handle::default())
+}
+
+pub fn tcp_race(addrs: &[SocketAddr], opts: &Options) -> Box<dyn Future<Item = TcpStream, Error = Box<dyn std::error::Error + Send + Sync>> + Send> {
     // Apply Happy Eyeballs in case of multiple proposed addresses.
     if addrs.len() > 1 {
         debug!("Setting up a race between multiple TCP client sockets. Who connects the first?");
@@ -202,8 +519,12 @@ pub fn tcp_race(addrs: &[SocketAddr]) -> Box<dyn Future<Item = TcpStream, Error
     let mut fu = FuturesUnordered::new();
     for addr in addrs {
         let addr = *addr;
+        let connect = match get_tcp_client_socket(&addr, opts) {
+            Ok(x) => futures::future::Either::A(TcpStream::connect_std(x, &addr, &tokio_reactor::Handle::default())),
+            Err(e) => futures::future::Either::B(futures::future::err(e)),
+        };
         fu.push(
-            TcpStream::connect(&addr)
+            connect
             .map(move |x| {
                 info!("Connected to TCP {}", addr);
                 x
@@ -230,16 +551,20 @@ pub fn tcp_race(addrs: &[SocketAddr]) -> Box<dyn Future<Item = TcpStream, Error
     Box::new(p)
 }
 
-pub fn tcp_connect_peer(addrs: &[SocketAddr]) -> BoxedNewPeerFuture {
-    let p = tcp_race(addrs)
-    .map(|x : TcpStream| {
+pub fn tcp_connect_peer(addrs: &[SocketAddr], opts: &Rc<Options>) -> BoxedNewPeerFuture {
+    let opts = opts.clone();
+    let p = tcp_race(addrs, &opts)
+    .map_err(|e|{let e : Box<dyn std::error::Error> = e; e})
+    .and_then(move |x : TcpStream| {
+        apply_tcp_options(&x, &opts)?;
+        let hup = tcp_hup_token(&x);
         let x = Rc::new(x);
-        Peer::new(
+        Ok(Peer::new(
             MyTcpStream(x.clone(), true),
             MyTcpStream(x.clone(), false),
-            None /* TODO */
-        )
-    }).map_err(|e|{let e : Box<dyn std::error::Error> = e; e});
+            hup,
+        ))
+    });
     /*let p = fu.into_future().and_then(|(x, _losers)| {
         let peer = x.unwrap();
         debug!("We have a winner. Disconnecting losers.");
@@ -249,43 +574,151 @@ pub fn tcp_connect_peer(addrs: &[SocketAddr]) -> BoxedNewPeerFuture {
     Box::new(p) as BoxedNewPeerFuture
 }
 
-pub fn tcp_listen_peer(addr: &SocketAddr, l2r: L2rUser, announce: bool) -> BoxedNewPeerStream {
-    let bound = match TcpListener::bind(addr) {
+pub fn tcp_listen_peer(
+    addr: &SocketAddr,
+    l2r: L2rUser,
+    opts: &Rc<Options>,
+    filter: IpFilter,
+) -> BoxedNewPeerStream {
+    let bound = match get_tcp_listener(addr, opts) {
         Ok(x) => x,
         Err(e) => return peer_err_s(e),
     };
     debug!("Listening TCP socket");
-    if announce {
+    if opts.announce_listens {
         println!("LISTEN proto=tcp,ip={},port={}", addr.ip(), addr.port());
     }
+    tcp_accept_peers(bound, l2r, opts, filter)
+}
+
+/// Accepted socket with client and server address
+type AcceptedTcp = (TcpStream, Option<SocketAddr>, Option<SocketAddr>);
+
+/// Connections from already listening TCP socket
+pub fn tcp_accept_peers(
+    bound: TcpListener,
+    l2r: L2rUser,
+    opts: &Rc<Options>,
+    filter: IpFilter,
+) -> BoxedNewPeerStream {
     use tk_listen::ListenExt;
+    let incoming = bound
+        .incoming()
+        .sleep_on_error(::std::time::Duration::from_millis(500));
+    let accepted: Box<dyn Stream<Item = AcceptedTcp, Error = ()>> = if opts.proxy_protocol {
+        Box::new(ProxiedConnections {
+            incoming: Some(Box::new(incoming)),
+            pending: futures::stream::FuturesUnordered::new(),
+            opts: opts.clone(),
+            filter: filter.clone(),
+        })
+    } else {
+        Box::new(incoming.map(|x| {
+            let addr = x.peer_addr().ok();
+            info!("Incoming TCP connection from {:?}", addr);
+            let server_addr = x.local_addr().ok();
+            (x, addr, server_addr)
+        }))
+    };
+    let opts = opts.clone();
     Box::new(
-        bound
-            .incoming()
-            .sleep_on_error(::std::time::Duration::from_millis(500))
-            .map(move |x| {
-                let addr = x.peer_addr().ok();
-                info!("Incoming TCP connection from {:?}", addr);
+        accepted
+            .filter(move |&(_, addr, _)| filter.check_accepted(addr))
+            .map(move |(x, addr, server_addr)| {
+                if let Err(e) = apply_tcp_options(&x, &opts) {
+                    warn!("Failed to set TCP socket options: {}", e);
+                }
 
                 match l2r {
                     L2rUser::FillIn(ref y) => {
                         let mut z = y.borrow_mut();
                         z.client_addr = addr.map(|a| format!("{}", a));
+                        z.server_addr = server_addr.map(|a| format!("{}", a));
                     }
                     L2rUser::ReadFrom(_) => {}
                 }
 
+                let hup = tcp_hup_token(&x);
                 let x = Rc::new(x);
                 Peer::new(
                     MyTcpStream(x.clone(), true),
                     MyTcpStream(x.clone(), false),
-                    None, /* TODO */
+                    hup,
                 )
             })
             .map_err(|()| crate::simple_err2("unreachable error?")),
     ) as BoxedNewPeerStream
 }
 
+type ProxiedConnection = Box<dyn Future<Item = Option<AcceptedTcp>, Error = ()>>;
+
+/// `--proxy-protocol`: connections with PROXY headers read. Headers are awaited independently,
+/// so clients that are slow to send them do not hold up others.
+struct ProxiedConnections {
+    incoming: Option<Box<dyn Stream<Item = TcpStream, Error = ()>>>,
+    pending: futures::stream::FuturesUnordered<ProxiedConnection>,
+    opts: Rc<Options>,
+    filter: IpFilter,
+}
+
+impl Stream for ProxiedConnections {
+    type Item = AcceptedTcp;
+    type Error = ();
+    fn poll(&mut self) -> futures::Poll<Option<AcceptedTcp>, ()> {
+        while let Some(ref mut incoming) = self.incoming {
+            match incoming.poll()? {
+                futures::Async::Ready(Some(x)) => self.pending.push(proxied_connection(x, &self.opts, &self.filter)),
+                futures::Async::Ready(None) => self.incoming = None,
+                futures::Async::NotReady => break,
+            }
+        }
+        loop {
+            match self.pending.poll()? {
+                futures::Async::Ready(Some(Some(x))) => return Ok(futures::Async::Ready(Some(x))),
+                futures::Async::Ready(Some(None)) => continue,
+                futures::Async::Ready(None) if self.incoming.is_none() => return Ok(futures::Async::Ready(None)),
+                _ => return Ok(futures::Async::NotReady),
+            }
+        }
+    }
+}
+
+/// `--proxy-protocol`: take addresses from PROXY header. `None` if connection is rejected.
+fn proxied_connection(x: TcpStream, opts: &Options, filter: &IpFilter) -> ProxiedConnection {
+    let peer = x.peer_addr().ok();
+    let local = x.local_addr().ok();
+    let trusted = &opts.proxy_protocol_trusted;
+    if !trusted.is_empty() && !peer.is_some_and(|a| trusted.iter().any(|n| n.contains(a.ip()))) {
+        warn!("Rejecting TCP connection from {:?}: not in --proxy-protocol-trusted", peer);
+        filter.count_rejection();
+        return Box::new(futures::future::ok(None));
+    }
+    Box::new(crate::proxy_protocol::read_header(x).then(move |r| match r {
+        Ok((x, Some((client, server)))) => {
+            info!("Incoming TCP connection from {} via {:?}", client, peer);
+            Ok(Some((x, Some(client), Some(server))))
+        }
+        Ok((x, None)) => {
+            info!("Incoming TCP connection from {:?}, PROXY header without addresses", peer);
+            Ok(Some((x, peer, local)))
+        }
+        Err(e) => {
+            warn!("Rejecting TCP connection from {:?}: {}", peer, e);
+            Ok(None)
+        }
+    }))
+}
+
+/// `--proxy-protocol-send`: write PROXY header with addresses of the incoming connection being relayed
+fn send_proxy_header(peer: Peer, header: Vec<u8>) -> BoxedNewPeerFuture {
+    let Peer(r, w, hup) = peer;
+    Box::new(
+        tokio_io::io::write_all(w, header)
+            .map(move |(w, _)| Peer(r, w, hup))
+            .map_err(box_up_err),
+    )
+}
+
 #[derive(Debug)]
 enum UdpPeerState {
     ConnectMode,
@@ -418,6 +851,281 @@ pub fn udp_listen_peer(addr: &SocketAddr, opts: &Rc<Options>) -> BoxedNewPeerFut
     )) as BoxedNewPeerFuture
 }
 
+/// Maximum number of packets queued for one `udp-server:` client, and for sending to all of them.
+/// Incoming packets above it are dropped, writers wait for outgoing ones.
+const UDP_SERVER_QUEUE_LEN: usize = 64;
+
+/// Shared by `udp-server:` listener, its clients and the task that receives packets for all of them
+struct UdpServerState {
+    s: UdpSocket,
+    clients: HashMap<SocketAddr, Rc<RefCell<UdpServerClient>>>,
+    /// Clients that sent their first packet, but are not yet handed to a session
+    new_clients: VecDeque<(SocketAddr, Rc<RefCell<UdpServerClient>>)>,
+    /// Packets from all clients' sessions, sent by the receiver task, as only one task
+    /// can wait for the socket to become writable
+    outgoing: VecDeque<(SocketAddr, Vec<u8>)>,
+    /// Sessions waiting for a room in `outgoing`
+    writers: Vec<futures::task::Task>,
+    listening: bool,
+    acceptor: Option<futures::task::Task>,
+    receiver: Option<futures::task::Task>,
+    buffer_size: usize,
+    idle_timeout: Duration,
+    max_clients: usize,
+}
+
+struct UdpServerClient {
+    packets: VecDeque<Vec<u8>>,
+    reader: Option<futures::task::Task>,
+    idle: tokio_timer::Delay,
+    expired: bool,
+}
+
+impl UdpServerClient {
+    fn touch(&mut self, idle_timeout: Duration) {
+        self.idle.reset(Instant::now() + idle_timeout);
+    }
+}
+
+impl UdpServerState {
+    fn send_outgoing(&mut self) {
+        let had_room = self.outgoing.len() < UDP_SERVER_QUEUE_LEN;
+        while let Some((addr, p)) = self.outgoing.pop_front() {
+            match self.s.send_to2(&p, &addr) {
+                Ok(_) => (),
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    self.outgoing.push_front((addr, p));
+                    break;
+                }
+                Err(e) => warn!("UDP server: failed to send to {}: {}", addr, e),
+            }
+        }
+        if !had_room && self.outgoing.len() < UDP_SERVER_QUEUE_LEN {
+            for t in self.writers.drain(..) {
+                t.notify();
+            }
+        }
+    }
+}
+
+pub fn udp_server_peer(addr: &SocketAddr, l2r: L2rUser, opts: &Rc<Options>) -> BoxedNewPeerStream {
+    let s = match get_udp(addr, opts).and_then(|x| apply_udp_options(&x, opts).map(|()| x)) {
+        Ok(x) => x,
+        Err(e) => return peer_err_s(e),
+    };
+    debug!("Ready for serving UDP clients");
+    if opts.announce_listens {
+        println!("LISTEN proto=udp,ip={},port={}", addr.ip(), addr.port());
+    }
+    let state = Rc::new(RefCell::new(UdpServerState {
+        s,
+        clients: HashMap::new(),
+        new_clients: VecDeque::new(),
+        outgoing: VecDeque::new(),
+        writers: vec![],
+        listening: true,
+        acceptor: None,
+        receiver: None,
+        buffer_size: opts.buffer_size,
+        idle_timeout: Duration::from_secs(opts.udp_idle_timeout),
+        max_clients: opts.udp_max_clients,
+    }));
+    crate::spawn_hack(UdpServerReceiver(state.clone()));
+    Box::new(UdpServerListener { state, l2r })
+}
+
+/// Receives all packets arriving to `udp-server:` socket and distributes them to clients.
+/// Also sends packets queued by clients' sessions.
+struct UdpServerReceiver(Rc<RefCell<UdpServerState>>);
+
+impl Future for UdpServerReceiver {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> futures::Poll<(), ()> {
+        if Rc::strong_count(&self.0) == 1 {
+            debug!("UDP server socket is no longer needed");
+            self.0.borrow_mut().send_outgoing();
+            return Ok(futures::Async::Ready(()));
+        }
+        let mut st = self.0.borrow_mut();
+        let st = &mut *st;
+        st.receiver = Some(futures::task::current());
+        st.send_outgoing();
+        let mut buf = vec![0; st.buffer_size];
+        loop {
+            let (n, addr) = match st.s.recv_from2(&mut buf) {
+                Ok(x) => x,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(futures::Async::NotReady),
+                Err(e) => {
+                    warn!("UDP server: {}", e);
+                    continue;
+                }
+            };
+            let client = if let Some(c) = st.clients.get(&addr) {
+                c.clone()
+            } else if !st.listening {
+                debug!("Ignoring UDP packet from new client {} after listening is over", addr);
+                continue;
+            } else if st.clients.len() >= st.max_clients {
+                warn!("Dropping UDP packet from new client {} because of --udp-max-clients", addr);
+                continue;
+            } else {
+                info!("New UDP client {}", addr);
+                let c = Rc::new(RefCell::new(UdpServerClient {
+                    packets: VecDeque::new(),
+                    reader: None,
+                    idle: tokio_timer::Delay::new(Instant::now() + st.idle_timeout),
+                    expired: false,
+                }));
+                st.clients.insert(addr, c.clone());
+                st.new_clients.push_back((addr, c.clone()));
+                if let Some(ref t) = st.acceptor {
+                    t.notify();
+                }
+                c
+            };
+            let mut c = client.borrow_mut();
+            if c.packets.len() >= UDP_SERVER_QUEUE_LEN {
+                debug!("Dropping UDP packet from client {} because of full queue", addr);
+                continue;
+            }
+            c.packets.push_back(buf[..n].to_vec());
+            c.touch(st.idle_timeout);
+            if let Some(ref t) = c.reader {
+                t.notify();
+            }
+        }
+    }
+}
+
+struct UdpServerListener {
+    state: Rc<RefCell<UdpServerState>>,
+    l2r: L2rUser,
+}
+
+impl Stream for UdpServerListener {
+    type Item = Peer;
+    type Error = Box<dyn std::error::Error>;
+    fn poll(&mut self) -> futures::Poll<Option<Peer>, Self::Error> {
+        let mut st = self.state.borrow_mut();
+        st.acceptor = Some(futures::task::current());
+        let (addr, client) = match st.new_clients.pop_front() {
+            Some(x) => x,
+            None => return Ok(futures::Async::NotReady),
+        };
+        if let L2rUser::FillIn(ref y) = self.l2r {
+            y.borrow_mut().client_addr = Some(format!("{}", addr));
+        }
+        let h = Rc::new(UdpServerClientHandle {
+            server: self.state.clone(),
+            addr,
+            client,
+        });
+        Ok(futures::Async::Ready(Some(Peer::new(
+            UdpServerClientReader(h.clone()),
+            UdpServerClientWriter(h),
+            None,
+        ))))
+    }
+}
+
+impl Drop for UdpServerListener {
+    fn drop(&mut self) {
+        let mut st = self.state.borrow_mut();
+        st.listening = false;
+        for (addr, _) in st.new_clients.drain(..).collect::<Vec<_>>() {
+            st.clients.remove(&addr);
+        }
+        if let Some(ref t) = st.receiver {
+            t.notify();
+        }
+    }
+}
+
+struct UdpServerClientHandle {
+    server: Rc<RefCell<UdpServerState>>,
+    addr: SocketAddr,
+    client: Rc<RefCell<UdpServerClient>>,
+}
+
+impl UdpServerClientHandle {
+    fn forget(&self) {
+        let mut st = self.server.borrow_mut();
+        if st.clients.get(&self.addr).is_some_and(|c| Rc::ptr_eq(c, &self.client)) {
+            st.clients.remove(&self.addr);
+        }
+    }
+}
+
+impl Drop for UdpServerClientHandle {
+    fn drop(&mut self) {
+        debug!("UDP client {} is gone", self.addr);
+        self.forget();
+        if let Some(ref t) = self.server.borrow().receiver {
+            t.notify();
+        }
+    }
+}
+
+struct UdpServerClientReader(Rc<UdpServerClientHandle>);
+struct UdpServerClientWriter(Rc<UdpServerClientHandle>);
+
+impl Read for UdpServerClientReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let mut c = self.0.client.borrow_mut();
+        if let Some(p) = c.packets.pop_front() {
+            let n = p.len().min(buf.len());
+            buf[..n].copy_from_slice(&p[..n]);
+            return Ok(n);
+        }
+        if c.expired {
+            return Ok(0);
+        }
+        c.reader = Some(futures::task::current());
+        match c.idle.poll() {
+            Ok(futures::Async::NotReady) => wouldblock(),
+            Ok(futures::Async::Ready(())) => {
+                info!("UDP client {} timed out", self.0.addr);
+                c.expired = true;
+                drop(c);
+                self.0.forget();
+                Ok(0)
+            }
+            Err(e) => Err(std::io::Error::other(e)),
+        }
+    }
+}
+
+impl Write for UdpServerClientWriter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let mut st = self.0.server.borrow_mut();
+        if st.outgoing.len() >= UDP_SERVER_QUEUE_LEN {
+            if !st.writers.iter().any(|t| t.will_notify_current()) {
+                st.writers.push(futures::task::current());
+            }
+            return wouldblock();
+        }
+        st.outgoing.push_back((self.0.addr, buf.to_vec()));
+        if let Some(ref t) = st.receiver {
+            t.notify();
+        }
+        self.0.client.borrow_mut().touch(st.idle_timeout);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for UdpServerClientReader {}
+
+impl AsyncWrite for UdpServerClientWriter {
+    fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+        Ok(().into())
+    }
+}
+
 impl Read for UdpPeerHandle {
     fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
         let mut p = self.0.borrow_mut();
@@ -528,3 +1236,40 @@ impl UndeprecateNonpollSendRecv for UdpSocket {
         }
     }
 }
+
+#[test]
+fn test_dns_cache() {
+    let opts = Options {
+        dns_cache_ttl: 30,
+        ..Default::default()
+    };
+    let hp: HostPort = "localhost:80".parse().unwrap();
+    let cache = DnsCache::default();
+    let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+    let addrs = rt.block_on(resolve(&hp, &cache, &opts)).unwrap();
+    assert!(!addrs.is_empty());
+    assert!(cache.0.borrow().contains_key(&("localhost".to_owned(), 80)));
+
+    // Cache hit is ready immediately, without a resolver thread
+    let fake: SocketAddr = "192.0.2.1:80".parse().unwrap();
+    cache.0.borrow_mut().get_mut(&("localhost".to_owned(), 80)).unwrap().addrs = vec![fake];
+    match resolve(&hp, &cache, &opts).poll() {
+        Ok(futures::Async::Ready(x)) => assert_eq!(x, vec![fake]),
+        _ => panic!("expected cached result"),
+    }
+
+    // Expired entry gets resolved and replaced
+    let old = Instant::now().checked_sub(Duration::from_secs(31)).unwrap();
+    cache.0.borrow_mut().get_mut(&("localhost".to_owned(), 80)).unwrap().resolved_at = old;
+    assert_eq!(rt.block_on(resolve(&hp, &cache, &opts)).unwrap(), addrs);
+    assert!(cache.0.borrow()[&("localhost".to_owned(), 80)].resolved_at > old);
+
+    // Zero TTL disables caching
+    let opts = Options {
+        dns_cache_ttl: 0,
+        ..Default::default()
+    };
+    let hp: HostPort = "localhost:81".parse().unwrap();
+    rt.block_on(resolve(&hp, &cache, &opts)).unwrap();
+    assert!(!cache.0.borrow().contains_key(&("localhost".to_owned(), 81)));
+}
@@ -1,9 +1,12 @@
+pub use super::proxy_protocol::ProxyProtocolVersion;
 pub use super::socks5_peer::SocksSocketAddr;
 
 use super::readdebt::DebtHandling;
 
 use std::ffi::OsString;
-use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 
 #[derive(Debug, Clone)]
 pub struct StaticFile {
@@ -12,10 +15,75 @@ pub struct StaticFile {
     pub content_type: String,
 }
 
+/// `--tls-pin`: SHA-256 hash of TLS server certificate or of its public key
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsPin {
+    pub spki: bool,
+    pub sha256: Vec<u8>,
+}
+
+/// IP address with optional prefix length, like `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpNetwork {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl FromStr for IpNetwork {
+    type Err = Box<dyn std::error::Error>;
+    fn from_str(s: &str) -> Result<IpNetwork, Self::Err> {
+        let (addr, prefix) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(x) => x.parse()?,
+            None => max,
+        };
+        if prefix > max {
+            Err(format!("Prefix length {} is too big for {}", prefix, addr))?;
+        }
+        Ok(IpNetwork { addr, prefix })
+    }
+}
+
+impl IpNetwork {
+    /// IPv4-mapped IPv6 addresses, as seen on IPv6 sockets, are matched against IPv4 networks
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(x) => x.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            x => x,
+        };
+        let (net, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(n), IpAddr::V4(a)) => (u32::from(n) as u128, u32::from(a) as u128, 32),
+            (IpAddr::V6(n), IpAddr::V6(a)) => (u128::from(n), u128::from(a), 128),
+            _ => return false,
+        };
+        let shift = bits - u32::from(self.prefix);
+        shift >= bits || net >> shift == ip >> shift
+    }
+}
+
+/// `--tls-sni-cert`: certificate and key to use for TLS clients asking for a specific server name
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct TlsSniCert {
+    /// Server name, possibly with a wildcard like `*.example.com`
+    pub name: String,
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    #[derivative(Debug = "ignore")]
+    pub cert: Vec<u8>,
+    #[derivative(Debug = "ignore")]
+    pub key: Vec<u8>,
+}
+
 extern crate http_bytes;
 use http_bytes::http;
 
-#[derive(SmartDefault, Derivative)]
+#[derive(SmartDefault, Derivative, Clone)]
 #[derivative(Debug)]
 pub struct Options {
     pub websocket_text_mode: bool,
@@ -29,6 +97,26 @@ pub struct Options {
     pub udp_join_multicast_iface_v4: Vec<std::net::Ipv4Addr>,
     pub udp_join_multicast_iface_v6: Vec<u32>,
     pub udp_reuseaddr: bool,
+    #[default = 60]
+    pub udp_idle_timeout: u64,
+    #[default = 1024]
+    pub udp_max_clients: usize,
+    pub tcp_nodelay: bool,
+    pub tcp_keepalive: Option<u64>,
+    pub tcp_keepalive_interval: Option<u64>,
+    pub tcp_keepalive_count: Option<u32>,
+    pub tcp_bind: Option<SocketAddr>,
+    pub tcp_bind_device: Option<String>,
+    pub tcp_mark: Option<u32>,
+    #[default = 1024]
+    pub tcp_listen_backlog: i32,
+    pub tcp_reuseport: bool,
+    pub proxy_protocol: bool,
+    pub proxy_protocol_trusted: Vec<IpNetwork>,
+    pub proxy_protocol_send: Option<ProxyProtocolVersion>,
+    pub allow_ip: Vec<IpNetwork>,
+    pub deny_ip: Vec<IpNetwork>,
+    pub x_forwarded_for_trusted: Vec<IpNetwork>,
     pub unidirectional: bool,
     pub unidirectional_reverse: bool,
     pub max_messages: Option<usize>,
@@ -37,6 +125,11 @@ pub struct Options {
     pub oneshot: bool,
     pub unlink_unix_socket: bool,
     pub unix_socket_accept_from_fd: bool,
+    pub unix_socket_mode: Option<u32>,
+    pub unix_socket_owner: Option<u32>,
+    pub unix_socket_group: Option<u32>,
+    pub unix_allow_uid: Vec<u32>,
+    pub unix_allow_gid: Vec<u32>,
     pub exec_args: Vec<String>,
     pub ws_c_uri: String, // TODO: delete this
     pub linemode_strip_newlines: bool,
@@ -64,6 +157,7 @@ pub struct Options {
     pub process_zero_sighup: bool,
     pub process_exit_sighup: bool,
     pub process_exit_on_disconnect: bool,
+    pub serial_control_prefix: Option<String>,
     pub socks_destination: Option<SocksSocketAddr>,
     pub auto_socks5: Option<SocketAddr>,
     pub socks5_bind_script: Option<OsString>,
@@ -77,11 +171,36 @@ pub struct Options {
     pub client_pkcs12_der: Option<Vec<u8>>,
     #[derivative(Debug = "ignore")]
     pub client_pkcs12_passwd: Option<String>,
+    #[derivative(Debug = "ignore")]
+    pub tls_cert: Option<Vec<u8>>,
+    #[derivative(Debug = "ignore")]
+    pub tls_key: Option<Vec<u8>>,
+    #[derivative(Debug = "ignore")]
+    pub client_tls_cert: Option<Vec<u8>>,
+    #[derivative(Debug = "ignore")]
+    pub client_tls_key: Option<Vec<u8>>,
+    pub pkcs12_der_file: Option<PathBuf>,
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    pub tls_client_ca: Option<Vec<u8>>,
+    pub tls_client_ca_file: Option<PathBuf>,
+    pub tls_client_cert_optional: bool,
+    pub tls_sni_cert: Vec<TlsSniCert>,
     pub tls_insecure: bool,
+    pub tls_ca: Vec<Vec<u8>>,
+    pub tls_no_system_ca: bool,
+    pub tls_pin: Vec<TlsPin>,
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    pub tls_keylog: Option<std::sync::Arc<super::tls_common::TlsKeyLog>>,
 
     pub headers_to_env: Vec<String>,
 
     pub max_parallel_conns: Option<usize>,
+    pub max_parallel_conns_per_ip: Option<usize>,
+    pub conn_rate_per_ip: Option<f64>,
+    pub conn_burst_per_ip: Option<u32>,
+    #[default = 30]
+    pub dns_cache_ttl: u64,
     pub ws_ping_interval: Option<u64>,
     pub ws_ping_timeout: Option<u64>,
 
@@ -141,4 +260,41 @@ pub struct Options {
     pub lengthprefixed_skip_read_direction: bool,
     pub lengthprefixed_skip_write_direction: bool,
 
+    pub sse_json: bool,
+    #[default = 3000]
+    pub sse_retry_millis: u64,
+    pub sse_no_reconnect: bool,
+
+    pub webhook_uri: Option<String>,
+    pub webhook_method: Option<String>,
+    pub webhook_headers: Vec<String>,
+    pub webhook_content_type: Option<String>,
+    #[default = 1]
+    pub webhook_concurrency: usize,
+    #[default = 3]
+    pub webhook_retries: usize,
+    #[default = 500]
+    pub webhook_retry_delay_millis: u64,
+    #[default = 30000]
+    pub webhook_timeout_millis: u64,
+    pub webhook_status: bool,
+
+    #[default = 25000]
+    pub longpoll_timeout_millis: u64,
+    #[default = 60000]
+    pub longpoll_idle_timeout_millis: u64,
+
+    pub socketio_namespaces: Vec<String>,
+    pub socketio_auth: Option<String>,
+    pub socketio_json: bool,
+
+    pub graphql_init_payload: Option<String>,
+
+    pub stomp_login: Option<String>,
+    pub stomp_passcode: Option<String>,
+    pub stomp_host: Option<String>,
+    #[default("10000,10000".to_owned())]
+    pub stomp_heartbeat: String,
+
+    pub remote_exit_code: bool,
 }
@@ -167,6 +167,36 @@ fn process_connect_peer(
         if let Some(ref z) = x.uri {
             cmd.env("WEBSOCAT_URI", z);
         };
+        if let Some(ref z) = x.tls_client_subject {
+            cmd.env("WEBSOCAT_TLS_CLIENT_SUBJECT", z);
+        };
+        if let Some(ref z) = x.tls_client_san {
+            cmd.env("WEBSOCAT_TLS_CLIENT_SAN", z);
+        };
+        if let Some(ref z) = x.tls_client_fingerprint {
+            cmd.env("WEBSOCAT_TLS_CLIENT_FINGERPRINT", z);
+        };
+        if let Some(ref z) = x.tls_server_name {
+            cmd.env("WEBSOCAT_TLS_SERVER_NAME", z);
+        };
+        if let Some(ref z) = x.tls_version {
+            cmd.env("WEBSOCAT_TLS_VERSION", z);
+        };
+        if let Some(ref z) = x.tls_cipher {
+            cmd.env("WEBSOCAT_TLS_CIPHER", z);
+        };
+        if let Some(ref z) = x.tls_alpn {
+            cmd.env("WEBSOCAT_TLS_ALPN", z);
+        };
+        if let Some(z) = x.peer_uid {
+            cmd.env("WEBSOCAT_PEER_UID", z.to_string());
+        };
+        if let Some(z) = x.peer_gid {
+            cmd.env("WEBSOCAT_PEER_GID", z.to_string());
+        };
+        if let Some(z) = x.peer_pid {
+            cmd.env("WEBSOCAT_PEER_PID", z.to_string());
+        };
         for (hn, hv) in &x.headers {
             cmd.env(format!("H_{}", hn), hv);
         }
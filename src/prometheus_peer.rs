@@ -97,6 +97,9 @@ pub struct GlobalStats {
     /// durations it took to make a function call to underlying node for writing
     #[metric(buckets(0.1e-3,1e-3,0.01,0.1,1,10))]
     write_timings: Histogram,
+
+    /// Number of incoming connections rejected due to `--allow-ip`, `--deny-ip` or `--proxy-protocol-trusted`
+    rejected_connections: IntCounter,
 }
 
 pub type HGlobalStats = Rc<GlobalStats>;
@@ -146,6 +149,13 @@ pub fn new_global_stats() -> GlobalState {
     (Rc::new(GlobalStats::new(prometheus::default_registry()).unwrap()), Rc::new(RefCell::new(None)))
 }
 
+/// Counter for `--allow-ip` / `--deny-ip` / `--proxy-protocol-trusted` rejections, if `--prometheus` is enabled
+pub fn rejected_connections_counter(cp: &ConstructParams) -> Option<IntCounter> {
+    cp.program_options.prometheus?;
+    let stats: std::cell::RefMut<GlobalState> = cp.global(new_global_stats);
+    Some(stats.0.rejected_connections.clone())
+}
+
 pub fn serve(psa: SocketAddr) -> crate::Result<()> {
     let tcp = TcpListener::bind(&psa)?;
     debug!("Listening TCP socket for Prometheus metrics");
@@ -0,0 +1,251 @@
+//! HAProxy PROXY protocol (v1 and v2) headers, to learn or pass on the original client address through a load balancer
+
+use futures::{Async, Future, Poll};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio_io::AsyncRead;
+use tokio_tcp::TcpStream;
+
+use super::Result;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8; 6] = b"PROXY ";
+/// `PROXY UNKNOWN\r\n`. Also less than minimal v2 header, so can be read without looking.
+const V1_MIN_LEN: usize = 15;
+const V1_MAX_LEN: usize = 107;
+
+/// How long incoming connection may take to send the header
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `--proxy-protocol-send`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+impl FromStr for ProxyProtocolVersion {
+    type Err = Box<dyn std::error::Error>;
+    fn from_str(s: &str) -> Result<ProxyProtocolVersion> {
+        match s {
+            "v1" | "1" => Ok(ProxyProtocolVersion::V1),
+            "v2" | "2" => Ok(ProxyProtocolVersion::V2),
+            _ => Err("PROXY protocol version should be `v1` or `v2`")?,
+        }
+    }
+}
+
+/// Source and destination addresses from the header.
+/// `None` means the header does not carry them (`UNKNOWN` or `LOCAL`) and actual socket addresses should be used.
+pub type ProxiedAddrs = Option<(SocketAddr, SocketAddr)>;
+
+#[derive(Debug, PartialEq)]
+pub enum Parse {
+    /// Need at least that number of bytes in total
+    Incomplete(usize),
+    Complete(ProxiedAddrs),
+}
+
+pub fn parse(buf: &[u8]) -> Result<Parse> {
+    if buf.first() == Some(&b'\r') {
+        let n = buf.len().min(V2_SIGNATURE.len());
+        if buf[..n] != V2_SIGNATURE[..n] {
+            Err("Invalid PROXY protocol v2 signature")?;
+        }
+        if buf.len() < 16 {
+            return Ok(Parse::Incomplete(16));
+        }
+        let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        if buf.len() < len {
+            return Ok(Parse::Incomplete(len));
+        }
+        return parse_v2(&buf[..len]).map(Parse::Complete);
+    }
+    let n = buf.len().min(V1_PREFIX.len());
+    if buf[..n] != V1_PREFIX[..n] {
+        Err("No PROXY protocol header")?;
+    }
+    if buf.len() < V1_MIN_LEN {
+        return Ok(Parse::Incomplete(V1_MIN_LEN));
+    }
+    if !buf.ends_with(b"\r\n") {
+        if buf.len() >= V1_MAX_LEN {
+            Err("PROXY protocol v1 header is too long")?;
+        }
+        return Ok(Parse::Incomplete(buf.len() + 1));
+    }
+    parse_v1(&buf[..buf.len() - 2]).map(Parse::Complete)
+}
+
+fn parse_v1(line: &[u8]) -> Result<ProxiedAddrs> {
+    let line = std::str::from_utf8(line)?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if parts.len() == 6 => (),
+        _ => Err(format!("Invalid PROXY protocol v1 header: {}", line))?,
+    }
+    let src: IpAddr = parts[2].parse()?;
+    let dst: IpAddr = parts[3].parse()?;
+    let v6 = parts[1] == "TCP6";
+    if src.is_ipv6() != v6 || dst.is_ipv6() != v6 {
+        Err(format!("Address family mismatch in PROXY protocol v1 header: {}", line))?;
+    }
+    let sport: u16 = parts[4].parse()?;
+    let dport: u16 = parts[5].parse()?;
+    Ok(Some((SocketAddr::new(src, sport), SocketAddr::new(dst, dport))))
+}
+
+fn parse_v2(h: &[u8]) -> Result<ProxiedAddrs> {
+    if h[12] >> 4 != 2 {
+        Err("Unsupported PROXY protocol version")?;
+    }
+    match h[12] & 0x0F {
+        0 => return Ok(None), // LOCAL, e.g. health check from the load balancer itself
+        1 => (),
+        _ => Err("Invalid PROXY protocol v2 command")?,
+    }
+    let a = &h[16..];
+    let port = |x: &[u8]| u16::from_be_bytes([x[0], x[1]]);
+    match h[13] >> 4 {
+        1 if a.len() >= 12 => {
+            let src = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+            let dst = Ipv4Addr::new(a[4], a[5], a[6], a[7]);
+            Ok(Some((
+                SocketAddr::new(src.into(), port(&a[8..])),
+                SocketAddr::new(dst.into(), port(&a[10..])),
+            )))
+        }
+        2 if a.len() >= 36 => {
+            let mut src = [0u8; 16];
+            let mut dst = [0u8; 16];
+            src.copy_from_slice(&a[0..16]);
+            dst.copy_from_slice(&a[16..32]);
+            Ok(Some((
+                SocketAddr::new(Ipv6Addr::from(src).into(), port(&a[32..])),
+                SocketAddr::new(Ipv6Addr::from(dst).into(), port(&a[34..])),
+            )))
+        }
+        // AF_UNSPEC or AF_UNIX
+        _ => Ok(None),
+    }
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(x) => x.to_ipv6_mapped(),
+        IpAddr::V6(x) => x,
+    }
+}
+
+/// Header to send before data. Mixed IPv4 and IPv6 addresses are sent as IPv6.
+pub fn encode(version: ProxyProtocolVersion, addrs: ProxiedAddrs) -> Vec<u8> {
+    match (version, addrs) {
+        (ProxyProtocolVersion::V1, None) => b"PROXY UNKNOWN\r\n".to_vec(),
+        (ProxyProtocolVersion::V1, Some((src, dst))) => {
+            let (proto, sip, dip) = match (src.ip(), dst.ip()) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => ("TCP4", s.to_string(), d.to_string()),
+                (s, d) => ("TCP6", to_v6(s).to_string(), to_v6(d).to_string()),
+            };
+            format!("PROXY {} {} {} {} {}\r\n", proto, sip, dip, src.port(), dst.port()).into_bytes()
+        }
+        (ProxyProtocolVersion::V2, None) => {
+            let mut v = V2_SIGNATURE.to_vec();
+            v.extend_from_slice(&[0x20, 0x00, 0, 0]);
+            v
+        }
+        (ProxyProtocolVersion::V2, Some((src, dst))) => {
+            let (family, mut a) = match (src.ip(), dst.ip()) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => (0x11, [s.octets(), d.octets()].concat()),
+                (s, d) => (0x21, [to_v6(s).octets(), to_v6(d).octets()].concat()),
+            };
+            a.extend_from_slice(&src.port().to_be_bytes());
+            a.extend_from_slice(&dst.port().to_be_bytes());
+            let mut v = V2_SIGNATURE.to_vec();
+            v.push(0x21);
+            v.push(family);
+            v.extend_from_slice(&(a.len() as u16).to_be_bytes());
+            v.extend_from_slice(&a);
+            v
+        }
+    }
+}
+
+/// Reads PROXY header from incoming connection, not consuming any bytes after it
+pub struct ReadHeader {
+    s: Option<TcpStream>,
+    buf: Vec<u8>,
+    want: usize,
+    deadline: tokio_timer::Delay,
+}
+
+pub fn read_header(s: TcpStream) -> ReadHeader {
+    ReadHeader {
+        s: Some(s),
+        buf: Vec::with_capacity(V1_MAX_LEN),
+        want: V1_MIN_LEN,
+        deadline: tokio_timer::Delay::new(Instant::now() + HEADER_TIMEOUT),
+    }
+}
+
+impl Future for ReadHeader {
+    type Item = (TcpStream, ProxiedAddrs);
+    type Error = Box<dyn std::error::Error>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.deadline.poll()?.is_ready() {
+            Err("Timed out waiting for PROXY protocol header")?;
+        }
+        loop {
+            while self.buf.len() < self.want {
+                let old = self.buf.len();
+                self.buf.resize(self.want, 0);
+                let r = self.s.as_mut().unwrap().poll_read(&mut self.buf[old..]);
+                match r {
+                    Ok(Async::Ready(0)) => Err("Connection closed before PROXY protocol header")?,
+                    Ok(Async::Ready(n)) => self.buf.truncate(old + n),
+                    Ok(Async::NotReady) => {
+                        self.buf.truncate(old);
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => Err(e)?,
+                }
+            }
+            match parse(&self.buf)? {
+                Parse::Incomplete(n) => self.want = n,
+                Parse::Complete(addrs) => return Ok(Async::Ready((self.s.take().unwrap(), addrs))),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_roundtrip() {
+    let v4: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+    let v4d: SocketAddr = "198.51.100.7:443".parse().unwrap();
+    let v6: SocketAddr = "[2001:db8::1]:1234".parse().unwrap();
+    for &ver in &[ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+        for &addrs in &[None, Some((v4, v4d)), Some((v6, v6))] {
+            let h = encode(ver, addrs);
+            assert_eq!(parse(&h).unwrap(), Parse::Complete(addrs));
+        }
+        let mixed = SocketAddr::new(to_v6(v4.ip()).into(), v4.port());
+        assert_eq!(parse(&encode(ver, Some((v4, v6)))).unwrap(), Parse::Complete(Some((mixed, v6))));
+    }
+    assert_eq!(
+        encode(ProxyProtocolVersion::V1, Some((v4, v4d))),
+        b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n".to_vec()
+    );
+}
+
+#[test]
+fn test_parse_partial() {
+    assert_eq!(parse(b"").unwrap(), Parse::Incomplete(V1_MIN_LEN));
+    assert_eq!(parse(b"PROXY TCP4 192.0.2.1").unwrap(), Parse::Incomplete(21));
+    assert_eq!(parse(&V2_SIGNATURE[..]).unwrap(), Parse::Incomplete(16));
+    assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+    assert!(parse(b"PROXY TCP4 x y 1 2\r\n").is_err());
+    assert!(parse(b"PROXY TCP4 2001:db8::1 192.0.2.1 1 2\r\n").is_err());
+    assert!(parse(b"PROXY TCP6 192.0.2.1 198.51.100.7 1 2\r\n").is_err());
+}
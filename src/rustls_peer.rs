@@ -0,0 +1,572 @@
+//! rustls-based implementation of TLS overlays. Mounted as `ssl_peer` instead of
+//! native-tls-based one when `rustls` feature is enabled.
+//!
+//! Uses bundled Mozilla root certificates, so works without OpenSSL or system certificate store.
+
+use futures::future::{ok, Future};
+use futures::Async;
+
+use std::convert::TryFrom;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use super::{peer_err, BoxedNewPeerFuture, Peer};
+use crate::util::peer_err2;
+use super::{L2rUser, Options};
+
+extern crate base64;
+extern crate rcgen;
+extern crate readwrite;
+extern crate ring;
+extern crate rustls;
+extern crate rustls_pemfile;
+extern crate webpki_roots;
+extern crate x509_parser;
+
+use self::rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use self::rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert,
+};
+use self::rustls::sign::CertifiedKey;
+use self::rustls::{
+    Certificate, CertificateError, ClientConfig, ClientConnection, Connection, OwnedTrustAnchor, PrivateKey,
+    ProtocolVersion, RootCertStore, ServerConfig, ServerConnection, ServerName,
+};
+
+use self::x509_parser::certificate::X509Certificate;
+use self::x509_parser::extensions::GeneralName;
+use self::x509_parser::objects::{oid2abbrev, oid_registry};
+use self::x509_parser::oid_registry::OID_PKCS9_EMAIL_ADDRESS;
+use self::x509_parser::parse_x509_certificate;
+use self::x509_parser::x509::X509Name;
+
+use tokio_io::{AsyncRead, AsyncWrite};
+
+pub use super::tls_common::{interpret_pem, interpret_pem_bundle, interpret_pkcs12};
+use super::tls_common::{
+    fingerprint_to_string, pin_matches, sni_matches, SelfSignedCert, SessionInfo, TlsAcceptorSlot, TlsKeyLog,
+};
+use super::options::TlsPin;
+
+type Error = Box<dyn ::std::error::Error>;
+
+pub type Acceptor = Arc<ServerConfig>;
+pub use self::server_config as build_acceptor;
+
+fn pem_certs(pem: &[u8], what: &str) -> Result<Vec<Certificate>, Error> {
+    let certs = rustls_pemfile::certs(&mut &pem[..])?;
+    if certs.is_empty() {
+        Err(format!("No certificates in {} file", what))?;
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// PKCS#8, PKCS#1 or SEC1 private key
+fn pem_key(pem: &[u8], what: &str) -> Result<PrivateKey, Error> {
+    for item in rustls_pemfile::read_all(&mut &pem[..])? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(x) | rustls_pemfile::Item::RSAKey(x) | rustls_pemfile::Item::ECKey(x) => {
+                return Ok(PrivateKey(x))
+            }
+            _ => (),
+        }
+    }
+    Err(format!("No private key in {} file", what))?
+}
+
+fn webpki_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+    }));
+    roots
+}
+
+/// Client identity from `--client-tls-cert`/`--client-tls-key`, if any
+fn client_identity(opts: &Options) -> Option<(Vec<Certificate>, PrivateKey)> {
+    if opts.client_pkcs12_der.is_some() {
+        error!("PKCS#12 client identities are not supported in rustls-based build, use --client-tls-cert and --client-tls-key\nContinuing without a client identity");
+        return None;
+    }
+    let (cert, key) = match (&opts.client_tls_cert, &opts.client_tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return None,
+    };
+    let identity = (|| Ok((pem_certs(cert, "--client-tls-cert")?, pem_key(key, "--client-tls-key")?)))();
+    identity
+        .map_err(|e: Error| {
+            error!(
+                "Unable to parse client identity: {}\nContinuing without a client identity",
+                e
+            )
+        })
+        .ok()
+}
+
+/// `--tls-self-signed`: generate a throwaway certificate for given host names and IP addresses.
+pub fn self_signed_cert(names: &[String]) -> Result<SelfSignedCert, Error> {
+    let mut params = rcgen::CertificateParams::new(names);
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params.distinguished_name.push(rcgen::DnType::CommonName, names[0].as_str());
+    let now = ::std::time::SystemTime::now();
+    params.not_before = now.into();
+    params.not_after = (now + ::std::time::Duration::from_secs(365 * 86400)).into();
+    let cert = rcgen::Certificate::from_params(params)?;
+    // Signature is randomized, so serialize only once and encode PEM from the same DER
+    let der = cert.serialize_der()?;
+    let b64 = base64::encode(&der);
+    let mut pem = "-----BEGIN CERTIFICATE-----\n".to_owned();
+    for line in b64.as_bytes().chunks(64) {
+        pem += ::std::str::from_utf8(line).unwrap();
+        pem += "\n";
+    }
+    pem += "-----END CERTIFICATE-----\n";
+    let fingerprint = ring::digest::digest(&ring::digest::SHA256, &der);
+    Ok(SelfSignedCert {
+        cert_pem: pem.into_bytes(),
+        key_pem: cert.serialize_private_key_pem().into_bytes(),
+        sha256: fingerprint.as_ref().to_vec(),
+    })
+}
+
+/// For `--insecure`
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// For `tls-connect:` without `--tls-domain`: check the certificate chain, but not the name
+struct NoHostnameVerification(WebPkiVerifier);
+
+impl ServerCertVerifier for NoHostnameVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self.0.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now) {
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName)) => {
+                Ok(ServerCertVerified::assertion())
+            }
+            x => x,
+        }
+    }
+}
+
+/// For `--tls-pin`: check only the hash of the certificate or its public key
+struct PinVerification(Vec<TlsPin>);
+
+impl ServerCertVerifier for PinVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let spki = cert_spki(&end_entity.0)
+            .ok_or(rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let cert_sha256 = ring::digest::digest(&ring::digest::SHA256, &end_entity.0);
+        let spki_sha256 = ring::digest::digest(&ring::digest::SHA256, spki);
+        if !pin_matches(&self.0, cert_sha256.as_ref(), spki_sha256.as_ref()) {
+            return Err(rustls::Error::General(
+                "TLS server certificate does not match any of --tls-pin fingerprints".to_owned(),
+            ));
+        }
+        info!("Server certificate matches the pin");
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Lets rustls write to `--tls-keylog`
+#[derive(Debug)]
+struct KeyLogAdapter(Arc<TlsKeyLog>);
+
+impl rustls::KeyLog for KeyLogAdapter {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        self.0.write_secret(label, client_random, secret);
+    }
+}
+
+fn client_config(progopt: &Options, verify_hostname: bool) -> Result<ClientConfig, Error> {
+    let mut roots = if progopt.tls_no_system_ca {
+        RootCertStore::empty()
+    } else {
+        webpki_roots()
+    };
+    for pem in &progopt.tls_ca {
+        for c in pem_certs(pem, "--tls-ca")? {
+            roots.add(&c)?;
+        }
+    }
+    let b = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots.clone());
+    let mut c = if let Some((certs, key)) = client_identity(progopt) {
+        debug!("Adding client identity to the TLS connection");
+        b.with_client_auth_cert(certs, key)?
+    } else {
+        b.with_no_client_auth()
+    };
+    if progopt.tls_insecure {
+        c.dangerous().set_certificate_verifier(Arc::new(NoVerification));
+    } else if !progopt.tls_pin.is_empty() {
+        c.dangerous()
+            .set_certificate_verifier(Arc::new(PinVerification(progopt.tls_pin.clone())));
+    } else if !verify_hostname {
+        c.dangerous()
+            .set_certificate_verifier(Arc::new(NoHostnameVerification(WebPkiVerifier::new(roots, None))));
+    }
+    if let Some(ref keylog) = progopt.tls_keylog {
+        c.key_log = Arc::new(KeyLogAdapter(keylog.clone()));
+    }
+    Ok(c)
+}
+
+pub fn server_config(progopt: &Options) -> Result<Arc<ServerConfig>, Error> {
+    let (cert, key) = match (&progopt.tls_cert, &progopt.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => Err("PKCS#12 archives are not supported in rustls-based build, use --tls-cert and --tls-key")?,
+    };
+    let b = ServerConfig::builder().with_safe_defaults();
+    let b = if let Some(ref ca) = progopt.tls_client_ca {
+        let mut roots = RootCertStore::empty();
+        for c in pem_certs(ca, "--tls-client-ca")? {
+            roots.add(&c)?;
+        }
+        if progopt.tls_client_cert_optional {
+            b.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed())
+        } else {
+            b.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+    } else {
+        b.with_no_client_auth()
+    };
+    let mut c = if progopt.tls_sni_cert.is_empty() {
+        b.with_single_cert(pem_certs(cert, "--tls-cert")?, pem_key(key, "--tls-key")?)?
+    } else {
+        let mut by_name = vec![];
+        for x in &progopt.tls_sni_cert {
+            let what = format!("--tls-sni-cert {}", x.name);
+            by_name.push((x.name.clone(), certified_key(&x.cert, &x.key, &what)?));
+        }
+        let default = certified_key(cert, key, "--tls-cert")?;
+        b.with_cert_resolver(Arc::new(SniResolver { by_name, default }))
+    };
+    if let Some(ref keylog) = progopt.tls_keylog {
+        c.key_log = Arc::new(KeyLogAdapter(keylog.clone()));
+    }
+    Ok(Arc::new(c))
+}
+
+fn certified_key(cert: &[u8], key: &[u8], what: &str) -> Result<Arc<CertifiedKey>, Error> {
+    let key = rustls::sign::any_supported_type(&pem_key(key, what)?)?;
+    Ok(Arc::new(CertifiedKey::new(pem_certs(cert, what)?, key)))
+}
+
+/// Picks certificate by server name requested by client, see `--tls-sni-cert`
+struct SniResolver {
+    by_name: Vec<(String, Arc<CertifiedKey>)>,
+    default: Arc<CertifiedKey>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        if let Some(name) = client_hello.server_name() {
+            for (pattern, ck) in &self.by_name {
+                if sni_matches(pattern, name) {
+                    return Some(ck.clone());
+                }
+            }
+        }
+        Some(self.default.clone())
+    }
+}
+
+pub fn ssl_connect(inner_peer: Peer, dom: Option<String>, progopt: &Options) -> BoxedNewPeerFuture {
+    let hup = inner_peer.2;
+    let squashed_peer = readwrite::ReadWriteAsync::new(inner_peer.0, inner_peer.1);
+
+    let config = match client_config(progopt, dom.is_some()) {
+        Ok(x) => Arc::new(x),
+        Err(e) => return peer_err2(e),
+    };
+    let name = dom.as_deref().unwrap_or("domainverificationdisabled");
+    let name = match ServerName::try_from(name) {
+        Ok(x) => x,
+        Err(e) => return peer_err(e),
+    };
+    let conn = match ClientConnection::new(config, name) {
+        Ok(x) => x,
+        Err(e) => return peer_err(e),
+    };
+
+    let verified = dom.is_some() || !progopt.tls_pin.is_empty();
+
+    info!("Connecting to TLS");
+    Box::new(Handshake(Some(TlsStream::new(conn.into(), squashed_peer))).and_then(move |s| {
+        if verified {
+            info!("Connected to TLS");
+        } else {
+            warn!("Connected to TLS without proper verification of certificate. Use --tls-domain option.");
+        }
+        session_info(&s.conn).report(None);
+        let (r, w) = s.split();
+        ok(Peer::new(r, w, hup))
+    }))
+}
+
+pub fn ssl_accept(
+    inner_peer: Peer,
+    l2r: L2rUser,
+    progopt: Rc<Options>,
+    slot: &TlsAcceptorSlot<Acceptor>,
+) -> BoxedNewPeerFuture {
+    let hup = inner_peer.2;
+    let squashed_peer = readwrite::ReadWriteAsync::new(inner_peer.0, inner_peer.1);
+
+    let config = match slot.get(&progopt, server_config) {
+        Ok(x) => x,
+        Err(e) => return peer_err2(e),
+    };
+    let conn = match ServerConnection::new(config) {
+        Ok(x) => x,
+        Err(e) => return peer_err(e),
+    };
+
+    debug!("Accepting a TLS connection");
+    Box::new(Handshake(Some(TlsStream::new(conn.into(), squashed_peer))).and_then(move |s| {
+        info!("Accepted TLS connection");
+        session_info(&s.conn).report(Some(&l2r));
+        if let Connection::Server(ref c) = s.conn {
+            if let (Some(name), L2rUser::FillIn(ref y)) = (c.server_name(), &l2r) {
+                debug!("  the client asked for server name {}", name);
+                y.borrow_mut().tls_server_name = Some(name.to_owned());
+            }
+        }
+        if let Some(cert) = s.conn.peer_certificates().and_then(|x| x.first()) {
+            fill_client_info(&cert.0, &l2r);
+        } else if progopt.tls_client_ca.is_some() {
+            info!("  the client did not present a certificate");
+        }
+        let (r, w) = s.split();
+        ok(Peer::new(r, w, hup))
+    }))
+}
+
+fn session_info(conn: &Connection) -> SessionInfo {
+    SessionInfo {
+        version: conn.protocol_version().map(|v| match v {
+            ProtocolVersion::TLSv1_2 => "TLSv1.2".to_owned(),
+            ProtocolVersion::TLSv1_3 => "TLSv1.3".to_owned(),
+            x => format!("{:?}", x),
+        }),
+        cipher: conn.negotiated_cipher_suite().map(|x| format!("{:?}", x.suite())),
+        alpn: conn.alpn_protocol().map(|x| String::from_utf8_lossy(x).into_owned()),
+        peer_certificates: conn
+            .peer_certificates()
+            .into_iter()
+            .flatten()
+            .map(|c| cert_subject_issuer(&c.0).unwrap_or_default())
+            .collect(),
+    }
+}
+
+fn fill_client_info(cert: &[u8], l2r: &L2rUser) {
+    let (subject, san) = cert_names(cert).unwrap_or_default();
+    info!("  the client presented a valid certificate: {}", subject);
+    if let L2rUser::FillIn(ref y) = *l2r {
+        let fingerprint = ring::digest::digest(&ring::digest::SHA256, cert);
+        let mut z = y.borrow_mut();
+        z.tls_client_subject = Some(subject);
+        z.tls_client_san = Some(san);
+        z.tls_client_fingerprint = Some(fingerprint_to_string(fingerprint.as_ref()));
+    }
+}
+
+fn parse_cert(cert: &[u8]) -> Option<X509Certificate<'_>> {
+    parse_x509_certificate(cert).ok().map(|(_, c)| c)
+}
+
+/// DER-encoded SubjectPublicKeyInfo, for `spki:` pins
+fn cert_spki(cert: &[u8]) -> Option<&[u8]> {
+    parse_cert(cert).map(|c| c.tbs_certificate.subject_pki.raw)
+}
+
+/// Distinguished name like `CN=client,O=Example`, same as with OpenSSL-based build
+fn name_to_string(name: &X509Name) -> String {
+    name.iter_attributes()
+        .map(|a| {
+            let k = if *a.attr_type() == OID_PKCS9_EMAIL_ADDRESS {
+                "emailAddress".to_owned()
+            } else {
+                oid2abbrev(a.attr_type(), oid_registry())
+                    .map(|x| x.to_owned())
+                    .unwrap_or_else(|_| a.attr_type().to_id_string())
+            };
+            format!("{}={}", k, a.as_str().unwrap_or_default())
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn cert_subject_issuer(cert: &[u8]) -> Option<(String, String)> {
+    let c = parse_cert(cert)?;
+    Some((name_to_string(c.subject()), name_to_string(c.issuer())))
+}
+
+/// Subject and subject alternative names like `DNS:example.com,email:a@example.com,IP:127.0.0.1`,
+/// same as with OpenSSL-based build
+fn cert_names(cert: &[u8]) -> Option<(String, String)> {
+    let c = parse_cert(cert)?;
+    let mut sans = vec![];
+    if let Ok(Some(ext)) = c.subject_alternative_name() {
+        for n in &ext.value.general_names {
+            match *n {
+                GeneralName::DNSName(x) => sans.push(format!("DNS:{}", x)),
+                GeneralName::RFC822Name(x) => sans.push(format!("email:{}", x)),
+                GeneralName::URI(x) => sans.push(format!("URI:{}", x)),
+                GeneralName::IPAddress(x) => {
+                    if let Ok(a) = <[u8; 4]>::try_from(x) {
+                        sans.push(format!("IP:{}", IpAddr::from(a)));
+                    } else if let Ok(a) = <[u8; 16]>::try_from(x) {
+                        sans.push(format!("IP:{}", IpAddr::from(a)));
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    Some((name_to_string(c.subject()), sans.join(",")))
+}
+
+type RawStream = readwrite::ReadWriteAsync<Box<dyn AsyncRead>, Box<dyn AsyncWrite>>;
+
+/// rustls connection atop of a non-blocking stream
+struct TlsStream {
+    conn: Connection,
+    sock: RawStream,
+    close_notify_sent: bool,
+}
+
+impl TlsStream {
+    fn new(conn: Connection, sock: RawStream) -> Self {
+        TlsStream {
+            conn,
+            sock,
+            close_notify_sent: false,
+        }
+    }
+
+    /// Write out pending TLS records
+    fn write_tls(&mut self) -> IoResult<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.sock)?;
+        }
+        Ok(())
+    }
+}
+
+/// Drives rustls handshake on a non-blocking stream
+struct Handshake(Option<TlsStream>);
+
+impl Future for Handshake {
+    type Item = TlsStream;
+    type Error = Error;
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        let s = self.0.as_mut().expect("Handshake polled after completion");
+        while s.conn.is_handshaking() {
+            match s.conn.complete_io(&mut s.sock) {
+                Ok(_) => (),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+        Ok(Async::Ready(self.0.take().unwrap()))
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    debug!("TLS connection closed without close_notify");
+                    return Ok(0);
+                }
+                x => return x,
+            }
+            self.write_tls()?;
+            if self.conn.read_tls(&mut self.sock)? == 0 {
+                debug!("EOF from the underlying connection of TLS");
+            }
+            if let Err(e) = self.conn.process_new_packets() {
+                let _ = self.write_tls();
+                return Err(IoError::new(ErrorKind::InvalidData, e));
+            }
+        }
+    }
+}
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.write_tls()?;
+        let n = self.conn.writer().write(buf)?;
+        // Data is buffered in rustls anyway; errors will show up on the next write or flush
+        let _ = self.write_tls();
+        Ok(n)
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        self.conn.writer().flush()?;
+        self.write_tls()?;
+        self.sock.flush()
+    }
+}
+impl AsyncRead for TlsStream {}
+impl AsyncWrite for TlsStream {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        if !self.close_notify_sent {
+            self.conn.send_close_notify();
+            self.close_notify_sent = true;
+        }
+        match self.write_tls() {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(e) => return Err(e),
+        }
+        self.sock.shutdown()
+    }
+}
+
+#[test]
+fn test_cert_names() {
+    let c = self_signed_cert(&["localhost".to_owned(), "example.com".to_owned()]).unwrap();
+    let der = &pem_certs(&c.cert_pem, "test").unwrap()[0].0;
+    assert_eq!(
+        cert_names(der),
+        Some(("CN=localhost".to_owned(), "DNS:localhost,DNS:example.com".to_owned()))
+    );
+    assert_eq!(cert_subject_issuer(der), Some(("CN=localhost".to_owned(), "CN=localhost".to_owned())));
+    assert_eq!(ring::digest::digest(&ring::digest::SHA256, der).as_ref(), &c.sha256[..]);
+    assert!(cert_spki(der).is_some());
+}
@@ -0,0 +1,458 @@
+//! `serial:` - serial port or other TTY device with line settings
+
+extern crate libc;
+extern crate tokio_file_unix;
+extern crate tokio_reactor;
+
+use futures;
+use std;
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::Result as IoResult;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use self::tokio_file_unix::File as UnixFile;
+
+use super::{once, BoxedNewPeerFuture, ConstructParams, Options, Peer, PeerConstructor, Result, Specifier};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlowControl {
+    None,
+    RtsCts,
+    XonXoff,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SerialSettings {
+    /// Keep current speed if not specified
+    pub baud: Option<u32>,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: u8,
+    pub flow: FlowControl,
+    /// Set or clear DTR line after opening
+    pub dtr: Option<bool>,
+    /// Set or clear RTS line after opening
+    pub rts: Option<bool>,
+}
+
+impl Default for SerialSettings {
+    fn default() -> SerialSettings {
+        SerialSettings {
+            baud: None,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+            flow: FlowControl::None,
+            dtr: None,
+            rts: None,
+        }
+    }
+}
+
+fn on_off(x: &str) -> Result<bool> {
+    match x {
+        "1" | "on" => Ok(true),
+        "0" | "off" => Ok(false),
+        _ => Err(format!("Expected `on` or `off` instead of `{}`", x))?,
+    }
+}
+
+/// Frame format like `8N1`
+fn parse_frame(s: &mut SerialSettings, x: &str) -> Result<()> {
+    let b = x.as_bytes();
+    if b.len() != 3 {
+        Err(format!("Unknown serial port setting `{}`", x))?;
+    }
+    s.data_bits = match b[0] {
+        b'5'..=b'8' => b[0] - b'0',
+        _ => Err(format!("Invalid number of data bits in `{}`, expected 5 to 8", x))?,
+    };
+    s.parity = match b[1].to_ascii_uppercase() {
+        b'N' => Parity::None,
+        b'E' => Parity::Even,
+        b'O' => Parity::Odd,
+        _ => Err(format!("Invalid parity in `{}`, expected N, E or O", x))?,
+    };
+    s.stop_bits = match b[2] {
+        b'1' | b'2' => b[2] - b'0',
+        _ => Err(format!("Invalid number of stop bits in `{}`, expected 1 or 2", x))?,
+    };
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct SerialDevice {
+    pub path: PathBuf,
+    pub settings: SerialSettings,
+}
+
+impl FromStr for SerialDevice {
+    type Err = Box<dyn std::error::Error>;
+    fn from_str(x: &str) -> Result<SerialDevice> {
+        let mut parts = x.split(',');
+        let path = parts.next().unwrap_or("");
+        if path.is_empty() {
+            Err("Expected device path, optionally followed by settings, like `/dev/ttyUSB0,115200,8N1`")?;
+        }
+        let mut s = SerialSettings::default();
+        for p in parts {
+            match p.trim() {
+                "" => (),
+                "rtscts" => s.flow = FlowControl::RtsCts,
+                "xonxoff" => s.flow = FlowControl::XonXoff,
+                "noflow" => s.flow = FlowControl::None,
+                p if p.bytes().all(|c| c.is_ascii_digit()) => {
+                    let baud = p.parse()?;
+                    if baud_constant(baud).is_none() {
+                        Err(format!("Unsupported baud rate {}", baud))?;
+                    }
+                    s.baud = Some(baud);
+                }
+                p => {
+                    if let Some(v) = p.strip_prefix("dtr=") {
+                        s.dtr = Some(on_off(v)?);
+                    } else if let Some(v) = p.strip_prefix("rts=") {
+                        s.rts = Some(on_off(v)?);
+                    } else {
+                        parse_frame(&mut s, p)?;
+                    }
+                }
+            }
+        }
+        Ok(SerialDevice {
+            path: path.into(),
+            settings: s,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Serial(pub SerialDevice);
+impl Specifier for Serial {
+    fn construct(&self, p: ConstructParams) -> PeerConstructor {
+        let ret = serial_peer(&self.0.path, &self.0.settings, &p.program_options);
+        once(Box::new(futures::future::result(ret)) as BoxedNewPeerFuture)
+    }
+    specifier_boilerplate!(noglobalstate singleconnect no_subspec);
+}
+specifier_class!(
+    name = SerialClass,
+    target = Serial,
+    prefixes = ["serial:", "tty:"],
+    arg_handling = parse,
+    overlay = false,
+    StreamOriented,
+    SingleConnect,
+    help = r#"
+Open serial port or other TTY device in raw mode with specified line settings. [A]
+
+Argument is a device path followed by comma-separated settings:
+
+* baud rate, like `115200`. Current speed is kept if not specified.
+* frame format: data bits, parity (`N`, `E` or `O`) and stop bits. Default is `8N1`.
+* flow control: `rtscts`, `xonxoff` or `noflow` (default)
+* `dtr=on`, `dtr=off`, `rts=on`, `rts=off` to set modem control lines after opening
+
+With `--serial-control-prefix`, incoming messages starting with the prefix are not written to the device,
+but interpreted as commands: `break` to send break condition, `dtr=on`, `dtr=off`, `rts=on`, `rts=off`.
+This works only in text mode (`-t`), as in binary mode data is not guaranteed to arrive in whole messages.
+
+Example: expose a microcontroller console to browsers
+
+    websocat -E -b ws-l:127.0.0.1:8080 reuse-raw:serial:/dev/ttyUSB0,115200,8N1
+
+Example: the same, also allowing to reset the board with `!dtr=off` and `!dtr=on` messages
+
+    websocat -E -t --serial-control-prefix='!' ws-l:127.0.0.1:8080 reuse-raw:serial:/dev/ttyACM0,9600
+"#
+);
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn baud_constant(baud: u32) -> Option<libc::speed_t> {
+    use self::libc::*;
+    Some(match baud {
+        50 => B50,
+        75 => B75,
+        110 => B110,
+        134 => B134,
+        150 => B150,
+        200 => B200,
+        300 => B300,
+        600 => B600,
+        1200 => B1200,
+        1800 => B1800,
+        2400 => B2400,
+        4800 => B4800,
+        9600 => B9600,
+        19200 => B19200,
+        38400 => B38400,
+        57600 => B57600,
+        115200 => B115200,
+        230400 => B230400,
+        460800 => B460800,
+        500000 => B500000,
+        576000 => B576000,
+        921600 => B921600,
+        1000000 => B1000000,
+        1152000 => B1152000,
+        1500000 => B1500000,
+        2000000 => B2000000,
+        2500000 => B2500000,
+        3000000 => B3000000,
+        3500000 => B3500000,
+        4000000 => B4000000,
+        _ => return None,
+    })
+}
+
+/// BSD-derived systems use numeric speeds directly
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn baud_constant(baud: u32) -> Option<libc::speed_t> {
+    Some(baud as libc::speed_t)
+}
+
+fn check(ret: libc::c_int) -> IoResult<()> {
+    if ret == -1 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn configure(fd: RawFd, s: &SerialSettings) -> IoResult<()> {
+    let mut t: libc::termios = unsafe { std::mem::zeroed() };
+    check(unsafe { libc::tcgetattr(fd, &mut t) })?;
+    unsafe { libc::cfmakeraw(&mut t) };
+    t.c_cflag &= !(libc::CSIZE | libc::PARENB | libc::PARODD | libc::CSTOPB | libc::CRTSCTS);
+    t.c_cflag |= libc::CLOCAL | libc::CREAD;
+    t.c_cflag |= match s.data_bits {
+        5 => libc::CS5,
+        6 => libc::CS6,
+        7 => libc::CS7,
+        _ => libc::CS8,
+    };
+    match s.parity {
+        Parity::None => (),
+        Parity::Even => t.c_cflag |= libc::PARENB,
+        Parity::Odd => t.c_cflag |= libc::PARENB | libc::PARODD,
+    }
+    if s.stop_bits == 2 {
+        t.c_cflag |= libc::CSTOPB;
+    }
+    t.c_iflag &= !(libc::IXON | libc::IXOFF | libc::IXANY);
+    match s.flow {
+        FlowControl::None => (),
+        FlowControl::RtsCts => t.c_cflag |= libc::CRTSCTS,
+        FlowControl::XonXoff => t.c_iflag |= libc::IXON | libc::IXOFF,
+    }
+    t.c_cc[libc::VMIN] = 1;
+    t.c_cc[libc::VTIME] = 0;
+    if let Some(speed) = s.baud.and_then(baud_constant) {
+        check(unsafe { libc::cfsetispeed(&mut t, speed) })?;
+        check(unsafe { libc::cfsetospeed(&mut t, speed) })?;
+    }
+    check(unsafe { libc::tcsetattr(fd, libc::TCSANOW, &t) })
+}
+
+fn set_modem_line(fd: RawFd, line: libc::c_int, on: bool) -> IoResult<()> {
+    let request = if on { libc::TIOCMBIS } else { libc::TIOCMBIC };
+    check(unsafe { libc::ioctl(fd, request, &line) })
+}
+
+pub fn serial_peer(path: &Path, s: &SerialSettings, opts: &Options) -> Result<Peer> {
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    let fd = f.as_raw_fd();
+    configure(fd, s).map_err(|e| format!("Failed to configure {}: {}", path.display(), e))?;
+    if let Some(on) = s.dtr {
+        set_modem_line(fd, libc::TIOCM_DTR, on)?;
+    }
+    if let Some(on) = s.rts {
+        set_modem_line(fd, libc::TIOCM_RTS, on)?;
+    }
+    debug!("Opened serial port {:?} with {:?}", path, s);
+    let control_prefix = match opts.serial_control_prefix {
+        Some(ref x) if opts.websocket_text_mode => Some(Rc::new(x.clone().into_bytes())),
+        Some(_) => {
+            warn!("--serial-control-prefix is ignored in binary mode, use --text");
+            None
+        }
+        None => None,
+    };
+    let io = UnixFile::new_nb(f)?.into_io(&tokio_reactor::Handle::default())?;
+    let sp = SerialPort {
+        io: Rc::new(RefCell::new(io)),
+        fd,
+        control_prefix,
+    };
+    Ok(Peer::new(sp.clone(), sp, None))
+}
+
+type ImplPollEvented = ::tokio_reactor::PollEvented<UnixFile<File>>;
+
+#[derive(Clone)]
+struct SerialPort {
+    io: Rc<RefCell<ImplPollEvented>>,
+    /// Owned by `io`
+    fd: RawFd,
+    control_prefix: Option<Rc<Vec<u8>>>,
+}
+
+impl SerialPort {
+    /// `--serial-control-prefix` command. Failures are only logged, as pseudo-terminals lack modem lines.
+    fn control(&self, cmd: &[u8]) {
+        let cmd = String::from_utf8_lossy(cmd);
+        let cmd = cmd.trim();
+        let ret = match cmd {
+            "break" => {
+                // Takes 0.25-0.5 seconds, so not on the event loop
+                let fd = unsafe { libc::dup(self.fd) };
+                if fd == -1 {
+                    warn!("Serial port command `break` failed: {}", std::io::Error::last_os_error());
+                    return;
+                }
+                std::thread::spawn(move || {
+                    match check(unsafe { libc::tcsendbreak(fd, 0) }) {
+                        Ok(()) => debug!("Serial port command `break` done"),
+                        Err(e) => warn!("Serial port command `break` failed: {}", e),
+                    }
+                    unsafe {
+                        libc::close(fd);
+                    }
+                });
+                return;
+            }
+            _ => {
+                let (line, v) = if let Some(v) = cmd.strip_prefix("dtr=") {
+                    (libc::TIOCM_DTR, v)
+                } else if let Some(v) = cmd.strip_prefix("rts=") {
+                    (libc::TIOCM_RTS, v)
+                } else {
+                    warn!("Unknown serial port command `{}`", cmd);
+                    return;
+                };
+                match on_off(v) {
+                    Ok(on) => set_modem_line(self.fd, line, on),
+                    Err(e) => {
+                        warn!("Serial port command `{}`: {}", cmd, e);
+                        return;
+                    }
+                }
+            }
+        };
+        match ret {
+            Ok(()) => debug!("Serial port command `{}` done", cmd),
+            Err(e) => warn!("Serial port command `{}` failed: {}", cmd, e),
+        }
+    }
+}
+
+impl AsyncRead for SerialPort {}
+impl Read for SerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.io.borrow_mut().read(buf)
+    }
+}
+
+impl AsyncWrite for SerialPort {
+    fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+        Ok(futures::Async::Ready(()))
+    }
+}
+impl Write for SerialPort {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        if let Some(ref prefix) = self.control_prefix {
+            if let Some(cmd) = buf.strip_prefix(&prefix[..]) {
+                self.control(cmd);
+                return Ok(buf.len());
+            }
+        }
+        self.io.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        self.io.borrow_mut().flush()
+    }
+}
+
+#[test]
+fn test_serial_settings() {
+    let s: SerialDevice = "/dev/ttyUSB0,115200,7E2,rtscts,dtr=off".parse().unwrap();
+    assert_eq!(s.path, PathBuf::from("/dev/ttyUSB0"));
+    assert_eq!(
+        s.settings,
+        SerialSettings {
+            baud: Some(115200),
+            data_bits: 7,
+            parity: Parity::Even,
+            stop_bits: 2,
+            flow: FlowControl::RtsCts,
+            dtr: Some(false),
+            rts: None,
+        }
+    );
+    let s: SerialDevice = "/dev/ttyS1".parse().unwrap();
+    assert_eq!(s.settings, SerialSettings::default());
+    assert!("/dev/ttyS1,9N1".parse::<SerialDevice>().is_err());
+    assert!("/dev/ttyS1,fast".parse::<SerialDevice>().is_err());
+    assert!(",9600".parse::<SerialDevice>().is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_serial_pty() {
+    use std::os::unix::io::FromRawFd;
+
+    let (mut master, slave) = unsafe {
+        let (mut m, mut s) = (0, 0);
+        let null = std::ptr::null_mut();
+        check(libc::openpty(&mut m, &mut s, null, null as *const _, null as *const _)).unwrap();
+        (File::from_raw_fd(m), File::from_raw_fd(s))
+    };
+    let path = std::fs::read_link(format!("/proc/self/fd/{}", slave.as_raw_fd())).unwrap();
+    let mut rt = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+    let mut opts = Options {
+        serial_control_prefix: Some("!".to_owned()),
+        websocket_text_mode: true,
+        ..Default::default()
+    };
+    let open = |rt: &mut tokio::runtime::current_thread::Runtime, opts: &Options| {
+        rt.block_on(futures::future::lazy(|| serial_peer(&path, &SerialSettings::default(), opts))).unwrap()
+    };
+    let Peer(r, w, _) = open(&mut rt, &opts);
+    let w = rt.block_on(tokio_io::io::write_all(w, b"!break")).unwrap().0;
+    let w = rt.block_on(tokio_io::io::write_all(w, b"hello")).unwrap().0;
+    let mut buf = [0; 5];
+    master.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+    master.write_all(b"world").unwrap();
+    let (r, buf) = rt.block_on(tokio_io::io::read_exact(r, [0; 5])).unwrap();
+    assert_eq!(&buf, b"world");
+    drop((r, w));
+
+    // No message boundaries, so no commands in binary mode
+    opts.websocket_text_mode = false;
+    let Peer(_r, w, _) = open(&mut rt, &opts);
+    let _w = rt.block_on(tokio_io::io::write_all(w, b"!dtr=on")).unwrap().0;
+    let mut buf = [0; 7];
+    master.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"!dtr=on");
+}
@@ -3,9 +3,11 @@ use super::{
     futures, my_copy, ConstructParams, L2rUser, L2rWriter, Options, Peer, PeerConstructor,
     ProgramState, Session, Specifier, Transfer,
 };
+use crate::conn_limit::{ConnLimiter, ConnSlot, STATUS_SERVICE_UNAVAILABLE, STATUS_TOO_MANY_REQUESTS};
 use crate::spawn_hack;
 use std;
 use std::cell::RefCell;
+use std::net::SocketAddr;
 use std::rc::Rc;
 use tokio_io;
 
@@ -101,10 +103,49 @@ impl Session {
     }
 }
 
+/// How many connections over the limits may be waiting for WebSocket upgrader to reply with HTTP error
+const MAX_REJECTED_IN_FLIGHT: usize = 64;
+/// How long a connection over the limits may take to get to the HTTP error reply
+const REJECTED_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
 fn l2r_new() -> L2rWriter {
     Rc::new(RefCell::new(Default::default()))
 }
 
+/// Check `--conncap` and per-IP limits for a new incoming connection, with client address in `cp`.
+/// `Err` is HTTP status for WebSocket upgrader to reject the connection with.
+fn admit(
+    cp: &ConstructParams,
+    cpc: usize,
+    max_parallel_conns: Option<usize>,
+    limiter: &Rc<ConnLimiter>,
+) -> Result<Option<ConnSlot>, u16> {
+    if let Some(cap) = max_parallel_conns {
+        if cpc > cap {
+            warn!("Dropping connection because of connection cap");
+            return Err(STATUS_SERVICE_UNAVAILABLE);
+        }
+    }
+    if !limiter.is_active() {
+        return Ok(None);
+    }
+    let ip = match cp.left_to_right {
+        L2rUser::FillIn(ref x) => x.borrow().client_addr.as_ref().and_then(|a| a.parse::<SocketAddr>().ok()),
+        L2rUser::ReadFrom(_) => None,
+    };
+    let ip = match ip {
+        Some(a) => a.ip(),
+        None => return Ok(None),
+    };
+    match limiter.acquire(ip) {
+        Ok(slot) => Ok(Some(slot)),
+        Err(reason) => {
+            warn!("Dropping connection from {}: {}", ip, reason);
+            Err(STATUS_TOO_MANY_REQUESTS)
+        }
+    }
+}
+
 pub fn serve<OE>(
     s1: Rc<dyn Specifier>,
     s2: Rc<dyn Specifier>,
@@ -167,6 +208,9 @@ where
 
     let max_parallel_conns = opts1.max_parallel_conns;
     let current_parallel_conns = Rc::new(::std::cell::Cell::new(0usize));
+    let rejected_in_flight = Rc::new(::std::cell::Cell::new(0usize));
+    let ws_upgrader = cp.borrow().global(crate::ws_server_peer::UpgraderPresent::default).0;
+    let limiter = Rc::new(ConnLimiter::new(&opts1));
 
     match left {
         PeerConstructor::Error(e) => {
@@ -176,15 +220,12 @@ where
         ServeMultipleTimes(stream) => {
             let runner = stream
                 .map(move |peer1| {
-                    let mut cpc = current_parallel_conns.get();
+                    let cpc = current_parallel_conns.get() + 1;
                     let cpc2 = current_parallel_conns.clone();
-                    cpc += 1;
-                    if let Some(cap) = max_parallel_conns {
-                        if cpc > cap {
-                            warn!("Dropping connection because of connection cap");
-                            return;
-                        }
-                    }
+                    let slot = match admit(&cp.borrow(), cpc, max_parallel_conns, &limiter) {
+                        Ok(x) => x,
+                        Err(_) => return,
+                    };
                     info!("Serving {} ongoing connections", cpc);
                     current_parallel_conns.set(cpc);
 
@@ -203,6 +244,7 @@ where
                             .map_err(move |e| e1_1(e))
                             .then(move |r| {
                                 cpc2.set(cpc2.get() - 1);
+                                drop(slot);
                                 futures::future::result(r)
                             }),
                     )
@@ -215,26 +257,53 @@ where
                 .map(move |peer1_| {
                     debug!("Underlying connection established");
 
-                    let mut cpc = current_parallel_conns.get();
+                    let cpc = current_parallel_conns.get() + 1;
                     let cpc2 = current_parallel_conns.clone();
-                    cpc += 1;
-                    if let Some(cap) = max_parallel_conns {
-                        if cpc > cap {
-                            warn!("Dropping connection because of connection cap");
-                            return;
-                        }
-                    }
-                    info!("Serving {} ongoing connections", cpc);
-                    current_parallel_conns.set(cpc);
+                    let rif2 = rejected_in_flight.clone();
+                    let admitted = admit(&cp.borrow(), cpc, max_parallel_conns, &limiter);
 
                     let cp_ = cp.borrow().deep_clone();
                     cp.borrow_mut().reset_l2r();
+                    // Rejected connections still go through the overlays for WebSocket upgrader to reply with HTTP error.
+                    // Without the upgrader, or with too many of them already waiting for it, they are just dropped.
+                    let rejected = admitted.is_err();
+                    let slot = match admitted {
+                        Ok(slot) => {
+                            info!("Serving {} ongoing connections", cpc);
+                            current_parallel_conns.set(cpc);
+                            slot
+                        }
+                        Err(_) if !ws_upgrader || rejected_in_flight.get() >= MAX_REJECTED_IN_FLIGHT => return,
+                        Err(status) => {
+                            if let L2rUser::FillIn(ref x) = cp_.left_to_right {
+                                x.borrow_mut().reject_status = Some(status);
+                            }
+                            rejected_in_flight.set(rejected_in_flight.get() + 1);
+                            None
+                        }
+                    };
                     let opts3 = opts2.clone();
                     let e1_1 = e1.clone();
                     let s2 = s2.clone();
                     let l2rc = cp_.left_to_right.clone();
+                    let upgrade = mapper(peer1_, l2rc);
+                    let upgrade: crate::BoxedNewPeerFuture = if rejected {
+                        Box::new(tokio_timer::Timeout::new(upgrade, REJECTED_TIMEOUT).map_err(|e| {
+                            e.into_inner()
+                                .unwrap_or_else(|| crate::simple_err2("Timed out rejecting connection over the limits"))
+                        }))
+                    } else {
+                        upgrade
+                    };
                     spawn_hack(
-                        mapper(peer1_, l2rc)
+                        upgrade
+                            .and_then(move |peer1| {
+                                if rejected {
+                                    Err(crate::simple_err2("Dropping connection because of connection limits"))
+                                } else {
+                                    Ok(peer1)
+                                }
+                            })
                             .and_then(move |peer1| {
                                 let cp2 = cp_.reply();
                                 let l2rc = cp2.left_to_right.clone();
@@ -247,7 +316,12 @@ where
                             })
                             .map_err(move |e| e1_1(e))
                             .then(move |r| {
-                                cpc2.set(cpc2.get() - 1);
+                                if !rejected {
+                                    cpc2.set(cpc2.get() - 1);
+                                } else {
+                                    rif2.set(rif2.get() - 1);
+                                }
+                                drop(slot);
                                 futures::future::result(r)
                             }),
                     )
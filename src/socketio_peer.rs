@@ -0,0 +1,519 @@
+use futures::future::{err, Future};
+use futures::{Async, Stream};
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::{box_up_err, simple_err, BoxedNewPeerFuture, Peer};
+use super::util::QueuedWriter;
+use super::{ConstructParams, Options, PeerConstructor, Specifier};
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use serde_json::Value;
+
+#[derive(Debug)]
+pub struct SocketIo<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for SocketIo<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p, _| socketio_peer(p, cp.program_options.clone()))
+    }
+    specifier_boilerplate!(noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = SocketIoClass,
+    target = SocketIo,
+    prefixes = ["socketio:", "socket.io:"],
+    arg_handling = subspec,
+    overlay = true,
+    MessageOriented,
+    MulticonnectnessDependsOnInnerType,
+    help = r#"
+[A] Speak Engine.IO v4 / Socket.IO v5 client protocol over inner WebSocket connection.
+
+Performs the handshake, connects to namespaces (`--socketio-namespace`, default is `/`)
+and waits until the server accepts the first of them; a refusal fails the connection.
+Answers pings automatically and converts between Socket.IO packets and simple messages:
+
+    [/namespace ][#ackid ]event json-args...
+    [/namespace ]ack#ackid json-args...
+
+Namespace prefix is omitted for the first connected namespace.
+`#ackid` means that the event requests an acknowledgement, which is then sent as `ack#ackid`.
+Arguments are whitespace-separated JSON values. If outgoing arguments are not valid JSON,
+the whole remainder of the message is sent as one string argument.
+With `--socketio-json`, event name and arguments are instead represented as a JSON array like `["event",1,2]`.
+
+Inner WebSocket URL should select websocket transport directly.
+
+Example:
+
+    websocat -t - socketio:ws://127.0.0.1:3000/socket.io/?EIO=4&transport=websocket
+
+    > chat "hello" {"room":1}
+    < chat "hello from server"
+"#
+);
+
+/// Socket.IO packet contents, as exchanged with the user
+#[derive(Debug, PartialEq)]
+struct SioMessage {
+    /// `None` means the primary namespace
+    ns: Option<String>,
+    ack_id: Option<u64>,
+    is_ack: bool,
+    /// For events, the first element is the event name
+    data: Vec<Value>,
+}
+
+impl SioMessage {
+    fn render(&self, json: bool) -> Vec<u8> {
+        let mut s = String::new();
+        if let Some(ref ns) = self.ns {
+            s.push_str(ns);
+            s.push(' ');
+        }
+        if self.is_ack {
+            s.push_str(&format!("ack#{}", self.ack_id.unwrap_or(0)));
+            for a in &self.data {
+                s.push(' ');
+                s.push_str(&a.to_string());
+            }
+            return s.into_bytes();
+        }
+        if let Some(id) = self.ack_id {
+            s.push_str(&format!("#{} ", id));
+        }
+        if json {
+            s.push_str(&Value::Array(self.data.clone()).to_string());
+        } else {
+            let mut it = self.data.iter();
+            match it.next() {
+                Some(Value::String(x)) => s.push_str(x),
+                Some(x) => s.push_str(&x.to_string()),
+                None => (),
+            }
+            for a in it {
+                s.push(' ');
+                s.push_str(&a.to_string());
+            }
+        }
+        s.into_bytes()
+    }
+
+    fn parse(msg: &str, json: bool) -> Result<SioMessage, Box<dyn std::error::Error>> {
+        let mut rest = msg.trim_end_matches(&['\n', '\r'][..]).trim_start();
+        let mut ns = None;
+        let mut ack_id = None;
+        let mut is_ack = false;
+
+        fn split_word(x: &str) -> (&str, &str) {
+            match x.find(char::is_whitespace) {
+                Some(i) => (&x[..i], x[i..].trim_start()),
+                None => (x, ""),
+            }
+        }
+
+        if rest.starts_with('/') {
+            let (w, r) = split_word(rest);
+            ns = Some(w.to_owned());
+            rest = r;
+        }
+        if rest.starts_with("ack#") {
+            let (w, r) = split_word(rest);
+            ack_id = Some(w[4..].parse()?);
+            is_ack = true;
+            rest = r;
+        } else if rest.starts_with('#') {
+            let (w, r) = split_word(rest);
+            ack_id = Some(w[1..].parse()?);
+            rest = r;
+        }
+
+        let data = if is_ack {
+            parse_args(rest)
+        } else if json {
+            match serde_json::from_str(rest)? {
+                Value::Array(x) => x,
+                _ => Err("Socket.IO event should be a JSON array")?,
+            }
+        } else {
+            let (event, args) = split_word(rest);
+            if event.is_empty() {
+                Err("Empty Socket.IO event name")?;
+            }
+            let mut data = vec![Value::String(event.to_owned())];
+            data.extend(parse_args(args));
+            data
+        };
+        Ok(SioMessage { ns, ack_id, is_ack, data })
+    }
+}
+
+fn parse_args(x: &str) -> Vec<Value> {
+    if x.is_empty() {
+        return vec![];
+    }
+    let it = serde_json::Deserializer::from_str(x).into_iter::<Value>();
+    match it.collect() {
+        Ok(v) => v,
+        Err(_) => vec![Value::String(x.to_owned())],
+    }
+}
+
+/// Packet type, namespace, ack id and payload
+type SioPacket = (u8, String, Option<u64>, Option<Value>);
+
+/// Parse Socket.IO packet that follows Engine.IO message type `4`.
+fn parse_sio_packet(p: &str) -> Result<SioPacket, Box<dyn std::error::Error>> {
+    let t = match p.bytes().next() {
+        Some(c) if c.is_ascii_digit() => c - b'0',
+        _ => Err("Invalid Socket.IO packet type")?,
+    };
+    let mut rest = &p[1..];
+    if t == 5 || t == 6 {
+        Err("Binary Socket.IO packets are not supported")?;
+    }
+    let mut ns = "/".to_owned();
+    if rest.starts_with('/') {
+        let end = rest.find(',').unwrap_or(rest.len());
+        ns = rest[..end].to_owned();
+        rest = &rest[(end + 1).min(rest.len())..];
+    }
+    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    let ack_id = if digits > 0 { Some(rest[..digits].parse()?) } else { None };
+    rest = &rest[digits..];
+    let data = if rest.is_empty() { None } else { Some(serde_json::from_str(rest)?) };
+    Ok((t, ns, ack_id, data))
+}
+
+struct Shared {
+    w: RefCell<QueuedWriter>,
+    namespaces: Vec<String>,
+    json: bool,
+}
+
+impl Shared {
+    fn ns_for_user(&self, ns: String) -> Option<String> {
+        if ns == self.namespaces[0] {
+            None
+        } else {
+            Some(ns)
+        }
+    }
+}
+
+fn encode_sio(t: u8, ns: &str, ack_id: Option<u64>, data: Option<&Value>) -> Vec<u8> {
+    let mut s = format!("4{}", t);
+    if ns != "/" {
+        s.push_str(ns);
+        s.push(',');
+    }
+    if let Some(id) = ack_id {
+        s.push_str(&format!("{}", id));
+    }
+    if let Some(d) = data {
+        s.push_str(&d.to_string());
+    }
+    s.into_bytes()
+}
+
+pub fn socketio_peer(inner_peer: Peer, opts: Rc<Options>) -> BoxedNewPeerFuture {
+    let (r, w, hup) = (inner_peer.0, inner_peer.1, inner_peer.2);
+    let mut namespaces = opts.socketio_namespaces.clone();
+    if namespaces.is_empty() {
+        namespaces.push("/".to_owned());
+    }
+    let auth = match opts.socketio_auth.as_ref().map(|x| serde_json::from_str::<Value>(x)) {
+        None => None,
+        Some(Ok(x)) => Some(x),
+        Some(Err(e)) => return Box::new(err(box_up_err(e))) as BoxedNewPeerFuture,
+    };
+    let json = opts.socketio_json;
+    let bufsz = opts.buffer_size;
+
+    info!("Waiting for Engine.IO open packet");
+    let f = tokio_io::io::read(r, vec![0; bufsz])
+        .map_err(box_up_err)
+        .and_then(move |(r, buf, n)| {
+            let handshake = (|| {
+                let open = std::str::from_utf8(&buf[..n])?;
+                if !open.starts_with('0') {
+                    Err("Expected Engine.IO open packet")?;
+                }
+                let open: Value = serde_json::from_str(&open[1..])?;
+                debug!("Engine.IO open packet: {}", open);
+                let ping_interval = open["pingInterval"].as_u64().unwrap_or(25000);
+                let ping_timeout = open["pingTimeout"].as_u64().unwrap_or(20000);
+                Ok(Duration::from_millis(ping_interval + ping_timeout))
+            })();
+            let liveness: Duration = match handshake {
+                Ok(x) => x,
+                Err(e) => return Box::new(err(e)) as BoxedNewPeerFuture,
+            };
+            let connects: Vec<Vec<u8>> = namespaces
+                .iter()
+                .map(|ns| encode_sio(0, ns, None, auth.as_ref()))
+                .collect();
+            let f = futures::stream::iter_ok::<_, IoError>(connects)
+                .fold(w, |w, pkt| tokio_io::io::write_all(w, pkt).map(|(w, _)| w))
+                .map_err(box_up_err)
+                .and_then(move |w| {
+                    info!("Connecting to Socket.IO namespaces {:?}", namespaces);
+                    let shared = Rc::new(Shared {
+                        w: RefCell::new(QueuedWriter::new(w)),
+                        namespaces,
+                        json,
+                    });
+                    let mut rd = Some(SocketIoReader {
+                        r,
+                        shared: shared.clone(),
+                        buf,
+                        liveness,
+                        timer: tokio_timer::Delay::new(Instant::now() + liveness),
+                        pending: VecDeque::new(),
+                    });
+                    // Don't report the connection as established until the primary namespace is
+                    // acknowledged, so that refusals fail the connection attempt itself.
+                    futures::future::poll_fn(move || loop {
+                        let ev = match rd.as_mut().unwrap().next_event() {
+                            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                                return Ok(Async::NotReady)
+                            }
+                            x => x.map_err(box_up_err)?,
+                        };
+                        match ev {
+                            SioEvent::Message(m) => rd.as_mut().unwrap().pending.push_back(m),
+                            SioEvent::Connected => return Ok(Async::Ready(rd.take().unwrap())),
+                            SioEvent::Closed => Err("Connection closed before Socket.IO namespace connect")?,
+                        }
+                    })
+                    .map(move |rd| {
+                        let wr = SocketIoWriter {
+                            shared,
+                            disconnect_sent: false,
+                        };
+                        Peer::new(rd, wr, hup)
+                    })
+                });
+            Box::new(f) as BoxedNewPeerFuture
+        });
+    Box::new(f) as BoxedNewPeerFuture
+}
+
+struct SocketIoReader {
+    r: Box<dyn AsyncRead>,
+    shared: Rc<Shared>,
+    buf: Vec<u8>,
+    /// If nothing arrives from server for this long, connection is considered dead
+    liveness: Duration,
+    timer: tokio_timer::Delay,
+    /// Messages received while waiting for the primary namespace connection
+    pending: VecDeque<Vec<u8>>,
+}
+
+enum SioEvent {
+    /// Rendered message for the user
+    Message(Vec<u8>),
+    /// Server acknowledged connection to the primary namespace
+    Connected,
+    Closed,
+}
+
+impl SocketIoReader {
+    /// Read and handle Engine.IO packets until there is something to report
+    fn next_event(&mut self) -> Result<SioEvent, IoError> {
+        loop {
+            self.shared.w.borrow_mut().flush_ignoring_wouldblock()?;
+            match self.timer.poll() {
+                Ok(Async::Ready(())) => {
+                    return Err(simple_err("Engine.IO ping timeout".to_owned()));
+                }
+                Ok(Async::NotReady) => (),
+                Err(e) => error!("tokio-timer's Delay: {}", e),
+            }
+            let n = self.r.read(&mut self.buf[..])?;
+            if n == 0 {
+                return Ok(SioEvent::Closed);
+            }
+            self.timer.reset(Instant::now() + self.liveness);
+            let pkt = match std::str::from_utf8(&self.buf[..n]) {
+                Ok(x) => x.trim_end(),
+                Err(_) => {
+                    warn!("Ignoring binary Engine.IO packet");
+                    continue;
+                }
+            };
+            trace!("Engine.IO packet: {}", pkt);
+            match pkt.as_bytes().first() {
+                Some(b'1') => {
+                    info!("Engine.IO connection closed by server");
+                    return Ok(SioEvent::Closed);
+                }
+                Some(b'2') => {
+                    debug!("Engine.IO ping");
+                    let mut w = self.shared.w.borrow_mut();
+                    let mut pong = pkt.as_bytes().to_vec();
+                    pong[0] = b'3';
+                    w.queue.push_back(pong);
+                    continue;
+                }
+                Some(b'4') => (),
+                _ => {
+                    debug!("Ignoring Engine.IO packet {:?}", pkt);
+                    continue;
+                }
+            }
+            let (t, ns, ack_id, data) = match parse_sio_packet(&pkt[1..]) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Ignoring Socket.IO packet: {}", e);
+                    continue;
+                }
+            };
+            let m = match t {
+                0 => {
+                    info!("Connected to Socket.IO namespace {}", ns);
+                    if ns == self.shared.namespaces[0] {
+                        return Ok(SioEvent::Connected);
+                    }
+                    continue;
+                }
+                1 => {
+                    info!("Socket.IO namespace {} disconnected", ns);
+                    if ns == self.shared.namespaces[0] {
+                        return Ok(SioEvent::Closed);
+                    }
+                    continue;
+                }
+                2 | 3 => {
+                    let data = match data {
+                        Some(Value::Array(x)) => x,
+                        _ => {
+                            warn!("Ignoring malformed Socket.IO event");
+                            continue;
+                        }
+                    };
+                    SioMessage {
+                        ns: self.shared.ns_for_user(ns),
+                        ack_id,
+                        is_ack: t == 3,
+                        data,
+                    }
+                }
+                4 => {
+                    let e = format!(
+                        "Socket.IO connection to namespace {} refused: {}",
+                        ns,
+                        data.unwrap_or(Value::Null)
+                    );
+                    return Err(simple_err(e));
+                }
+                _ => {
+                    debug!("Ignoring Socket.IO packet {:?}", pkt);
+                    continue;
+                }
+            };
+            return Ok(SioEvent::Message(m.render(self.shared.json)));
+        }
+    }
+}
+
+impl Read for SocketIoReader {
+    fn read(&mut self, b: &mut [u8]) -> Result<usize, IoError> {
+        let m = match self.pending.pop_front() {
+            Some(m) => m,
+            None => loop {
+                match self.next_event()? {
+                    SioEvent::Message(m) => break m,
+                    SioEvent::Connected => continue,
+                    SioEvent::Closed => return Ok(0),
+                }
+            },
+        };
+        if m.len() > b.len() {
+            warn!("Socket.IO message is too big for the buffer, truncating it");
+        }
+        let l = m.len().min(b.len());
+        b[..l].copy_from_slice(&m[..l]);
+        Ok(l)
+    }
+}
+impl AsyncRead for SocketIoReader {}
+
+struct SocketIoWriter {
+    shared: Rc<Shared>,
+    disconnect_sent: bool,
+}
+
+impl Write for SocketIoWriter {
+    fn write(&mut self, b: &[u8]) -> Result<usize, IoError> {
+        let mut w = self.shared.w.borrow_mut();
+        if !w.queue.is_empty() {
+            w.flush_queue()?;
+        }
+        let msg = String::from_utf8_lossy(b);
+        if msg.trim().is_empty() {
+            return Ok(b.len());
+        }
+        let m = match SioMessage::parse(&msg, self.shared.json) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Failed to interpret message as Socket.IO event: {}", e);
+                return Ok(b.len());
+            }
+        };
+        let ns = m.ns.unwrap_or_else(|| self.shared.namespaces[0].clone());
+        let t = if m.is_ack { 3 } else { 2 };
+        let pkt = encode_sio(t, &ns, m.ack_id, Some(&Value::Array(m.data)));
+        w.queue.push_back(pkt);
+        w.flush_ignoring_wouldblock()?;
+        Ok(b.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.shared.w.borrow_mut().flush_queue()
+    }
+}
+impl AsyncWrite for SocketIoWriter {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        let mut w = self.shared.w.borrow_mut();
+        if !self.disconnect_sent {
+            self.disconnect_sent = true;
+            let namespaces = self.shared.namespaces.clone();
+            for ns in namespaces {
+                w.queue.push_back(encode_sio(1, &ns, None, None));
+            }
+        }
+        match w.flush_queue() {
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            x => x?,
+        }
+        w.w.shutdown()
+    }
+}
+
+#[test]
+fn test_socketio_messages() {
+    let m = SioMessage::parse("/admin #12 chat \"hi\" {\"a\":1}\n", false).unwrap();
+    assert_eq!(m.ns.as_ref().unwrap(), "/admin");
+    assert_eq!(m.ack_id, Some(12));
+    assert_eq!(Value::Array(m.data.clone()).to_string(), r#"["chat","hi",{"a":1}]"#);
+    assert_eq!(m.render(false), b"/admin #12 chat \"hi\" {\"a\":1}".to_vec());
+    assert_eq!(m.render(true), b"/admin #12 [\"chat\",\"hi\",{\"a\":1}]".to_vec());
+
+    let m = SioMessage::parse("say hello world", false).unwrap();
+    assert_eq!(Value::Array(m.data).to_string(), r#"["say","hello world"]"#);
+
+    let m = SioMessage::parse("ack#3 true", false).unwrap();
+    assert!(m.is_ack);
+    assert_eq!(encode_sio(3, "/", m.ack_id, Some(&Value::Array(m.data))), b"433[true]".to_vec());
+
+    let (t, ns, id, data) = parse_sio_packet("2/admin,7[\"ev\",1]").unwrap();
+    assert_eq!((t, &ns[..], id), (2, "/admin", Some(7)));
+    assert_eq!(data.unwrap().to_string(), r#"["ev",1]"#);
+}
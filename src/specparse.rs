@@ -8,7 +8,7 @@ pub fn spec(s: &str) -> Result<Rc<dyn Specifier>> {
 }
 
 fn some_checks(s: &str) -> Result<()> {
-    #[cfg(not(feature = "ssl"))]
+    #[cfg(not(any(feature = "ssl", feature = "rustls")))]
     {
         if s.starts_with("wss://") {
             Err("SSL is not compiled in. Use ws:// or get/make another Websocat build.\nYou can also try to workaround missing SSL by using ws-c:cmd:socat trick (see some ws-c: example)")?
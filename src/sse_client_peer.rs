@@ -0,0 +1,361 @@
+extern crate http_bytes;
+
+use futures::future::ok;
+use futures::{Async, Future};
+
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use super::{BoxedNewPeerFuture, Peer};
+use super::{once, simple_err, wouldblock, ConstructParams, PeerConstructor, Specifier};
+use super::trivial_peer::DevNull;
+
+use std::io::{Error as IoError, Read};
+use tokio_io::AsyncRead;
+
+use http_bytes::http::header::{HeaderName, HeaderValue, ACCEPT};
+
+#[derive(Debug)]
+pub struct SseClient(pub Rc<dyn Specifier>);
+impl Specifier for SseClient {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        once(sse_client_peer(self.0.clone(), cp))
+    }
+    specifier_boilerplate!(singleconnect noglobalstate has_subspec);
+    self_0_is_subspecifier!(...);
+}
+specifier_class!(
+    name = SseClientClass,
+    target = SseClient,
+    prefixes = ["sse:", "sse-client:"],
+    arg_handling = subspec,
+    overlay = true,
+    MessageOriented,
+    SingleConnect,
+    help = r#"
+[A] Consume server-sent events (`text/event-stream`) from inner HTTP peer.
+
+Each event's `data:` lines (joined by newlines) become one message.
+With `--sse-json`, messages are JSON objects like
+`{"event":"message","data":"...","id":"42"}` instead.
+
+When the stream ends or the connection fails, it is re-established after
+`--sse-retry-millis` (or the delay that server specified in `retry:`),
+sending `Last-Event-ID` request header if server has assigned any `id:`.
+Use `--sse-no-reconnect` to just finish instead.
+
+Data written to this peer is discarded.
+
+Example: bridge SSE feed to WebSocket clients:
+
+    websocat -E -t ws-l:127.0.0.1:8080 broadcast:sse:http://127.0.0.1:1234/events
+
+Example: print events from a feed, with their names:
+
+    websocat -U --sse-json - sse:http://127.0.0.1:1234/events
+"#
+);
+
+#[derive(Debug)]
+struct SseEvent {
+    event: String,
+    data: Vec<u8>,
+    id: String,
+}
+
+/// Incremental `text/event-stream` parser
+#[derive(Default)]
+struct SseParser {
+    line: Vec<u8>,
+    /// Previous byte was CR, so LF that follows it should not end another line
+    after_cr: bool,
+    bom_checked: bool,
+    data: Vec<u8>,
+    event: String,
+    last_event_id: String,
+    retry: Option<u64>,
+}
+
+impl SseParser {
+    fn feed(&mut self, buf: &[u8], out: &mut VecDeque<SseEvent>) {
+        for &c in buf {
+            if self.after_cr {
+                self.after_cr = false;
+                if c == b'\n' {
+                    continue;
+                }
+            }
+            match c {
+                b'\r' => {
+                    self.after_cr = true;
+                    self.process_line(out);
+                }
+                b'\n' => self.process_line(out),
+                _ => self.line.push(c),
+            }
+        }
+    }
+
+    fn process_line(&mut self, out: &mut VecDeque<SseEvent>) {
+        let mut line = std::mem::take(&mut self.line);
+        if !self.bom_checked {
+            self.bom_checked = true;
+            if line.starts_with(b"\xEF\xBB\xBF") {
+                line.drain(0..3);
+            }
+        }
+        if line.is_empty() {
+            self.dispatch(out);
+            return;
+        }
+        if line[0] == b':' {
+            // comment
+            return;
+        }
+        let (field, value) = match line.iter().position(|&x| x == b':') {
+            Some(i) => {
+                let mut v = &line[i + 1..];
+                if v.starts_with(b" ") {
+                    v = &v[1..];
+                }
+                (&line[..i], v)
+            }
+            None => (&line[..], &b""[..]),
+        };
+        match field {
+            b"event" => self.event = String::from_utf8_lossy(value).into_owned(),
+            b"data" => {
+                self.data.extend_from_slice(value);
+                self.data.push(b'\n');
+            }
+            b"id" => {
+                if !value.contains(&0) {
+                    self.last_event_id = String::from_utf8_lossy(value).into_owned();
+                }
+            }
+            b"retry" => {
+                if !value.is_empty() && value.iter().all(|x| x.is_ascii_digit()) {
+                    if let Ok(x) = String::from_utf8_lossy(value).parse() {
+                        self.retry = Some(x);
+                    }
+                }
+            }
+            _ => debug!("Ignoring unknown SSE field"),
+        }
+    }
+
+    fn dispatch(&mut self, out: &mut VecDeque<SseEvent>) {
+        let event = std::mem::take(&mut self.event);
+        let mut data = std::mem::take(&mut self.data);
+        if data.is_empty() {
+            return;
+        }
+        data.pop();
+        out.push_back(SseEvent {
+            event: if event.is_empty() { "message".to_owned() } else { event },
+            data,
+            id: self.last_event_id.clone(),
+        });
+    }
+
+    /// Forget incomplete event when connection ends. Last event ID and retry delay survive.
+    fn reset_connection(&mut self) {
+        self.line.clear();
+        self.data.clear();
+        self.event.clear();
+        self.after_cr = false;
+        self.bom_checked = false;
+    }
+}
+
+struct SseReader {
+    s: Rc<dyn Specifier>,
+    cp: ConstructParams,
+    p: Option<Peer>,
+    n: Option<BoxedNewPeerFuture>,
+    ratelimiter: Option<tokio_timer::Delay>,
+    parser: SseParser,
+    events: VecDeque<SseEvent>,
+    /// Remainder of a message that did not fit into reader's buffer
+    debt: Vec<u8>,
+    buf: Vec<u8>,
+    json: bool,
+    reconnect: bool,
+    reconnect_delay: std::time::Duration,
+    finished: bool,
+    already_warned: bool,
+}
+
+impl SseReader {
+    fn inner_cp(&self) -> ConstructParams {
+        let mut cp = self.cp.clone();
+        let mut opts = (*cp.program_options).clone();
+        if !opts.request_headers.iter().any(|(hn, _)| hn == ACCEPT) {
+            opts.request_headers.push((ACCEPT, HeaderValue::from_static("text/event-stream")));
+        }
+        if !self.parser.last_event_id.is_empty() {
+            match HeaderValue::from_str(&self.parser.last_event_id) {
+                Ok(hv) => opts.request_headers.push((HeaderName::from_static("last-event-id"), hv)),
+                Err(_) => warn!("SSE event id is not suitable for Last-Event-ID header"),
+            }
+        }
+        cp.program_options = Rc::new(opts);
+        cp
+    }
+
+    fn disconnected(&mut self) {
+        self.p = None;
+        self.parser.reset_connection();
+        if !self.reconnect {
+            self.finished = true;
+            return;
+        }
+        if let Some(x) = self.parser.retry {
+            self.reconnect_delay = std::time::Duration::from_millis(x);
+        }
+        info!("Reconnecting to SSE server in {} ms", self.reconnect_delay.as_millis());
+        self.ratelimiter = Some(tokio_timer::Delay::new(std::time::Instant::now() + self.reconnect_delay));
+    }
+
+    fn render(&self, ev: SseEvent) -> Vec<u8> {
+        if !self.json {
+            return ev.data;
+        }
+        let mut j = serde_json::json!({
+            "event": ev.event,
+            "data": String::from_utf8_lossy(&ev.data),
+        });
+        if !ev.id.is_empty() {
+            j["id"] = serde_json::Value::String(ev.id);
+        }
+        j.to_string().into_bytes()
+    }
+}
+
+impl Read for SseReader {
+    fn read(&mut self, b: &mut [u8]) -> Result<usize, IoError> {
+        loop {
+            if !self.debt.is_empty() {
+                let l = b.len().min(self.debt.len());
+                b[..l].copy_from_slice(&self.debt[..l]);
+                self.debt.drain(..l);
+                return Ok(l);
+            }
+            if let Some(ev) = self.events.pop_front() {
+                let msg = self.render(ev);
+                if msg.len() > b.len() {
+                    warn!("SSE event is too big for the buffer, splitting it");
+                }
+                let l = b.len().min(msg.len());
+                b[..l].copy_from_slice(&msg[..l]);
+                self.debt = msg[l..].to_vec();
+                return Ok(l);
+            }
+            if self.finished {
+                return Ok(0);
+            }
+            if let Some(delay) = self.ratelimiter.as_mut() {
+                match delay.poll() {
+                    Ok(Async::Ready(_)) => {
+                        self.ratelimiter = None;
+                    }
+                    Err(e) => {
+                        error!("tokio-timer's Delay: {}", e);
+                        self.ratelimiter = None;
+                    }
+                    Ok(Async::NotReady) => return wouldblock(),
+                }
+            }
+            if let Some(ref mut p) = self.p {
+                match p.0.read(&mut self.buf[..]) {
+                    Ok(0) => {
+                        info!("SSE stream ended");
+                        self.disconnected();
+                    }
+                    Ok(n) => {
+                        self.parser.feed(&self.buf[..n], &mut self.events);
+                    }
+                    Err(e) => {
+                        if e.kind() == ::std::io::ErrorKind::WouldBlock {
+                            return Err(e);
+                        }
+                        warn!("SSE stream: {}", e);
+                        self.disconnected();
+                    }
+                }
+                continue;
+            }
+            if let Some(mut bnpf) = self.n.take() {
+                match bnpf.poll() {
+                    Ok(Async::Ready(p)) => {
+                        debug!("Connected to SSE server");
+                        self.p = Some(p);
+                    }
+                    Ok(Async::NotReady) => {
+                        self.n = Some(bnpf);
+                        return wouldblock();
+                    }
+                    Err(e) => {
+                        if !self.reconnect {
+                            return Err(simple_err(format!("{}", e)));
+                        }
+                        if !self.already_warned {
+                            self.already_warned = true;
+                            warn!("Connecting to SSE server failed: {}. Further failures will have lower log severity.", e);
+                        } else {
+                            info!("Connecting to SSE server failed: {}", e);
+                        }
+                        self.disconnected();
+                    }
+                }
+                continue;
+            }
+            let cp = self.inner_cp();
+            let l2r = cp.left_to_right.clone();
+            self.n = Some(self.s.construct(cp).get_only_first_conn(l2r));
+        }
+    }
+}
+impl AsyncRead for SseReader {}
+
+pub fn sse_client_peer(s: Rc<dyn Specifier>, cp: ConstructParams) -> BoxedNewPeerFuture {
+    let opts = cp.program_options.clone();
+    let r = SseReader {
+        s,
+        cp,
+        p: None,
+        n: None,
+        ratelimiter: None,
+        parser: Default::default(),
+        events: VecDeque::new(),
+        debt: vec![],
+        buf: vec![0; opts.buffer_size],
+        json: opts.sse_json,
+        reconnect: !opts.sse_no_reconnect,
+        reconnect_delay: std::time::Duration::from_millis(opts.sse_retry_millis),
+        finished: false,
+        already_warned: false,
+    };
+    let peer = Peer::new(r, DevNull, None);
+    Box::new(ok(peer)) as BoxedNewPeerFuture
+}
+
+#[test]
+fn test_sse_parser() {
+    let mut p = SseParser::default();
+    let mut out = VecDeque::new();
+    p.feed(b"\xEF\xBB\xBF: comment\r\nevent: upd", &mut out);
+    p.feed(b"ate\ndata: 1\r", &mut out);
+    p.feed(b"\ndata:2\n\nid: 5\nretry: 100\ndata: 3\n\n", &mut out);
+    p.feed(b"data: incomplete", &mut out);
+    p.reset_connection();
+    assert_eq!(out.len(), 2);
+    assert_eq!(out[0].event, "update");
+    assert_eq!(out[0].data, b"1\n2");
+    assert_eq!(out[0].id, "");
+    assert_eq!(out[1].event, "message");
+    assert_eq!(out[1].data, b"3");
+    assert_eq!(out[1].id, "5");
+    assert_eq!(p.retry, Some(100));
+    assert!(p.data.is_empty());
+}
@@ -0,0 +1,447 @@
+//! Direct use of OpenSSL for `ssl` feature, on platforms where native-tls is based on it anyway.
+//! Needed for features native-tls does not have, like client certificate verification or key logging.
+
+use futures::future::{ok, Future};
+use futures::Async;
+
+use std::io::{Error as IoError, Read, Result as IoResult, Write};
+use std::net::IpAddr;
+
+use super::{simple_err, BoxedNewPeerFuture, Peer};
+use crate::util::peer_err2;
+use super::{HupToken, L2rUser, Options};
+
+extern crate openssl;
+extern crate readwrite;
+
+use self::openssl::hash::MessageDigest;
+use self::openssl::pkcs12::Pkcs12;
+use self::openssl::pkey::PKey;
+use self::openssl::sha::sha256;
+pub use self::openssl::ssl::SslAcceptor;
+use self::openssl::ssl::{
+    ErrorCode, HandshakeError, MidHandshakeSslStream, NameType, SniError, SslAcceptorBuilder, SslConnector,
+    SslContextBuilder, SslMethod, SslRef, SslStream, SslVerifyMode,
+};
+use self::openssl::x509::store::X509StoreBuilder;
+use self::openssl::x509::{X509NameRef, X509Ref, X509VerifyResult, X509};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use super::options::TlsPin;
+use super::tls_common::{fingerprint_to_string, pin_matches, sni_matches, SelfSignedCert, SessionInfo};
+
+/// Convert private key in any format OpenSSL understands to PKCS#8.
+/// native-tls itself insists on PKCS#8 keys, but PKCS#1 and SEC1 ones are also common.
+pub fn pkcs8_key(key: &[u8]) -> Result<Vec<u8>, Box<dyn ::std::error::Error>> {
+    Ok(PKey::private_key_from_pem(key)?.private_key_to_pem_pkcs8()?)
+}
+
+/// `--tls-self-signed`: generate a throwaway certificate for given host names and IP addresses.
+pub fn self_signed_cert(names: &[String]) -> Result<SelfSignedCert, Box<dyn ::std::error::Error>> {
+    use self::openssl::asn1::{Asn1Integer, Asn1Time};
+    use self::openssl::bn::{BigNum, MsbOption};
+    use self::openssl::ec::{EcGroup, EcKey};
+    use self::openssl::nid::Nid;
+    use self::openssl::x509::extension::{BasicConstraints, ExtendedKeyUsage, SubjectAlternativeName};
+    use self::openssl::x509::{X509Builder, X509NameBuilder};
+
+    let key = PKey::from_ec_key(EcKey::generate(&*EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, &names[0])?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(127, MsbOption::MAYBE_ZERO, false)?;
+
+    let mut b = X509Builder::new()?;
+    b.set_version(2)?;
+    b.set_serial_number(&*Asn1Integer::from_bn(&serial)?)?;
+    b.set_subject_name(&name)?;
+    b.set_issuer_name(&name)?;
+    b.set_pubkey(&key)?;
+    b.set_not_before(&*Asn1Time::days_from_now(0)?)?;
+    b.set_not_after(&*Asn1Time::days_from_now(365)?)?;
+    b.append_extension(BasicConstraints::new().critical().build()?)?;
+    b.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+    let mut san = SubjectAlternativeName::new();
+    for n in names {
+        if n.parse::<IpAddr>().is_ok() {
+            san.ip(n);
+        } else {
+            san.dns(n);
+        }
+    }
+    let san = san.build(&b.x509v3_context(None, None))?;
+    b.append_extension(san)?;
+    b.sign(&key, MessageDigest::sha256())?;
+    let cert = b.build();
+
+    Ok(SelfSignedCert {
+        cert_pem: cert.to_pem()?,
+        key_pem: key.private_key_to_pem_pkcs8()?,
+        sha256: cert.digest(MessageDigest::sha256())?.to_vec(),
+    })
+}
+
+fn openssl_connector(progopt: &Options) -> Result<SslConnector, Box<dyn ::std::error::Error>> {
+    let mut b = SslConnector::builder(SslMethod::tls())?;
+    if progopt.tls_insecure || !progopt.tls_pin.is_empty() {
+        // With pins the certificate is checked after the handshake instead
+        b.set_verify(SslVerifyMode::NONE);
+    }
+    let mut store = X509StoreBuilder::new()?;
+    if !progopt.tls_no_system_ca {
+        store.set_default_paths()?;
+    }
+    for pem in &progopt.tls_ca {
+        for c in X509::stack_from_pem(pem)? {
+            store.add_cert(c)?;
+        }
+    }
+    b.set_cert_store(store.build());
+
+    let identity = if let (Some(cert), Some(key)) = (&progopt.client_tls_cert, &progopt.client_tls_key) {
+        set_pem_identity(&mut b, cert, key)
+    } else if let Some(ref der) = progopt.client_pkcs12_der {
+        set_pkcs12_identity(&mut b, der, progopt.client_pkcs12_passwd.as_deref())
+    } else {
+        Ok(())
+    };
+    if let Err(e) = identity {
+        error!(
+            "Unable to parse client identity: {}\nContinuing without a client identity",
+            e
+        )
+    }
+    set_keylog(&mut b, progopt);
+    Ok(b.build())
+}
+
+fn set_keylog(b: &mut SslContextBuilder, progopt: &Options) {
+    if let Some(ref keylog) = progopt.tls_keylog {
+        let keylog = keylog.clone();
+        b.set_keylog_callback(move |_, line| keylog.write_line(line));
+    }
+}
+
+fn check_pins(pins: &[TlsPin], cert: Option<X509>) -> Result<(), Box<dyn ::std::error::Error>> {
+    let cert = cert.ok_or("TLS server did not present a certificate")?;
+    let spki = cert.public_key()?.public_key_to_der()?;
+    if !pin_matches(pins, &cert.digest(MessageDigest::sha256())?, &sha256(&spki)) {
+        Err("TLS server certificate does not match any of --tls-pin fingerprints")?;
+    }
+    Ok(())
+}
+
+/// Check certificate that native-tls got from server against `--tls-pin`s
+pub fn check_pins_der(pins: &[TlsPin], cert: Option<&[u8]>) -> Result<(), Box<dyn ::std::error::Error>> {
+    check_pins(pins, cert.map(X509::from_der).transpose()?)
+}
+
+/// Subject and issuer of a certificate that native-tls got from peer
+pub fn certificate_names(der: &[u8]) -> Result<(String, String), Box<dyn ::std::error::Error>> {
+    let c = X509::from_der(der)?;
+    Ok((x509_name_to_string(c.subject_name()), x509_name_to_string(c.issuer_name())))
+}
+
+pub fn connect(inner_peer: Peer, dom: Option<String>, progopt: &Options) -> BoxedNewPeerFuture {
+    let hup = inner_peer.2;
+    let squashed_peer = readwrite::ReadWriteAsync::new(inner_peer.0, inner_peer.1);
+
+    let config = openssl_connector(progopt).and_then(|c| {
+        let mut config = c.configure()?;
+        if dom.is_none() {
+            config.set_use_server_name_indication(false);
+            config.set_verify_hostname(false);
+        }
+        Ok(config)
+    });
+    let config = match config {
+        Ok(x) => x,
+        Err(e) => return peer_err2(e),
+    };
+    let pins = progopt.tls_pin.clone();
+
+    info!("Connecting to TLS");
+    let verified = dom.is_some() || !pins.is_empty();
+    let handshake = match MidHandshake::new(config.connect(dom.as_deref().unwrap_or(""), squashed_peer)) {
+        Ok(x) => x,
+        Err(e) => return peer_err2(e),
+    };
+    Box::new(handshake.and_then(move |s| {
+        if !pins.is_empty() {
+            check_pins(&pins, s.ssl().peer_certificate())?;
+            info!("Connected to TLS, server certificate matches the pin");
+        } else if verified {
+            info!("Connected to TLS");
+        } else {
+            warn!("Connected to TLS without proper verification of certificate. Use --tls-domain option.");
+        }
+        session_info(s.ssl()).report(None);
+        let (r, w) = OpensslStream(s).split();
+        Ok(Peer::new(r, w, hup))
+    }))
+}
+
+/// Set PEM certificate chain and private key
+fn set_pem_identity(b: &mut SslContextBuilder, cert: &[u8], key: &[u8]) -> Result<(), Box<dyn ::std::error::Error>> {
+    let mut chain = X509::stack_from_pem(cert)?.into_iter();
+    let leaf = chain.next().ok_or("No certificates in the certificate file")?;
+    b.set_certificate(&leaf)?;
+    for c in chain {
+        b.add_extra_chain_cert(c)?;
+    }
+    let key = PKey::private_key_from_pem(key)?;
+    b.set_private_key(&key)?;
+    Ok(())
+}
+
+fn set_pkcs12_identity(
+    b: &mut SslContextBuilder,
+    der: &[u8],
+    passwd: Option<&str>,
+) -> Result<(), Box<dyn ::std::error::Error>> {
+    let p = Pkcs12::from_der(der)?.parse2(passwd.unwrap_or(""))?;
+    let cert = p.cert.ok_or("No certificate in PKCS12 archive")?;
+    let key = p.pkey.ok_or("No private key in PKCS12 archive")?;
+    b.set_certificate(&cert)?;
+    b.set_private_key(&key)?;
+    for c in p.ca.into_iter().flatten() {
+        b.add_extra_chain_cert(c)?;
+    }
+    Ok(())
+}
+
+/// Settings shared by the main acceptor and `--tls-sni-cert` ones: protocols and ciphers,
+/// client certificate verification and key logging
+fn acceptor_builder(progopt: &Options) -> Result<SslAcceptorBuilder, Box<dyn ::std::error::Error>> {
+    let mut b = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    set_keylog(&mut b, progopt);
+
+    if let Some(ref ca) = progopt.tls_client_ca {
+        let cas = X509::stack_from_pem(ca)?;
+        if cas.is_empty() {
+            Err("No certificates in --tls-client-ca file")?;
+        }
+        let mut store = X509StoreBuilder::new()?;
+        for c in cas {
+            b.add_client_ca(&c)?;
+            store.add_cert(c)?;
+        }
+        b.set_verify_cert_store(store.build())?;
+        if progopt.tls_client_cert_optional {
+            b.set_verify(SslVerifyMode::PEER);
+        } else {
+            b.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+    }
+    Ok(b)
+}
+
+pub fn acceptor(progopt: &Options) -> Result<SslAcceptor, Box<dyn ::std::error::Error>> {
+    let mut b = acceptor_builder(progopt)?;
+    if let (Some(cert), Some(key)) = (&progopt.tls_cert, &progopt.tls_key) {
+        set_pem_identity(&mut b, cert, key).map_err(|e| format!("--tls-cert: {}", e))?;
+    } else {
+        let der = progopt
+            .pkcs12_der
+            .as_ref()
+            .expect("lint should have caught the missing pkcs12_der option");
+        set_pkcs12_identity(&mut b, der, progopt.pkcs12_passwd.as_deref())?;
+    }
+    b.check_private_key()?;
+
+    if !progopt.tls_sni_cert.is_empty() {
+        let mut by_name = vec![];
+        for x in &progopt.tls_sni_cert {
+            let mut c = acceptor_builder(progopt)?;
+            set_pem_identity(&mut c, &x.cert, &x.key)
+                .and_then(|()| Ok(c.check_private_key()?))
+                .map_err(|e| format!("--tls-sni-cert {}: {}", x.name, e))?;
+            by_name.push((x.name.clone(), c.build().into_context()));
+        }
+        b.set_servername_callback(move |ssl, _alert| {
+            let name = ssl.servername(NameType::HOST_NAME).map(|x| x.to_owned());
+            if let Some(name) = name {
+                if let Some((_, ctx)) = by_name.iter().find(|(p, _)| sni_matches(p, &name)) {
+                    ssl.set_ssl_context(ctx).map_err(|_| SniError::ALERT_FATAL)?;
+                }
+            }
+            Ok(())
+        });
+    }
+    Ok(b.build())
+}
+pub fn accept(
+    acceptor: &SslAcceptor,
+    stream: readwrite::ReadWriteAsync<Box<dyn AsyncRead>, Box<dyn AsyncWrite>>,
+    l2r: L2rUser,
+    hup: Option<HupToken>,
+) -> BoxedNewPeerFuture {
+    debug!("Accepting a TLS connection using OpenSSL");
+    let handshake = match MidHandshake::new(acceptor.accept(stream)) {
+        Ok(x) => x,
+        Err(e) => return peer_err2(e),
+    };
+    Box::new(handshake.and_then(move |s| {
+        info!("Accepted TLS connection");
+        session_info(s.ssl()).report(Some(&l2r));
+        if let (Some(name), L2rUser::FillIn(ref y)) = (s.ssl().servername(NameType::HOST_NAME), &l2r) {
+            debug!("  the client asked for server name {}", name);
+            y.borrow_mut().tls_server_name = Some(name.to_owned());
+        }
+        if let Some(cert) = s.ssl().peer_certificate() {
+            let subject = x509_name_to_string(cert.subject_name());
+            info!("  the client presented a valid certificate: {}", subject);
+            if let L2rUser::FillIn(ref y) = l2r {
+                let mut z = y.borrow_mut();
+                z.tls_client_subject = Some(subject);
+                z.tls_client_san = Some(subject_alt_names(&cert));
+                z.tls_client_fingerprint = cert
+                    .digest(MessageDigest::sha256())
+                    .ok()
+                    .map(|d| fingerprint_to_string(&d));
+            }
+        } else if s.ssl().verify_mode().contains(SslVerifyMode::PEER) {
+            info!("  the client did not present a certificate");
+        }
+        let (r, w) = OpensslStream(s).split();
+        ok(Peer::new(r, w, hup))
+    }))
+}
+
+fn session_info(ssl: &SslRef) -> SessionInfo {
+    let names = |c: &X509Ref| (x509_name_to_string(c.subject_name()), x509_name_to_string(c.issuer_name()));
+    let mut peer_certificates = vec![];
+    // Server side does not get client's own certificate in the chain
+    if ssl.is_server() {
+        peer_certificates.extend(ssl.peer_certificate().map(|c| names(&c)));
+    }
+    peer_certificates.extend(ssl.peer_cert_chain().into_iter().flatten().map(names));
+    SessionInfo {
+        version: Some(ssl.version_str().to_owned()),
+        cipher: ssl.current_cipher().map(|c| c.name().to_owned()),
+        alpn: ssl.selected_alpn_protocol().map(|x| String::from_utf8_lossy(x).into_owned()),
+        peer_certificates,
+    }
+}
+
+/// Like `CN=client,O=Example`
+fn x509_name_to_string(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|e| {
+            let k = e.object().nid().short_name().unwrap_or("?");
+            let v = e.data().to_string().unwrap_or_default();
+            format!("{}={}", k, v)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Like `DNS:example.com,email:a@example.com,IP:127.0.0.1`
+fn subject_alt_names(cert: &X509Ref) -> String {
+    let mut v = vec![];
+    for n in cert.subject_alt_names().into_iter().flatten() {
+        if let Some(x) = n.dnsname() {
+            v.push(format!("DNS:{}", x));
+        } else if let Some(x) = n.email() {
+            v.push(format!("email:{}", x));
+        } else if let Some(x) = n.uri() {
+            v.push(format!("URI:{}", x));
+        } else if let Some(x) = n.ipaddress() {
+            let ip = match x.len() {
+                4 => Some(IpAddr::from([x[0], x[1], x[2], x[3]])),
+                16 => {
+                    let mut a = [0u8; 16];
+                    a.copy_from_slice(x);
+                    Some(IpAddr::from(a))
+                }
+                _ => None,
+            };
+            if let Some(ip) = ip {
+                v.push(format!("IP:{}", ip));
+            }
+        }
+    }
+    v.join(",")
+}
+
+type RawStream = readwrite::ReadWriteAsync<Box<dyn AsyncRead>, Box<dyn AsyncWrite>>;
+
+/// Drives OpenSSL handshake on a non-blocking stream
+struct MidHandshake(Option<Result<SslStream<RawStream>, MidHandshakeSslStream<RawStream>>>);
+
+impl MidHandshake {
+    fn new(
+        r: Result<SslStream<RawStream>, HandshakeError<RawStream>>,
+    ) -> Result<Self, Box<dyn ::std::error::Error>> {
+        match r {
+            Ok(s) => Ok(MidHandshake(Some(Ok(s)))),
+            Err(HandshakeError::WouldBlock(m)) => Ok(MidHandshake(Some(Err(m)))),
+            Err(HandshakeError::Failure(m)) => Err(Box::new(m.into_error())),
+            Err(HandshakeError::SetupFailure(e)) => Err(Box::new(e)),
+        }
+    }
+}
+
+impl Future for MidHandshake {
+    type Item = SslStream<RawStream>;
+    type Error = Box<dyn ::std::error::Error>;
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        match self.0.take().expect("MidHandshake polled after completion") {
+            Ok(s) => Ok(Async::Ready(s)),
+            Err(m) => match m.handshake() {
+                Ok(s) => Ok(Async::Ready(s)),
+                Err(HandshakeError::WouldBlock(m)) => {
+                    self.0 = Some(Err(m));
+                    Ok(Async::NotReady)
+                }
+                Err(HandshakeError::Failure(m)) => {
+                    let verify = m.ssl().verify_result();
+                    if verify != X509VerifyResult::OK {
+                        if m.ssl().is_server() {
+                            warn!("Rejected TLS client certificate: {}", verify);
+                        } else {
+                            warn!("Rejected TLS server certificate: {}", verify);
+                        }
+                    }
+                    Err(Box::new(m.into_error()))
+                }
+                Err(HandshakeError::SetupFailure(e)) => Err(Box::new(e)),
+            },
+        }
+    }
+}
+
+struct OpensslStream(SslStream<RawStream>);
+
+impl Read for OpensslStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.0.read(buf)
+    }
+}
+impl Write for OpensslStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> IoResult<()> {
+        self.0.flush()
+    }
+}
+impl AsyncRead for OpensslStream {}
+impl AsyncWrite for OpensslStream {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        match self.0.shutdown() {
+            Ok(_) => (),
+            Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => (),
+            Err(e) => match e.into_io_error() {
+                Ok(e) if e.kind() == ::std::io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Ok(e) => return Err(e),
+                Err(e) => return Err(simple_err(format!("{}", e))),
+            },
+        }
+        self.0.get_mut().shutdown()
+    }
+}
+
@@ -1,237 +1,227 @@
-use futures::future::{ok, Future};
+use futures::future::Future;
 
 use std::rc::Rc;
 
-use super::{box_up_err, peer_err, BoxedNewPeerFuture, Peer};
-use super::{ConstructParams, L2rUser, Options, PeerConstructor, Specifier};
+use super::{box_up_err, BoxedNewPeerFuture, Peer};
+use crate::util::peer_err2;
+use super::{L2rUser, Options};
 
 pub extern crate native_tls;
 extern crate readwrite;
 extern crate tokio_tls;
 
-use self::native_tls::{Identity as Pkcs12, TlsAcceptor, TlsConnector};
+use self::native_tls::{Certificate, Identity, TlsAcceptor, TlsConnector};
 use self::tokio_tls::{TlsAcceptor as TlsAcceptorExt, TlsConnector as TlsConnectorExt};
 
-use std::ffi::{OsStr, OsString};
-
-pub fn interpret_pkcs12(x: &OsStr) -> ::std::result::Result<Vec<u8>, OsString> {
-    match (|| {
-        use std::io::Read;
-        let mut f = ::std::fs::File::open(x)?;
-        let mut v = Vec::with_capacity(2048);
-        f.read_to_end(&mut v)?;
-        Ok(v)
-    })() {
-        Err(e) => {
-            let e: Box<dyn (::std::error::Error)> = e;
-            let o: OsString = format!("{}", e).into();
-            Err(o)
-        }
-        Ok(x) => Ok(x),
+pub use super::tls_common::{interpret_pem, interpret_pem_bundle, interpret_pkcs12};
+use super::tls_common::{SelfSignedCert, SessionInfo, TlsAcceptorSlot};
+
+/// native-tls uses OpenSSL on these platforms, so using OpenSSL directly does not bring in a new dependency
+#[cfg(not(any(windows, target_os = "macos", target_os = "ios")))]
+use super::ssl_openssl;
+
+/// Features that native-tls does not support, only available with OpenSSL
+#[cfg(any(windows, target_os = "macos", target_os = "ios"))]
+const NEEDS_OPENSSL: &str = "is not supported by native TLS implementation on this platform. Build websocat with `rustls` feature to use it";
+
+/// Build identity from PEM certificate chain and a private key in any format OpenSSL understands
+#[cfg(not(any(windows, target_os = "macos", target_os = "ios")))]
+fn pem_identity(cert: &[u8], key: &[u8]) -> Result<Identity, Box<dyn ::std::error::Error>> {
+    Ok(Identity::from_pkcs8(cert, &ssl_openssl::pkcs8_key(key)?)?)
+}
+
+/// Build identity from PEM certificate chain and a PKCS#8 private key
+#[cfg(any(windows, target_os = "macos", target_os = "ios"))]
+fn pem_identity(cert: &[u8], key: &[u8]) -> Result<Identity, Box<dyn ::std::error::Error>> {
+    Identity::from_pkcs8(cert, key)
+        .map_err(|e| format!("{}. Note that private key should be in PKCS#8 format (`BEGIN PRIVATE KEY`)", e).into())
+}
+
+/// Identity for accepting TLS connections, from `--tls-cert`/`--tls-key` or `--pkcs12-der`
+pub fn server_identity(opts: &Options) -> Result<Identity, Box<dyn ::std::error::Error>> {
+    if let (Some(cert), Some(key)) = (&opts.tls_cert, &opts.tls_key) {
+        return pem_identity(cert, key);
     }
+    let der = opts
+        .pkcs12_der
+        .as_ref()
+        .expect("lint should have caught the missing pkcs12_der option");
+    let passwd = opts.pkcs12_passwd.as_deref().unwrap_or("");
+    Ok(Identity::from_pkcs12(der, passwd)?)
 }
 
-#[derive(Debug)]
-pub struct TlsConnect<T: Specifier>(pub T);
-impl<T: Specifier> Specifier for TlsConnect<T> {
-    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
-        let inner = self.0.construct(cp.clone());
-        inner.map(move |p, l2r| {
-            ssl_connect(
-                p,
-                l2r,
-                cp.program_options.tls_domain.clone(),
-                cp.program_options.tls_insecure,
-                cp.program_options.client_pkcs12_der.clone(),
-                cp.program_options.client_pkcs12_passwd.clone(),
+/// Client identity from `--client-tls-cert`/`--client-tls-key` or `--client-pkcs12-der`, if any
+pub fn client_identity(opts: &Options) -> Option<Identity> {
+    let identity = if let (Some(cert), Some(key)) = (&opts.client_tls_cert, &opts.client_tls_key) {
+        pem_identity(cert, key)
+    } else if let Some(ref der) = opts.client_pkcs12_der {
+        let passwd = opts.client_pkcs12_passwd.as_deref().unwrap_or("");
+        Identity::from_pkcs12(der, passwd).map_err(|e| e.into())
+    } else {
+        return None;
+    };
+    identity
+        .map_err(|e| {
+            error!(
+                "Unable to parse client identity: {}\nContinuing without a client identity",
+                e
             )
         })
-    }
-    specifier_boilerplate!(noglobalstate has_subspec);
-    self_0_is_subspecifier!(proxy_is_multiconnect);
+        .ok()
 }
-specifier_class!(
-    name = TlsConnectClass,
-    target = TlsConnect,
-    prefixes = ["ssl-connect:","ssl-c:","ssl:","tls:","tls-connect:","tls-c:","c-ssl:","connect-ssl:","c-tls:","connect-tls:"],
-    arg_handling = subspec,
-    overlay = true,
-    StreamOriented,
-    MulticonnectnessDependsOnInnerType,
-    help = r#"
-Overlay to add TLS encryption atop of existing connection [A]
-
-Example: manually connect to a secure websocket
-
-    websocat -t - ws-c:tls-c:tcp:174.129.224.73:1080 --ws-c-uri ws://echo.websocket.org --tls-domain echo.websocket.org
-
-For a user-friendly solution, see --socks5 command-line option
-"#
-);
-
-#[derive(Debug)]
-pub struct TlsAccept<T: Specifier>(pub T);
-impl<T: Specifier> Specifier for TlsAccept<T> {
-    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
-        let inner = self.0.construct(cp.clone());
-        inner.map(move |p, l2r| ssl_accept(p, l2r, cp.program_options.clone()))
-    }
-    specifier_boilerplate!(noglobalstate has_subspec);
-    self_0_is_subspecifier!(proxy_is_multiconnect);
+
+
+/// `--tls-self-signed`: generate a throwaway certificate for given host names and IP addresses.
+#[cfg(not(any(windows, target_os = "macos", target_os = "ios")))]
+pub fn self_signed_cert(names: &[String]) -> Result<SelfSignedCert, Box<dyn ::std::error::Error>> {
+    ssl_openssl::self_signed_cert(names)
 }
-specifier_class!(
-    name = TlsAcceptClass,
-    target = TlsAccept,
-    prefixes = [
-        "ssl-accept:",
-        "ssl-a:",
-        "tls-a:",
-        "tls-accept:",
-        "a-ssl:",
-        "accept-ssl:",
-        "accept-tls:",
-        "accept-tls:"
-    ],
-    arg_handling = subspec,
-    overlay = true,
-    StreamOriented,
-    MulticonnectnessDependsOnInnerType,
-    help = r#"
-Accept an TLS connection using arbitrary backing stream. [A]
-
-Example: The same as in TlsListenClass's example, but with manual acceptor
-
-    websocat -E -b --pkcs12-der=q.pkcs12 tls-a:tcp-l:127.0.0.1:1234 mirror:
-"#
-);
-
-specifier_alias!(
-    name = TlsListenClass,
-    prefixes = [
-        "ssl-listen:",
-        "ssl-l:",
-        "tls-l:",
-        "tls-listen:",
-        "l-ssl:",
-        "listen-ssl:",
-        "listen-tls:",
-        "listen-tls:"
-    ],
-    alias = "tls-accept:tcp-l:",
-    help = r#"
-Listen for SSL connections on a TCP port
-
-Example: Non-websocket SSL echo server
-
-    websocat -E -b --pkcs12-der=q.pkcs12 ssl-listen:127.0.0.1:1234 mirror:
-    socat - ssl:127.0.0.1:1234,verify=0
-"#
-);
-
-specifier_alias!(
-    name = WssListenClass,
-    prefixes = ["wss-listen:", "wss-l:", "l-wss:", "wss-listen:"],
-    alias = "ws-u:tls-accept:tcp-l:",
-    help = r#"
-Listen for secure WebSocket connections on a TCP port
-
-Example: wss:// echo server + client for testing
-
-    websocat -E -t --pkcs12-der=q.pkcs12 wss-listen:127.0.0.1:1234 mirror:
-    websocat --ws-c-uri=wss://localhost/ -t - ws-c:cmd:'socat - ssl:127.0.0.1:1234,verify=0'
-
-See [moreexamples.md](./moreexamples.md) for info about generation of `q.pkcs12`.
-"#
-);
 
-use tokio_io::AsyncRead;
+#[cfg(any(windows, target_os = "macos", target_os = "ios"))]
+pub fn self_signed_cert(_names: &[String]) -> Result<SelfSignedCert, Box<dyn ::std::error::Error>> {
+    Err(format!("--tls-self-signed {}", NEEDS_OPENSSL))?
+}
 
-pub fn ssl_connect(
-    inner_peer: Peer,
-    _l2r: L2rUser,
-    dom: Option<String>,
-    tls_insecure: bool,
-    client_identity : Option<Vec<u8>>,
-    client_identity_password : Option<String>,
-) -> BoxedNewPeerFuture {
-    let hup = inner_peer.2;
-    let squashed_peer = readwrite::ReadWriteAsync::new(inner_peer.0, inner_peer.1);
+use tokio_io::AsyncRead;
 
-    fn gettlsc(nohost: bool, noverify: bool, client_identity : Option<Vec<u8>>, client_identity_password : Option<String>) -> native_tls::Result<TlsConnectorExt> {
-        let mut b = TlsConnector::builder();
-        if nohost {
-            b.danger_accept_invalid_hostnames(true);
-        }
-        if noverify {
-            b.danger_accept_invalid_hostnames(true);
-            b.danger_accept_invalid_certs(true);
+fn gettlsc(nohost: bool, progopt: &Options) -> Result<TlsConnectorExt, Box<dyn ::std::error::Error>> {
+    let mut b = TlsConnector::builder();
+    if nohost {
+        b.danger_accept_invalid_hostnames(true);
+    }
+    if progopt.tls_insecure || !progopt.tls_pin.is_empty() {
+        // With pins the certificate is checked after the handshake instead
+        b.danger_accept_invalid_hostnames(true);
+        b.danger_accept_invalid_certs(true);
+    }
+    for pem in &progopt.tls_ca {
+        for c in pem_blocks(pem, "CERTIFICATE") {
+            b.add_root_certificate(Certificate::from_pem(c)?);
         }
-        
-        if let Some(client_ident) = client_identity {
-            let identity = super::ssl_peer::native_tls::Identity::from_pkcs12(
-                &client_ident,
-                &client_identity_password.unwrap_or("".to_string()),
-            )
-            .map_err(|e| {
-                error!(
-                    "Unable to parse client identity: {}\nContinuing without a client identity",
-                    e
-                )
-            })
-            .ok();
-            if let Some(x) = identity {
-                b.identity(x);
+    }
+    b.disable_built_in_roots(progopt.tls_no_system_ca);
+
+    if let Some(x) = client_identity(progopt) {
+        b.identity(x);
+    }
+
+    let tlsc: TlsConnector = b.build()?;
+    Ok(TlsConnectorExt::from(tlsc))
+}
+
+/// Split PEM bundle into separate `-----BEGIN {label}-----` ... `-----END {label}-----` blocks
+fn pem_blocks<'a>(pem: &'a [u8], label: &str) -> Vec<&'a [u8]> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let find = |hay: &[u8], needle: &str| hay.windows(needle.len()).position(|w| w == needle.as_bytes());
+    let mut ret = vec![];
+    let mut rest = pem;
+    while let Some(b) = find(rest, &begin) {
+        rest = &rest[b..];
+        match find(rest, &end) {
+            Some(e) => {
+                ret.push(&rest[..e + end.len()]);
+                rest = &rest[e + end.len()..];
             }
+            None => break,
         }
+    }
+    ret
+}
+
+/// native-tls cannot log TLS secrets, so OpenSSL is used directly for that
+fn use_openssl(progopt: &Options) -> bool {
+    progopt.tls_keylog.is_some()
+}
+
+#[cfg(not(any(windows, target_os = "macos", target_os = "ios")))]
+fn check_pins(pins: &[super::options::TlsPin], cert: Option<&[u8]>) -> Result<(), Box<dyn ::std::error::Error>> {
+    ssl_openssl::check_pins_der(pins, cert)
+}
 
-        let tlsc: TlsConnector = b.build()?;
-        Ok(TlsConnectorExt::from(tlsc))
+#[cfg(any(windows, target_os = "macos", target_os = "ios"))]
+fn check_pins(_pins: &[super::options::TlsPin], _cert: Option<&[u8]>) -> Result<(), Box<dyn ::std::error::Error>> {
+    Err(format!("--tls-pin {}", NEEDS_OPENSSL))?
+}
+
+pub fn ssl_connect(inner_peer: Peer, dom: Option<String>, progopt: &Options) -> BoxedNewPeerFuture {
+    if use_openssl(progopt) {
+        #[cfg(not(any(windows, target_os = "macos", target_os = "ios")))]
+        return ssl_openssl::connect(inner_peer, dom, progopt);
+        #[cfg(any(windows, target_os = "macos", target_os = "ios"))]
+        return peer_err2(format!("--tls-keylog {}", NEEDS_OPENSSL).into());
     }
+    let hup = inner_peer.2;
+    let squashed_peer = readwrite::ReadWriteAsync::new(inner_peer.0, inner_peer.1);
 
-    let tls = match gettlsc(dom.is_none(), tls_insecure, client_identity, client_identity_password) {
+    let tls = match gettlsc(dom.is_none(), progopt) {
         Ok(x) => x,
-        Err(e) => return peer_err(e),
+        Err(e) => return peer_err2(e),
     };
+    let pins = progopt.tls_pin.clone();
 
     info!("Connecting to TLS");
-    if let Some(dom) = dom {
-        Box::new(
-            tls.connect(dom.as_str(), squashed_peer)
-                .map_err(box_up_err)
-                .and_then(move |tls_stream| {
+    let verified = dom.is_some() || !pins.is_empty();
+    let dom = dom.unwrap_or_else(|| "domainverificationdisabled".to_owned());
+    Box::new(
+        tls.connect(dom.as_str(), squashed_peer)
+            .map_err(box_up_err)
+            .and_then(move |tls_stream| {
+                let cert = match tls_stream.get_ref().peer_certificate()? {
+                    Some(c) => Some(c.to_der()?),
+                    None => None,
+                };
+                if !pins.is_empty() {
+                    check_pins(&pins, cert.as_deref())?;
+                    info!("Connected to TLS, server certificate matches the pin");
+                } else if verified {
                     info!("Connected to TLS");
-                    let (r, w) = tls_stream.split();
-                    ok(Peer::new(r, w, hup))
-                }),
-        )
-    } else {
-        Box::new(tls.connect("domainverificationdisabled", squashed_peer).map_err(box_up_err).and_then(move |tls_stream| {
-            warn!("Connected to TLS without proper verification of certificate. Use --tls-domain option.");
-            let (r,w) = tls_stream.split();
-            ok(Peer::new(r,w, hup))
-        }))
+                } else {
+                    warn!("Connected to TLS without proper verification of certificate. Use --tls-domain option.");
+                }
+                native_session_info(cert).report(None);
+                let (r, w) = tls_stream.split();
+                Ok(Peer::new(r, w, hup))
+            }),
+    )
+}
+
+/// TLS acceptor: OpenSSL one when client certificates need to be verified, certificates need to be selected by SNI
+/// or TLS secrets need to be logged, as native-tls does not support that. native-tls one otherwise.
+#[derive(Clone)]
+pub enum Acceptor {
+    Native(TlsAcceptorExt),
+    #[cfg(not(any(windows, target_os = "macos", target_os = "ios")))]
+    Openssl(ssl_openssl::SslAcceptor),
+}
+
+pub use self::gettlsa as build_acceptor;
+
+pub fn gettlsa(progopt: &Options) -> Result<Acceptor, Box<dyn ::std::error::Error>> {
+    if use_openssl(progopt) || progopt.tls_client_ca.is_some() || !progopt.tls_sni_cert.is_empty() {
+        #[cfg(not(any(windows, target_os = "macos", target_os = "ios")))]
+        return Ok(Acceptor::Openssl(ssl_openssl::acceptor(progopt)?));
+        #[cfg(any(windows, target_os = "macos", target_os = "ios"))]
+        Err(format!("--tls-client-ca, --tls-sni-cert or --tls-keylog {}", NEEDS_OPENSSL))?;
     }
+    let identity = server_identity(progopt)?;
+    Ok(Acceptor::Native(TlsAcceptorExt::from(TlsAcceptor::builder(identity).build()?)))
 }
 
-pub fn ssl_accept(inner_peer: Peer, _l2r: L2rUser, progopt: Rc<Options>) -> BoxedNewPeerFuture {
+pub fn ssl_accept(
+    inner_peer: Peer,
+    l2r: L2rUser,
+    progopt: Rc<Options>,
+    slot: &TlsAcceptorSlot<Acceptor>,
+) -> BoxedNewPeerFuture {
     let hup = inner_peer.2;
     let squashed_peer = readwrite::ReadWriteAsync::new(inner_peer.0, inner_peer.1);
 
-    fn gettlsa(cert: &[u8], passwd: &str) -> native_tls::Result<TlsAcceptorExt> {
-        let pkcs12 = Pkcs12::from_pkcs12(cert, passwd)?;
-        Ok(TlsAcceptorExt::from(TlsAcceptor::builder(pkcs12).build()?))
-    }
-
-    let der = progopt
-        .pkcs12_der
-        .as_ref()
-        .expect("lint should have caught the missing pkcs12_der option");
-    let passwd = progopt
-        .pkcs12_passwd.as_deref()
-        .unwrap_or("");
-    let tls = match gettlsa(der, passwd) {
-        Ok(x) => x,
-        Err(e) => return peer_err(e),
+    let tls = match slot.get(&progopt, gettlsa) {
+        Ok(Acceptor::Native(x)) => x,
+        #[cfg(not(any(windows, target_os = "macos", target_os = "ios")))]
+        Ok(Acceptor::Openssl(x)) => return ssl_openssl::accept(&x, squashed_peer, l2r, hup),
+        Err(e) => return peer_err2(e),
     };
 
     debug!("Accepting a TLS connection");
@@ -240,20 +230,29 @@ pub fn ssl_accept(inner_peer: Peer, _l2r: L2rUser, progopt: Rc<Options>) -> Boxe
             .map_err(box_up_err)
             .and_then(move |tls_stream| {
                 info!("Accepted TLS connection");
-                match tls_stream.get_ref().peer_certificate() {
-                    Ok(Some(_cert)) => {
-                        // Does not actually work with native-tls
-                        info!("  the client presented an identity certificate.");
-                    }
-                    Ok(None) => {
-                        debug!("  no identity certificate from the client. But Websocat may have failed to request it.");
-                    }
-                    Err(e) => {
-                        warn!("Error getting identity certificate from client: {}", e);
-                    }
-                }
+                let cert = match tls_stream.get_ref().peer_certificate()? {
+                    Some(c) => Some(c.to_der()?),
+                    None => None,
+                };
+                native_session_info(cert).report(Some(&l2r));
                 let (r, w) = tls_stream.split();
-                ok(Peer::new(r, w, hup))
+                Ok(Peer::new(r, w, hup))
             }),
     )
 }
+
+/// native-tls does not tell protocol version, cipher and ALPN, only the peer certificate
+fn native_session_info(cert: Option<Vec<u8>>) -> SessionInfo {
+    debug!("  TLS version and cipher are not exposed by native-tls");
+    #[cfg(not(any(windows, target_os = "macos", target_os = "ios")))]
+    let peer_certificates = cert.and_then(|c| ssl_openssl::certificate_names(&c).ok()).into_iter().collect();
+    #[cfg(any(windows, target_os = "macos", target_os = "ios"))]
+    let peer_certificates = {
+        let _ = cert;
+        vec![]
+    };
+    SessionInfo {
+        peer_certificates,
+        ..Default::default()
+    }
+}
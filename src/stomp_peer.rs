@@ -0,0 +1,506 @@
+use futures::future::{err, loop_fn, ok, Future, Loop};
+use futures::Async;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::{box_up_err, simple_err, simple_err2, wouldblock, BoxedNewPeerFuture, Peer};
+use super::util::QueuedWriter;
+use super::{ConstructParams, Options, PeerConstructor, Specifier};
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+#[derive(Debug)]
+pub struct Stomp<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for Stomp<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p, _| stomp_peer(p, cp.program_options.clone()))
+    }
+    specifier_boilerplate!(noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = StompClass,
+    target = Stomp,
+    prefixes = ["stomp:"],
+    arg_handling = subspec,
+    overlay = true,
+    MessageOriented,
+    MulticonnectnessDependsOnInnerType,
+    help = r#"
+[A] Speak STOMP 1.2 client protocol over inner connection (typically WebSocket).
+
+Sends CONNECT frame (see `--stomp-login`, `--stomp-passcode`, `--stomp-host`
+and `--stomp-heartbeat` options), waits for CONNECTED and maintains heart-beats.
+Incoming frames (e.g. MESSAGE) are returned as command, headers, empty line and body,
+without the terminating NUL. Outgoing messages are simple commands:
+
+    SUBSCRIBE <destination> [id]
+    UNSUBSCRIBE <id>
+    SEND <destination> <body>
+    ACK <id> / NACK <id>
+    BEGIN <tx> / COMMIT <tx> / ABORT <tx>
+
+Subscription ids default to `sub-0`, `sub-1` and so on. A message containing newlines is
+instead sent as a complete frame, with terminating NUL appended if missing.
+Frames are delimited by NUL (or `content-length`) themselves, so stream-oriented
+inner peers like `tcp:` work too.
+
+When input is finished, DISCONNECT is sent and the overlay exits after the server acknowledges it.
+
+In `-t` mode newlines inside incoming frames are replaced by spaces. Use `-0` (`--null-terminated`)
+to keep frames intact, with commands on input also separated by NUL bytes instead of newlines.
+
+Example:
+
+    websocat -t - stomp:ws://127.0.0.1:8080/ws
+
+    > SUBSCRIBE /topic/greetings
+    > SEND /app/hello {"name":"websocat"}
+    < MESSAGE
+    < destination:/topic/greetings
+    < ...
+"#
+);
+
+const DISCONNECT_RECEIPT: &str = "websocat-disconnect";
+
+#[derive(Debug, PartialEq)]
+struct StompFrame {
+    command: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+fn escape_header(x: &str) -> String {
+    let mut s = String::with_capacity(x.len());
+    for c in x.chars() {
+        match c {
+            '\\' => s.push_str("\\\\"),
+            '\r' => s.push_str("\\r"),
+            '\n' => s.push_str("\\n"),
+            ':' => s.push_str("\\c"),
+            c => s.push(c),
+        }
+    }
+    s
+}
+
+fn unescape_header(x: &str) -> String {
+    let mut s = String::with_capacity(x.len());
+    let mut it = x.chars();
+    while let Some(c) = it.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match it.next() {
+            Some('r') => s.push('\r'),
+            Some('n') => s.push('\n'),
+            Some('c') => s.push(':'),
+            Some(c) => s.push(c),
+            None => (),
+        }
+    }
+    s
+}
+
+impl StompFrame {
+    fn new(command: &str) -> StompFrame {
+        StompFrame {
+            command: command.to_owned(),
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    fn header(mut self, k: &str, v: &str) -> StompFrame {
+        self.headers.push((k.to_owned(), v.to_owned()));
+        self
+    }
+
+    /// Value of the first occurrence of the header, as specified by STOMP
+    fn get(&self, k: &str) -> Option<&str> {
+        self.headers.iter().find(|(hk, _)| hk == k).map(|(_, v)| &v[..])
+    }
+
+    /// CONNECT and CONNECTED frames do not use header escaping
+    fn escaped(&self) -> bool {
+        self.command != "CONNECT" && self.command != "CONNECTED"
+    }
+
+    /// Frame as shown to user: without the terminating NUL
+    fn render(&self) -> Vec<u8> {
+        let mut s = String::new();
+        s.push_str(&self.command);
+        s.push('\n');
+        for (k, v) in &self.headers {
+            if self.escaped() {
+                s.push_str(&format!("{}:{}\n", escape_header(k), escape_header(v)));
+            } else {
+                s.push_str(&format!("{}:{}\n", k, v));
+            }
+        }
+        s.push('\n');
+        let mut v = s.into_bytes();
+        v.extend_from_slice(&self.body);
+        v
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut v = self.render();
+        v.push(0);
+        v
+    }
+}
+
+/// Extract one complete frame from the beginning of `buf`, skipping heart-beat EOLs.
+/// Returns `None` if more data is needed.
+fn take_frame(buf: &mut Vec<u8>) -> Result<Option<StompFrame>, Box<dyn std::error::Error>> {
+    let eols = buf.iter().take_while(|&&c| c == b'\n' || c == b'\r').count();
+    buf.drain(..eols);
+    if buf.is_empty() {
+        return Ok(None);
+    }
+
+    let mut lines = vec![];
+    let mut pos = 0;
+    loop {
+        let nl = match buf[pos..].iter().position(|&c| c == b'\n') {
+            Some(x) => pos + x,
+            None => return Ok(None),
+        };
+        let mut line = &buf[pos..nl];
+        if line.ends_with(b"\r") {
+            line = &line[..line.len() - 1];
+        }
+        pos = nl + 1;
+        if line.is_empty() {
+            break;
+        }
+        lines.push(std::str::from_utf8(line)?.to_owned());
+    }
+
+    let mut f = StompFrame::new(&lines[0]);
+    for l in &lines[1..] {
+        let colon = l.find(':').ok_or("STOMP header without a colon")?;
+        let (k, v) = (&l[..colon], &l[colon + 1..]);
+        if f.escaped() {
+            f.headers.push((unescape_header(k), unescape_header(v)));
+        } else {
+            f.headers.push((k.to_owned(), v.to_owned()));
+        }
+    }
+
+    let body_end = match f.get("content-length") {
+        Some(cl) => {
+            let end = pos + cl.trim().parse::<usize>()?;
+            if buf.len() <= end {
+                return Ok(None);
+            }
+            if buf[end] != 0 {
+                Err("STOMP frame body is not followed by NUL")?;
+            }
+            end
+        }
+        None => match buf[pos..].iter().position(|&c| c == 0) {
+            Some(x) => pos + x,
+            None => return Ok(None),
+        },
+    };
+    f.body = buf[pos..body_end].to_vec();
+    buf.drain(..=body_end);
+    Ok(Some(f))
+}
+
+/// Interpret user's message as a frame to send
+fn parse_command(msg: &str, next_sub: &mut u64) -> Result<StompFrame, Box<dyn std::error::Error>> {
+    let msg = msg.trim_end_matches(&['\n', '\r', '\0'][..]).trim_start();
+    if msg.contains('\n') {
+        let mut v = msg.as_bytes().to_vec();
+        v.push(0);
+        return Ok(take_frame(&mut v)?.ok_or("Incomplete STOMP frame")?);
+    }
+
+    let (cmd, rest) = match msg.find(char::is_whitespace) {
+        Some(i) => (&msg[..i], msg[i..].trim_start()),
+        None => (msg, ""),
+    };
+    let cmd = cmd.to_ascii_uppercase();
+    let mut words = rest.split_whitespace();
+    let mut arg = |what: &'static str| words.next().ok_or(what);
+    let f = match &cmd[..] {
+        "SUBSCRIBE" => {
+            let dest = arg("SUBSCRIBE needs a destination")?;
+            let id = match arg("") {
+                Ok(x) => x.to_owned(),
+                Err(_) => {
+                    *next_sub += 1;
+                    format!("sub-{}", *next_sub - 1)
+                }
+            };
+            info!("Subscribing to {} with id {}", dest, id);
+            StompFrame::new("SUBSCRIBE")
+                .header("destination", dest)
+                .header("id", &id)
+                .header("ack", "auto")
+        }
+        "UNSUBSCRIBE" => StompFrame::new("UNSUBSCRIBE").header("id", arg("UNSUBSCRIBE needs an id")?),
+        "SEND" => {
+            let dest = arg("SEND needs a destination")?;
+            let body = rest[dest.len()..].trim_start();
+            let mut f = StompFrame::new("SEND")
+                .header("destination", dest)
+                .header("content-length", &format!("{}", body.len()));
+            f.body = body.as_bytes().to_vec();
+            f
+        }
+        "ACK" | "NACK" => StompFrame::new(&cmd).header("id", arg("ACK/NACK needs an id")?),
+        "BEGIN" | "COMMIT" | "ABORT" => {
+            StompFrame::new(&cmd).header("transaction", arg("Transaction name is required")?)
+        }
+        _ => Err(format!("Unknown STOMP command {:?}", cmd))?,
+    };
+    Ok(f)
+}
+
+fn parse_heartbeat(x: &str) -> Result<(u64, u64), Box<dyn std::error::Error>> {
+    let mut it = x.split(',').map(|x| x.trim().parse::<u64>());
+    match (it.next(), it.next(), it.next()) {
+        (Some(a), Some(b), None) => Ok((a?, b?)),
+        _ => Err("STOMP heart-beat should be two comma-separated numbers")?,
+    }
+}
+
+/// Heart-beat period from our and peer's settings; zero on either side means no heart-beating
+fn heartbeat_period(ours: u64, theirs: u64) -> Option<Duration> {
+    if ours == 0 || theirs == 0 {
+        None
+    } else {
+        Some(Duration::from_millis(ours.max(theirs)))
+    }
+}
+
+pub fn stomp_peer(inner_peer: Peer, opts: Rc<Options>) -> BoxedNewPeerFuture {
+    let (r, w, hup) = (inner_peer.0, inner_peer.1, inner_peer.2);
+    let (cx, cy) = match parse_heartbeat(&opts.stomp_heartbeat) {
+        Ok(x) => x,
+        Err(e) => return Box::new(err(e)) as BoxedNewPeerFuture,
+    };
+    let mut connect = StompFrame::new("CONNECT")
+        .header("accept-version", "1.2,1.1,1.0")
+        .header("host", opts.stomp_host.as_ref().map(|x| &x[..]).unwrap_or("/"))
+        .header("heart-beat", &format!("{},{}", cx, cy));
+    if let Some(ref x) = opts.stomp_login {
+        connect = connect.header("login", x);
+    }
+    if let Some(ref x) = opts.stomp_passcode {
+        connect = connect.header("passcode", x);
+    }
+    let bufsz = opts.buffer_size;
+
+    info!("Sending STOMP CONNECT frame");
+    let f = tokio_io::io::write_all(w, connect.encode())
+        .and_then(|(w, _)| tokio_io::io::flush(w))
+        .map_err(box_up_err)
+        .and_then(move |w| {
+            loop_fn((r, vec![0; bufsz], vec![]), |(r, buf, mut pending)| {
+                match take_frame(&mut pending) {
+                    Ok(Some(f)) => return Box::new(ok(Loop::Break((r, buf, pending, f))))
+                        as Box<dyn Future<Item = _, Error = _>>,
+                    Ok(None) => (),
+                    Err(e) => return Box::new(err(e)),
+                }
+                Box::new(tokio_io::io::read(r, buf).map_err(box_up_err).and_then(|(r, buf, n)| {
+                    if n == 0 {
+                        return Err(simple_err2("Connection closed before STOMP CONNECTED frame"));
+                    }
+                    pending.extend_from_slice(&buf[..n]);
+                    Ok(Loop::Continue((r, buf, pending)))
+                }))
+            })
+            .and_then(move |(r, buf, pending, f): (Box<dyn AsyncRead>, Vec<u8>, Vec<u8>, StompFrame)| {
+                match &f.command[..] {
+                    "CONNECTED" => (),
+                    "ERROR" => {
+                        let e = format!(
+                            "STOMP connection refused: {} {}",
+                            f.get("message").unwrap_or(""),
+                            String::from_utf8_lossy(&f.body)
+                        );
+                        return Err(box_up_err(simple_err(e)));
+                    }
+                    x => return Err(box_up_err(simple_err(format!("Expected STOMP CONNECTED frame, got {}", x)))),
+                }
+                info!("STOMP connected, version {}", f.get("version").unwrap_or("1.0"));
+                let (sx, sy) = parse_heartbeat(f.get("heart-beat").unwrap_or("0,0"))?;
+                let send_period = heartbeat_period(cx, sy);
+                // Be lenient about server's heart-beats: allow to miss one
+                let recv_period = heartbeat_period(cy, sx).map(|x| x * 2);
+                debug!("STOMP heart-beats: sending every {:?}, expecting within {:?}", send_period, recv_period);
+                let shared = Rc::new(RefCell::new(QueuedWriter::new(w)));
+                let timer = |p: Option<Duration>| p.map(|p| (p, tokio_timer::Delay::new(Instant::now() + p)));
+                let rd = StompReader {
+                    r,
+                    shared: shared.clone(),
+                    buf,
+                    pending,
+                    send_heartbeat: timer(send_period),
+                    recv_heartbeat: timer(recv_period),
+                };
+                let wr = StompWriter {
+                    shared,
+                    next_sub: 0,
+                    disconnect_sent: false,
+                };
+                Ok(Peer::new(rd, wr, hup))
+            })
+        });
+    Box::new(f) as BoxedNewPeerFuture
+}
+
+struct StompReader {
+    r: Box<dyn AsyncRead>,
+    shared: Rc<RefCell<QueuedWriter>>,
+    buf: Vec<u8>,
+    /// Received bytes that do not form a complete frame yet
+    pending: Vec<u8>,
+    send_heartbeat: Option<(Duration, tokio_timer::Delay)>,
+    recv_heartbeat: Option<(Duration, tokio_timer::Delay)>,
+}
+
+impl Read for StompReader {
+    fn read(&mut self, b: &mut [u8]) -> Result<usize, IoError> {
+        loop {
+            self.shared.borrow_mut().flush_ignoring_wouldblock()?;
+            let f = match take_frame(&mut self.pending) {
+                Ok(x) => x,
+                Err(e) => return Err(simple_err(format!("Malformed STOMP frame: {}", e))),
+            };
+            if let Some(f) = f {
+                match &f.command[..] {
+                    "RECEIPT" if f.get("receipt-id") == Some(DISCONNECT_RECEIPT) => {
+                        info!("STOMP disconnected");
+                        return Ok(0);
+                    }
+                    "ERROR" => warn!("STOMP error: {}", f.get("message").unwrap_or("")),
+                    _ => (),
+                }
+                let m = f.render();
+                if m.len() > b.len() {
+                    warn!("STOMP frame is too big for the buffer, truncating it");
+                }
+                let l = m.len().min(b.len());
+                b[..l].copy_from_slice(&m[..l]);
+                return Ok(l);
+            }
+            if let Some((period, ref mut delay)) = self.send_heartbeat {
+                match delay.poll() {
+                    Ok(Async::Ready(())) => {
+                        trace!("Sending STOMP heart-beat");
+                        delay.reset(Instant::now() + period);
+                        self.shared.borrow_mut().queue.push_back(b"\n".to_vec());
+                        continue;
+                    }
+                    Ok(Async::NotReady) => (),
+                    Err(e) => error!("tokio-timer's Delay: {}", e),
+                }
+            }
+            if let Some((_, ref mut delay)) = self.recv_heartbeat {
+                match delay.poll() {
+                    Ok(Async::Ready(())) => {
+                        return Err(simple_err("STOMP server heart-beat timeout".to_owned()));
+                    }
+                    Ok(Async::NotReady) => (),
+                    Err(e) => error!("tokio-timer's Delay: {}", e),
+                }
+            }
+            let n = match self.r.read(&mut self.buf[..]) {
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return wouldblock(),
+                x => x?,
+            };
+            if n == 0 {
+                return Ok(0);
+            }
+            if let Some((period, ref mut delay)) = self.recv_heartbeat {
+                delay.reset(Instant::now() + period);
+            }
+            self.pending.extend_from_slice(&self.buf[..n]);
+        }
+    }
+}
+impl AsyncRead for StompReader {}
+
+struct StompWriter {
+    shared: Rc<RefCell<QueuedWriter>>,
+    next_sub: u64,
+    disconnect_sent: bool,
+}
+
+impl Write for StompWriter {
+    fn write(&mut self, b: &[u8]) -> Result<usize, IoError> {
+        let mut w = self.shared.borrow_mut();
+        if !w.queue.is_empty() {
+            w.flush_queue()?;
+        }
+        let msg = String::from_utf8_lossy(b);
+        if msg.trim_matches(|c: char| c.is_whitespace() || c == '\0').is_empty() {
+            return Ok(b.len());
+        }
+        let f = match parse_command(&msg, &mut self.next_sub) {
+            Ok(f) => f,
+            Err(e) => {
+                warn!("Failed to interpret message as STOMP command: {}", e);
+                return Ok(b.len());
+            }
+        };
+        w.queue.push_back(f.encode());
+        w.flush_ignoring_wouldblock()?;
+        Ok(b.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.shared.borrow_mut().flush_queue()
+    }
+}
+impl AsyncWrite for StompWriter {
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        let mut w = self.shared.borrow_mut();
+        if !self.disconnect_sent {
+            self.disconnect_sent = true;
+            let f = StompFrame::new("DISCONNECT").header("receipt", DISCONNECT_RECEIPT);
+            w.queue.push_back(f.encode());
+        }
+        match w.flush_queue() {
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            x => x?,
+        }
+        // Underlying connection gets closed when reading finishes, after server's RECEIPT
+        Ok(Async::Ready(()))
+    }
+}
+
+#[test]
+fn test_stomp_frames() {
+    let mut buf = b"\n\r\nMESSAGE\r\ndestination:/topic/a\\cb\ncontent-length:3\n\na\0b\0\nERR".to_vec();
+    let f = take_frame(&mut buf).unwrap().unwrap();
+    assert_eq!(f.command, "MESSAGE");
+    assert_eq!(f.get("destination"), Some("/topic/a:b"));
+    assert_eq!(f.body, b"a\0b");
+    assert_eq!(f.render(), b"MESSAGE\ndestination:/topic/a\\cb\ncontent-length:3\n\na\0b".to_vec());
+    assert_eq!(take_frame(&mut buf).unwrap(), None);
+    assert_eq!(buf, b"ERR");
+
+    let mut n = 0;
+    let f = parse_command("SUBSCRIBE /topic/x\n", &mut n).unwrap();
+    assert_eq!(f.encode(), b"SUBSCRIBE\ndestination:/topic/x\nid:sub-0\nack:auto\n\n\0".to_vec());
+    assert_eq!(n, 1);
+    let f = parse_command("send /queue/y hello  world", &mut n).unwrap();
+    assert_eq!(f.encode(), b"SEND\ndestination:/queue/y\ncontent-length:12\n\nhello  world\0".to_vec());
+    let f = parse_command("SEND\ndestination:/q\n\nbody", &mut n).unwrap();
+    assert_eq!(f.get("destination"), Some("/q"));
+    assert_eq!(f.body, b"body");
+    assert!(parse_command("FOO bar", &mut n).is_err());
+}
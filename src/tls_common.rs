@@ -0,0 +1,309 @@
+//! Parts of TLS support shared between native-tls (`ssl` feature) and rustls (`rustls` feature) implementations
+
+use std::cell::RefCell;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use super::options::TlsPin;
+use super::{L2rUser, Options};
+
+pub fn interpret_pkcs12(x: &OsStr) -> ::std::result::Result<Vec<u8>, OsString> {
+    match (|| {
+        use std::io::Read;
+        let mut f = ::std::fs::File::open(x)?;
+        let mut v = Vec::with_capacity(2048);
+        f.read_to_end(&mut v)?;
+        Ok(v)
+    })() {
+        Err(e) => {
+            let e: Box<dyn ::std::error::Error> = e;
+            let o: OsString = format!("{}", e).into();
+            Err(o)
+        }
+        Ok(x) => Ok(x),
+    }
+}
+
+pub fn interpret_pem(x: &OsStr) -> ::std::result::Result<Vec<u8>, OsString> {
+    let v = interpret_pkcs12(x)?;
+    if !v.windows(11).any(|w| w == b"-----BEGIN ") {
+        return Err(format!("{} does not look like a PEM file", x.to_string_lossy()).into());
+    }
+    Ok(v)
+}
+
+/// Contents of a PEM file, or concatenated contents of all PEM files in a directory
+pub fn interpret_pem_bundle(x: &OsStr) -> ::std::result::Result<Vec<u8>, OsString> {
+    if !::std::path::Path::new(x).is_dir() {
+        return interpret_pem(x);
+    }
+    let mut v = vec![];
+    let entries = ::std::fs::read_dir(x).map_err(|e| OsString::from(format!("{}", e)))?;
+    for e in entries {
+        let e = e.map_err(|e| OsString::from(format!("{}", e)))?;
+        if e.path().is_dir() {
+            continue;
+        }
+        if let Ok(mut pem) = interpret_pem(e.path().as_os_str()) {
+            v.append(&mut pem);
+            v.push(b'\n');
+        }
+    }
+    if v.is_empty() {
+        return Err(format!("No PEM files in {}", x.to_string_lossy()).into());
+    }
+    Ok(v)
+}
+
+/// Check server certificate against `--tls-pin`s
+pub fn pin_matches(pins: &[TlsPin], cert_sha256: &[u8], spki_sha256: &[u8]) -> bool {
+    pins.iter()
+        .any(|p| p.sha256 == if p.spki { spki_sha256 } else { cert_sha256 })
+}
+
+/// Result of `--tls-self-signed` certificate generation
+pub struct SelfSignedCert {
+    pub cert_pem: Vec<u8>,
+    pub key_pem: Vec<u8>,
+    pub sha256: Vec<u8>,
+}
+
+/// Colon-separated uppercase hex, like `openssl x509 -fingerprint` prints
+pub fn fingerprint_to_string(sha256: &[u8]) -> String {
+    sha256.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":")
+}
+
+/// `--tls-keylog` or `SSLKEYLOGFILE`: file to append TLS secrets to in NSS key log format,
+/// so that traffic can be decrypted by Wireshark
+#[derive(Debug)]
+pub struct TlsKeyLog(Mutex<File>);
+
+impl TlsKeyLog {
+    pub fn open(path: &Path) -> ::std::io::Result<TlsKeyLog> {
+        let f = ::std::fs::OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(TlsKeyLog(Mutex::new(f)))
+    }
+
+    /// Append a ready-made line, like OpenSSL provides
+    pub fn write_line(&self, line: &str) {
+        let mut f = self.0.lock().unwrap();
+        if let Err(e) = writeln!(f, "{}", line) {
+            error!("Failed to write TLS key log: {}", e);
+        }
+    }
+
+    /// Append a line from parts, like rustls provides
+    pub fn write_secret(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let hex = |x: &[u8]| x.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        self.write_line(&format!("{} {} {}", label, hex(client_random), hex(secret)));
+    }
+}
+
+/// Negotiated parameters of established TLS connection
+#[derive(Default)]
+pub struct SessionInfo {
+    pub version: Option<String>,
+    pub cipher: Option<String>,
+    pub alpn: Option<String>,
+    /// Subject and issuer of each certificate the peer presented, starting from its own one
+    pub peer_certificates: Vec<(String, String)>,
+}
+
+impl SessionInfo {
+    /// Log the parameters and, on server side, remember them for `--set-environment`
+    pub fn report(self, l2r: Option<&L2rUser>) {
+        let unknown = || "unknown".to_owned();
+        info!(
+            "  TLS version: {}, cipher: {}, ALPN: {}",
+            self.version.clone().unwrap_or_else(unknown),
+            self.cipher.clone().unwrap_or_else(unknown),
+            self.alpn.clone().unwrap_or_else(|| "none".to_owned()),
+        );
+        for (i, (subject, issuer)) in self.peer_certificates.iter().enumerate() {
+            info!("  peer certificate #{}: {}, issued by {}", i, subject, issuer);
+        }
+        if let Some(L2rUser::FillIn(y)) = l2r {
+            let mut z = y.borrow_mut();
+            z.tls_version = self.version;
+            z.tls_cipher = self.cipher;
+            z.tls_alpn = self.alpn;
+        }
+    }
+}
+
+/// Whether `--tls-sni-cert` name (maybe a wildcard like `*.example.com`) is suitable for server name requested by client
+pub fn sni_matches(pattern: &str, name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let pattern = pattern.to_ascii_lowercase();
+    if let Some(suffix) = pattern.strip_prefix("*.") {
+        match name.find('.') {
+            Some(dot) => dot > 0 && name[dot + 1..] == *suffix,
+            None => false,
+        }
+    } else {
+        pattern == name
+    }
+}
+
+/// Modification times of files that server identity was loaded from
+fn identity_mtimes(progopt: &Options) -> Vec<Option<SystemTime>> {
+    [
+        &progopt.pkcs12_der_file,
+        &progopt.tls_cert_file,
+        &progopt.tls_key_file,
+        &progopt.tls_client_ca_file,
+    ]
+    .iter()
+    .filter_map(|x| x.as_ref())
+    .chain(progopt.tls_sni_cert.iter().flat_map(|x| vec![&x.cert_file, &x.key_file]))
+    .map(|x| ::std::fs::metadata(x).and_then(|m| m.modified()).ok())
+    .collect()
+}
+
+pub type AcceptorBuilder<A> = fn(&Options) -> Result<A, Box<dyn ::std::error::Error>>;
+
+struct TlsAcceptorSlotImpl<A> {
+    acceptor: Option<A>,
+    mtimes: Vec<Option<SystemTime>>,
+    sighup_handler_installed: bool,
+}
+
+/// Current acceptor, shared by all `tls-accept:` overlays and replaced when certificates get reloaded
+pub struct TlsAcceptorSlot<A>(Rc<RefCell<TlsAcceptorSlotImpl<A>>>);
+
+impl<A> Default for TlsAcceptorSlot<A> {
+    fn default() -> Self {
+        TlsAcceptorSlot(Rc::new(RefCell::new(TlsAcceptorSlotImpl {
+            acceptor: None,
+            mtimes: vec![],
+            sighup_handler_installed: false,
+        })))
+    }
+}
+
+impl<A> Clone for TlsAcceptorSlot<A> {
+    fn clone(&self) -> Self {
+        TlsAcceptorSlot(self.0.clone())
+    }
+}
+
+impl<A: Clone + 'static> TlsAcceptorSlot<A> {
+    pub fn get(
+        &self,
+        progopt: &Rc<Options>,
+        build: AcceptorBuilder<A>,
+    ) -> Result<A, Box<dyn ::std::error::Error>> {
+        let mut s = self.0.borrow_mut();
+        if s.acceptor.is_none() {
+            s.mtimes = identity_mtimes(progopt);
+            s.acceptor = Some(build(progopt)?);
+        } else if identity_mtimes(progopt) != s.mtimes {
+            info!("TLS certificate files changed");
+            Self::reload(&mut s, progopt, build);
+        }
+        Ok(s.acceptor.clone().unwrap())
+    }
+
+    /// Reload certificates on SIGHUP. Should be called when the overlay gets constructed,
+    /// not on first connection, as the signal would terminate websocat until then.
+    pub fn reload_on_sighup(&self, progopt: &Rc<Options>, build: AcceptorBuilder<A>) {
+        let mut s = self.0.borrow_mut();
+        if !s.sighup_handler_installed {
+            s.sighup_handler_installed = true;
+            self.install_sighup_handler(progopt.clone(), build);
+        }
+    }
+
+    /// Re-read certificate files. Old acceptor stays in use if that fails.
+    fn reload(s: &mut TlsAcceptorSlotImpl<A>, progopt: &Options, build: AcceptorBuilder<A>) {
+        s.mtimes = identity_mtimes(progopt);
+        let r = (|| {
+            let mut o = progopt.clone();
+            if let Some(ref f) = o.pkcs12_der_file {
+                o.pkcs12_der = Some(::std::fs::read(f)?);
+            }
+            if let Some(ref f) = o.tls_cert_file {
+                o.tls_cert = Some(::std::fs::read(f)?);
+            }
+            if let Some(ref f) = o.tls_key_file {
+                o.tls_key = Some(::std::fs::read(f)?);
+            }
+            if let Some(ref f) = o.tls_client_ca_file {
+                o.tls_client_ca = Some(::std::fs::read(f)?);
+            }
+            for x in &mut o.tls_sni_cert {
+                x.cert = ::std::fs::read(&x.cert_file)?;
+                x.key = ::std::fs::read(&x.key_file)?;
+            }
+            build(&o)
+        })();
+        match r {
+            Ok(x) => {
+                info!("Reloaded TLS certificate");
+                s.acceptor = Some(x);
+            }
+            Err(e) => error!("Failed to reload TLS certificate, continuing to use the old one: {}", e),
+        }
+    }
+
+    #[cfg(all(unix, feature = "signal_handler"))]
+    fn install_sighup_handler(&self, progopt: Rc<Options>, build: AcceptorBuilder<A>) {
+        extern crate libc;
+        extern crate tokio_signal;
+        use crate::spawn_hack;
+        use futures::{Future, Stream};
+        let slot = self.clone();
+        let prog = tokio_signal::unix::Signal::new(libc::SIGHUP)
+            .flatten_stream()
+            .for_each(move |_| {
+                info!("Got SIGHUP, reloading TLS certificate");
+                Self::reload(&mut slot.0.borrow_mut(), &progopt, build);
+                Ok(())
+            })
+            .map_err(|e| error!("Failed to install SIGHUP handler: {}", e));
+        spawn_hack(prog);
+    }
+
+    #[cfg(not(all(unix, feature = "signal_handler")))]
+    fn install_sighup_handler(&self, _progopt: Rc<Options>, _build: AcceptorBuilder<A>) {}
+}
+
+#[test]
+fn test_sni_matches() {
+    assert!(sni_matches("example.com", "Example.COM"));
+    assert!(!sni_matches("example.com", "www.example.com"));
+    assert!(sni_matches("*.example.com", "www.example.com"));
+    assert!(!sni_matches("*.example.com", "example.com"));
+    assert!(!sni_matches("*.example.com", "a.b.example.com"));
+    assert!(sni_matches("Example.COM", "example.com"));
+    assert!(sni_matches("*.Example.com", "WWW.example.COM"));
+}
+
+#[test]
+fn test_acceptor_slot_reload() {
+    use std::time::Duration;
+    fn build(o: &Options) -> Result<Vec<u8>, Box<dyn ::std::error::Error>> {
+        Ok(o.tls_cert.clone().unwrap())
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("cert.pem");
+    ::std::fs::write(&path, b"old").unwrap();
+    let opts = Rc::new(Options {
+        tls_cert: Some(b"old".to_vec()),
+        tls_cert_file: Some(path.clone()),
+        ..Default::default()
+    });
+    let slot = TlsAcceptorSlot::default();
+    assert_eq!(slot.get(&opts, build).unwrap(), b"old");
+    assert_eq!(slot.get(&opts, build).unwrap(), b"old");
+
+    ::std::fs::write(&path, b"new").unwrap();
+    let f = File::options().write(true).open(&path).unwrap();
+    f.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+    assert_eq!(slot.get(&opts, build).unwrap(), b"new");
+}
@@ -0,0 +1,123 @@
+//! TLS overlays. Only the connector and acceptor differ between native-tls (`ssl` feature)
+//! and rustls (`rustls` feature) builds; those live in `ssl_peer`.
+
+use std::rc::Rc;
+
+use super::ssl_peer::{build_acceptor, ssl_accept, ssl_connect, Acceptor};
+use super::tls_common::TlsAcceptorSlot;
+use super::{ConstructParams, PeerConstructor, Specifier};
+
+#[derive(Debug)]
+pub struct TlsConnect<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for TlsConnect<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p, _l2r| ssl_connect(p, cp.program_options.tls_domain.clone(), &cp.program_options))
+    }
+    specifier_boilerplate!(noglobalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = TlsConnectClass,
+    target = TlsConnect,
+    prefixes = ["ssl-connect:","ssl-c:","ssl:","tls:","tls-connect:","tls-c:","c-ssl:","connect-ssl:","c-tls:","connect-tls:"],
+    arg_handling = subspec,
+    overlay = true,
+    StreamOriented,
+    MulticonnectnessDependsOnInnerType,
+    help = r#"
+Overlay to add TLS encryption atop of existing connection [A]
+
+Example: manually connect to a secure websocket
+
+    websocat -t - ws-c:tls-c:tcp:174.129.224.73:1080 --ws-c-uri ws://echo.websocket.org --tls-domain echo.websocket.org
+
+For a user-friendly solution, see --socks5 command-line option
+"#
+);
+
+#[derive(Debug)]
+pub struct TlsAccept<T: Specifier>(pub T);
+impl<T: Specifier> Specifier for TlsAccept<T> {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        let slot = cp.global(TlsAcceptorSlot::<Acceptor>::default).clone();
+        slot.reload_on_sighup(&cp.program_options, build_acceptor);
+        let inner = self.0.construct(cp.clone());
+        inner.map(move |p, l2r| ssl_accept(p, l2r, cp.program_options.clone(), &slot))
+    }
+    specifier_boilerplate!(globalstate has_subspec);
+    self_0_is_subspecifier!(proxy_is_multiconnect);
+}
+specifier_class!(
+    name = TlsAcceptClass,
+    target = TlsAccept,
+    prefixes = [
+        "ssl-accept:",
+        "ssl-a:",
+        "tls-a:",
+        "tls-accept:",
+        "a-ssl:",
+        "accept-ssl:",
+        "accept-tls:",
+        "accept-tls:"
+    ],
+    arg_handling = subspec,
+    overlay = true,
+    StreamOriented,
+    MulticonnectnessDependsOnInnerType,
+    help = r#"
+Accept an TLS connection using arbitrary backing stream. [A]
+
+Certificate files are re-read when they change (checked on each new connection)
+or when websocat receives SIGHUP. Established connections are not affected.
+
+Build with `rustls` feature accepts only PEM files (--tls-cert and --tls-key), not --pkcs12-der.
+
+Example: The same as in TlsListenClass's example, but with manual acceptor
+
+    websocat -E -b --pkcs12-der=q.pkcs12 tls-a:tcp-l:127.0.0.1:1234 mirror:
+"#
+);
+
+specifier_alias!(
+    name = TlsListenClass,
+    prefixes = [
+        "ssl-listen:",
+        "ssl-l:",
+        "tls-l:",
+        "tls-listen:",
+        "l-ssl:",
+        "listen-ssl:",
+        "listen-tls:",
+        "listen-tls:"
+    ],
+    alias = "tls-accept:tcp-l:",
+    help = r#"
+Listen for SSL connections on a TCP port
+
+Example: Non-websocket SSL echo server
+
+    websocat -E -b --pkcs12-der=q.pkcs12 ssl-listen:127.0.0.1:1234 mirror:
+    socat - ssl:127.0.0.1:1234,verify=0
+
+Example: the same, using PEM files (e.g. from Let's Encrypt)
+
+    websocat -E -b --tls-cert=fullchain.pem --tls-key=privkey.pem ssl-listen:127.0.0.1:1234 mirror:
+"#
+);
+
+specifier_alias!(
+    name = WssListenClass,
+    prefixes = ["wss-listen:", "wss-l:", "l-wss:", "wss-listen:"],
+    alias = "ws-u:tls-accept:tcp-l:",
+    help = r#"
+Listen for secure WebSocket connections on a TCP port
+
+Example: wss:// echo server + client for testing
+
+    websocat -E -t --pkcs12-der=q.pkcs12 wss-listen:127.0.0.1:1234 mirror:
+    websocat --ws-c-uri=wss://localhost/ -t - ws-c:cmd:'socat - ssl:127.0.0.1:1234,verify=0'
+
+See [moreexamples.md](./moreexamples.md) for info about generation of `q.pkcs12`.
+"#
+);
@@ -21,7 +21,7 @@ use self::tokio_uds::{UnixDatagram, UnixListener, UnixStream};
 #[allow(unused_imports)]
 use super::simple_err;
 use super::{box_up_err, peer_err_s, util::peer_err_sb, BoxedNewPeerFuture, BoxedNewPeerStream, Peer};
-use super::{multi, once, ConstructParams, Options, PeerConstructor, Specifier};
+use super::{multi, once, ConstructParams, L2rUser, Options, PeerConstructor, Specifier};
 
 #[derive(Debug, Clone)]
 pub struct UnixConnect(pub PathBuf);
@@ -58,7 +58,7 @@ Example: forward connections from websockets to a UNIX stream socket
 pub struct UnixListen(pub PathBuf);
 impl Specifier for UnixListen {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
-        multi(unix_listen_peer(&self.0, &p.program_options))
+        multi(unix_listen_peer(&self.0, p.left_to_right, &p.program_options))
     }
     specifier_boilerplate!(noglobalstate multiconnect no_subspec);
 }
@@ -103,7 +103,13 @@ websocat just as well - UNIX sockets are not a requirement for this feature.
 
 See `moreexamples.md` for SystemD usage (untested).
 
-TODO: --chmod option?
+Use `--chmod` and `--chown` to set access rights for the socket, or `--unix-allow-uid` and
+`--unix-allow-gid` to check credentials of connecting processes. Their user, group and process IDs
+are available to `exec:` as `WEBSOCAT_PEER_UID`, `WEBSOCAT_PEER_GID` and `WEBSOCAT_PEER_PID` with `-e`.
+
+Example: WebSocket bridge usable only by members of `wsusers` group
+
+    websocat --unlink --chmod 660 --chown :wsusers -E -b ws-u:unix-l:/run/ws.sock tcp:127.0.0.1:22
 "#
 );
 
@@ -196,6 +202,7 @@ impl Specifier for AbstractListen {
     fn construct(&self, cp: ConstructParams) -> PeerConstructor {
         multi(unix_listen_peer(
             &to_abstract(&self.0),
+            cp.left_to_right,
             &cp.program_options,
         ))
     }
@@ -346,7 +353,7 @@ pub fn unix_connect_peer(addr: &Path) -> BoxedNewPeerFuture {
     ) as BoxedNewPeerFuture
 }
 
-pub fn unix_listen_peer(addr: &Path, opts: &Rc<Options>) -> BoxedNewPeerStream {
+pub fn unix_listen_peer(addr: &Path, l2r: L2rUser, opts: &Rc<Options>) -> BoxedNewPeerStream {
     let bound = if opts.unix_socket_accept_from_fd {
         // Special mode for SystemD (untested yet)
         let fdnum: libc::c_int = match addr.to_str().map(|x|x.parse()) {
@@ -365,7 +372,15 @@ pub fn unix_listen_peer(addr: &Path, opts: &Rc<Options>) -> BoxedNewPeerStream {
         if opts.unlink_unix_socket {
             let _ = ::std::fs::remove_file(addr);
         };
+        // Keep the socket private until --chmod is applied
+        let old_umask = opts.unix_socket_mode.map(|_| unsafe { libc::umask(0o177) });
         let bound = UnixListener::bind(addr);
+        if let Some(m) = old_umask {
+            unsafe {
+                libc::umask(m);
+            }
+        }
+        let bound = bound.and_then(|b| set_unix_socket_perms(addr, opts).map(|()| b));
         if opts.announce_listens {
             let poss = addr.as_os_str();
             use std::os::unix::ffi::OsStrExt;
@@ -382,14 +397,107 @@ pub fn unix_listen_peer(addr: &Path, opts: &Rc<Options>) -> BoxedNewPeerStream {
         Err(e) => return peer_err_s(e),
     };
     debug!("UNIX listening socket should be ready");
-    // TODO: chmod
+    unix_accept_peers(bound, l2r, opts)
+}
+
+/// `--chmod` and `--chown` for just bound listening socket. Abstract sockets are left as is.
+pub fn set_unix_socket_perms(addr: &Path, opts: &Options) -> IoResult<()> {
+    use std::os::unix::ffi::OsStrExt;
+    if addr.as_os_str().as_bytes().first() == Some(&b'\0') {
+        return Ok(());
+    }
+    if let Some(mode) = opts.unix_socket_mode {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(addr, std::fs::Permissions::from_mode(mode))?;
+    }
+    if opts.unix_socket_owner.is_some() || opts.unix_socket_group.is_some() {
+        std::os::unix::fs::chown(addr, opts.unix_socket_owner, opts.unix_socket_group)?;
+    }
+    Ok(())
+}
+
+/// Credentials of the process on the other side of UNIX socket
+#[derive(Debug, Clone, Copy)]
+struct PeerCred {
+    uid: u32,
+    gid: u32,
+    pid: Option<i32>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_cred(s: &UnixStream) -> IoResult<PeerCred> {
+    use std::os::unix::io::AsRawFd;
+    let mut c = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            s.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut c as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(PeerCred {
+        uid: c.uid,
+        gid: c.gid,
+        pid: Some(c.pid),
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_cred(s: &UnixStream) -> IoResult<PeerCred> {
+    let c = s.peer_cred()?;
+    Ok(PeerCred {
+        uid: c.uid,
+        gid: c.gid,
+        pid: None,
+    })
+}
+
+/// Record credentials of incoming connection and check them against `--unix-allow-uid` and `--unix-allow-gid`
+pub fn admit_unix_peer(x: &UnixStream, l2r: &L2rUser, opts: &Options) -> bool {
+    let cred = match peer_cred(x) {
+        Ok(c) => {
+            info!("Incoming unix socket connection from uid={} gid={} pid={:?}", c.uid, c.gid, c.pid);
+            Some(c)
+        }
+        Err(e) => {
+            info!("Incoming unix socket connection, failed to get peer credentials: {}", e);
+            None
+        }
+    };
+    if !opts.unix_allow_uid.is_empty() || !opts.unix_allow_gid.is_empty() {
+        let allowed = cred.is_some_and(|c| {
+            opts.unix_allow_uid.contains(&c.uid) || opts.unix_allow_gid.contains(&c.gid)
+        });
+        if !allowed {
+            warn!("Rejecting unix socket connection: not permitted by --unix-allow-uid/--unix-allow-gid");
+            return false;
+        }
+    }
+    if let L2rUser::FillIn(ref y) = *l2r {
+        let mut z = y.borrow_mut();
+        z.peer_uid = cred.map(|c| c.uid);
+        z.peer_gid = cred.map(|c| c.gid);
+        z.peer_pid = cred.and_then(|c| c.pid);
+    }
+    true
+}
+
+/// Connections from already listening UNIX socket
+pub fn unix_accept_peers(bound: UnixListener, l2r: L2rUser, opts: &Rc<Options>) -> BoxedNewPeerStream {
     use tk_listen::ListenExt;
+    let opts = opts.clone();
     Box::new(
         bound
             .incoming()
             .sleep_on_error(::std::time::Duration::from_millis(500))
+            .filter(move |x| admit_unix_peer(x, &l2r, &opts))
             .map(|x| {
-                info!("Incoming unix socket connection");
                 let x = Rc::new(x);
                 Peer::new(
                     MyUnixStream(x.clone(), true),
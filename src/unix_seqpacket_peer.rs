@@ -1,9 +1,9 @@
 extern crate tokio_reactor;
 
 use super::{
-    futures, libc, multi, once, peer_err_s, simple_err, BoxedNewPeerFuture, BoxedNewPeerStream,
-    ConstructParams, MyUnixStream, Options, Peer, PeerConstructor, Specifier, UnixListener,
-    UnixStream,
+    admit_unix_peer, futures, libc, multi, once, peer_err_s, set_unix_socket_perms, simple_err,
+    BoxedNewPeerFuture, BoxedNewPeerStream, ConstructParams, L2rUser, MyUnixStream, Options, Peer,
+    PeerConstructor, Specifier, UnixListener, UnixStream,
 };
 use futures::Stream;
 use std::path::{Path, PathBuf};
@@ -48,7 +48,7 @@ Example: forward connections from websockets to a UNIX seqpacket abstract socket
 pub struct SeqpacketListen(pub PathBuf);
 impl Specifier for SeqpacketListen {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
-        multi(seqpacket_listen_peer(&self.0, &p.program_options))
+        multi(seqpacket_listen_peer(&self.0, p.left_to_right, &p.program_options))
     }
     specifier_boilerplate!(noglobalstate multiconnect no_subspec);
 }
@@ -132,7 +132,7 @@ pub fn seqpacket_connect_peer(addr: &Path) -> BoxedNewPeerFuture {
     Box::new(futures::future::result(getpeer(addr))) as BoxedNewPeerFuture
 }
 
-pub fn seqpacket_listen_peer(addr: &Path, opts: &Rc<Options>) -> BoxedNewPeerStream {
+pub fn seqpacket_listen_peer(addr: &Path, l2r: L2rUser, opts: &Rc<Options>) -> BoxedNewPeerStream {
     fn getfd(addr: &Path, opts: &Rc<Options>) -> Option<i32> {
         use self::libc::{
             bind, c_char, close, listen, sa_family_t, sockaddr_un, socket, socklen_t, unlink,
@@ -163,7 +163,12 @@ pub fn seqpacket_listen_peer(addr: &Path, opts: &Rc<Options>) -> BoxedNewPeerStr
                 }
                 let sa_len = l + size_of::<sa_family_t>();
                 let sa_ = &sa as *const self::libc::sockaddr_un as *const self::libc::sockaddr;
+                // Keep the socket private until --chmod is applied
+                let old_umask = opts.unix_socket_mode.map(|_| self::libc::umask(0o177));
                 let ret = bind(s, sa_, sa_len as socklen_t);
+                if let Some(m) = old_umask {
+                    self::libc::umask(m);
+                }
                 if ret == -1 {
                     close(s);
                     return None;
@@ -191,19 +196,28 @@ pub fn seqpacket_listen_peer(addr: &Path, opts: &Rc<Options>) -> BoxedNewPeerStr
         Some(x) => x,
         None => return peer_err_s(simple_err("Failed to get or bind socket".into())),
     };
+    if !addr.as_os_str().to_string_lossy().starts_with('@') {
+        if let Err(e) = set_unix_socket_perms(addr, opts) {
+            unsafe {
+                self::libc::close(fd);
+            }
+            return peer_err_s(e);
+        }
+    }
     let l1: ::std::os::unix::net::UnixListener =
         unsafe { ::std::os::unix::io::FromRawFd::from_raw_fd(fd) };
     let bound = match UnixListener::from_std(l1, &tokio_reactor::Handle::default()) {
         Ok(x) => x,
         Err(e) => return peer_err_s(e),
     };
+    let opts = opts.clone();
     use tk_listen::ListenExt;
     Box::new(
         bound
             .incoming()
             .sleep_on_error(::std::time::Duration::from_millis(500))
+            .filter(move |x| admit_unix_peer(x, &l2r, &opts))
             .map(|x| {
-                info!("Incoming unix socket connection");
                 let x = Rc::new(x);
                 Peer::new(
                     MyUnixStream(x.clone(), true),
@@ -3,6 +3,7 @@ use super::{
     PeerConstructor, Rc, HupToken,
 };
 use super::{Future, Stream};
+use std::io::Write;
 
 pub fn wouldblock<T>() -> std::io::Result<T> {
     Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, ""))
@@ -127,3 +128,42 @@ impl Peer {
         )
     }
 }
+
+/// Write half shared between reader and writer of a message-based protocol peer.
+/// Complete protocol messages are queued and sent when the underlying writer is ready,
+/// so replies generated by the reader (pongs, acks) don't interleave with partial writes.
+pub struct QueuedWriter {
+    pub w: Box<dyn AsyncWrite>,
+    pub queue: std::collections::VecDeque<Vec<u8>>,
+}
+
+impl QueuedWriter {
+    pub fn new(w: Box<dyn AsyncWrite>) -> Self {
+        QueuedWriter {
+            w,
+            queue: std::collections::VecDeque::new(),
+        }
+    }
+
+    /// Try to send all queued messages
+    pub fn flush_queue(&mut self) -> std::io::Result<()> {
+        while let Some(msg) = self.queue.front() {
+            let n = self.w.write(msg)?;
+            if n < msg.len() {
+                let rest = msg[n..].to_vec();
+                self.queue[0] = rest;
+            } else {
+                self.queue.pop_front();
+            }
+        }
+        self.w.flush()
+    }
+
+    /// Same as `flush_queue`, but leaving something in the queue for later is not an error
+    pub fn flush_ignoring_wouldblock(&mut self) -> std::io::Result<()> {
+        match self.flush_queue() {
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(()),
+            x => x,
+        }
+    }
+}
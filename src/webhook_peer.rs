@@ -0,0 +1,423 @@
+extern crate http_bytes;
+
+use futures::future::{ok, loop_fn, Loop};
+use futures::stream::FuturesOrdered;
+use futures::task::Task;
+use futures::{Async, Future, Stream};
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use super::{box_up_err, BoxedNewPeerFuture, Peer};
+use super::{once, wouldblock, ConstructParams, PeerConstructor, Specifier};
+
+use std::io::{Error as IoError, Read, Write};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use http_bytes::http;
+use http::header::{HeaderName, HeaderValue};
+
+#[derive(Debug)]
+pub struct Webhook(pub Rc<dyn Specifier>);
+impl Specifier for Webhook {
+    fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        once(webhook_peer(self.0.clone(), cp))
+    }
+    specifier_boilerplate!(singleconnect noglobalstate has_subspec);
+    self_0_is_subspecifier!(...);
+}
+specifier_class!(
+    name = WebhookClass,
+    target = Webhook,
+    prefixes = ["webhook:"],
+    arg_handling = subspec,
+    overlay = true,
+    MessageOriented,
+    SingleConnect,
+    help = r#"
+[A] Issue a separate HTTP request for each written message, using inner
+specifier to connect to the HTTP server each time. Response bodies become
+messages that can be read back, in the same order as requests.
+
+Request line and headers are specified by `--webhook-uri`, `--webhook-method`,
+`--webhook-header` and `--webhook-content-type`. They are templates:
+`{msg}` is replaced with the message (percent-encoded in URI, trailing newline removed)
+and `{seq}` is replaced with sequence number of the message, starting from 1.
+Message itself becomes the request body unless method is GET or HEAD.
+
+Up to `--webhook-concurrency` requests are in flight simultaneously.
+Requests that fail, time out (`--webhook-timeout-millis`) or get 5xx response are retried
+`--webhook-retries` times with exponential backoff starting from `--webhook-retry-delay-millis`.
+Use `--webhook-status` to get replies like `{"status":200,"body":"..."}`.
+A request that fails after all retries gives `{"status":0,"error":"..."}` reply with `--webhook-status`
+and ends the session with error without it, so that replies never get paired with wrong messages.
+
+Example: turn each incoming WebSocket message into a POST request and reply with response body:
+
+    websocat -t ws-l:127.0.0.1:8080 webhook:tcp:127.0.0.1:80 --webhook-uri http://127.0.0.1/hook?n={seq}
+"#
+);
+
+type ReplyFuture = Box<dyn Future<Item = Vec<u8>, Error = Box<dyn std::error::Error>>>;
+type HttpFuture = Box<dyn Future<Item = (u16, Vec<u8>), Error = Box<dyn std::error::Error>>>;
+
+struct State {
+    s: Rc<dyn Specifier>,
+    cp: ConstructParams,
+    seq: u64,
+    inflight: FuturesOrdered<ReplyFuture>,
+    replies: VecDeque<Vec<u8>>,
+    /// Remainder of a reply that did not fit into reader's buffer
+    debt: Vec<u8>,
+    reader_task: Option<Task>,
+    writer_task: Option<Task>,
+    driver_task: Option<Task>,
+    closed: bool,
+    /// A request failed after all retries, later replies are not delivered
+    failed: Option<String>,
+}
+
+impl State {
+    fn wake_reader(&mut self) {
+        if let Some(t) = self.reader_task.take() {
+            t.notify();
+        }
+    }
+
+    fn build_request(&mut self, msg: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let o = &self.cp.program_options;
+        self.seq += 1;
+        let seq = self.seq;
+        let method = o.webhook_method.as_ref().map(|x| &x[..]).unwrap_or("POST");
+        let method = expand_template(method, msg, seq, false);
+        let uri = o.webhook_uri.as_ref().map(|x| &x[..]).unwrap_or("/");
+        let uri = expand_template(uri, msg, seq, true);
+
+        let mut b = http::request::Builder::default();
+        b.method(&method[..]);
+        b.uri(&uri[..]);
+        for (hn, hv) in &o.request_headers {
+            b.header(hn, hv);
+        }
+        for h in &o.webhook_headers {
+            let colon = h.find(':').ok_or("Argument to --webhook-header must contain `:` character")?;
+            let hv = expand_template(h[colon + 1..].trim_start(), msg, seq, false);
+            b.header(HeaderName::from_bytes(&h.as_bytes()[..colon])?, HeaderValue::from_str(&hv)?);
+        }
+        let body: &[u8] = if method == "GET" || method == "HEAD" { b"" } else { msg };
+        if !body.is_empty() {
+            let ct = o.webhook_content_type.as_ref().map(|x| &x[..]).unwrap_or("application/octet-stream");
+            let ct = expand_template(ct, msg, seq, false);
+            b.header(http::header::CONTENT_TYPE, HeaderValue::from_str(&ct)?);
+        }
+        b.header(http::header::CONTENT_LENGTH, body.len());
+        b.header(http::header::CONNECTION, "close");
+        let request = b.body(())?;
+        debug!("Webhook request {}: {} {}", seq, method, uri);
+        let mut v = http_bytes::request_header_to_vec(&request);
+        v.extend_from_slice(body);
+        Ok(v)
+    }
+}
+
+/// Spawned task that drives in-flight requests regardless of whether the peer is being read or written
+struct Driver(Rc<RefCell<State>>);
+
+impl Future for Driver {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> futures::Poll<(), ()> {
+        let mut st = self.0.borrow_mut();
+        loop {
+            match st.inflight.poll() {
+                Ok(Async::Ready(Some(reply))) => {
+                    // Empty messages would look like EOF, so skip empty response bodies
+                    if !reply.is_empty() && st.failed.is_none() {
+                        st.replies.push_back(reply);
+                        st.wake_reader();
+                    }
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    if st.failed.is_none() {
+                        st.failed = Some(e.to_string());
+                    }
+                    st.wake_reader();
+                }
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+            }
+            if let Some(t) = st.writer_task.take() {
+                t.notify();
+            }
+        }
+        if st.closed && st.inflight.is_empty() {
+            st.wake_reader();
+            return Ok(Async::Ready(()));
+        }
+        st.driver_task = Some(futures::task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+fn expand_template(t: &str, msg: &[u8], seq: u64, in_uri: bool) -> String {
+    if !t.contains('{') {
+        return t.to_owned();
+    }
+    let mut msg = msg;
+    while msg.ends_with(b"\n") || msg.ends_with(b"\r") {
+        msg = &msg[..msg.len() - 1];
+    }
+    let msg = if in_uri {
+        let mut s = String::with_capacity(msg.len());
+        for &c in msg {
+            if c.is_ascii_alphanumeric() || b"-._~".contains(&c) {
+                s.push(c as char);
+            } else {
+                s.push_str(&format!("%{:02X}", c));
+            }
+        }
+        s
+    } else {
+        String::from_utf8_lossy(msg).into_owned()
+    };
+    t.replace("{msg}", &msg).replace("{seq}", &format!("{}", seq))
+}
+
+/// Parse complete `Connection: close` HTTP response into status code and body
+fn parse_response(buf: &[u8]) -> Result<(u16, Vec<u8>), Box<dyn std::error::Error>> {
+    let headend = buf
+        .windows(4)
+        .position(|x| x == b"\r\n\r\n")
+        .ok_or("Trimmed HTTP head")?
+        + 4;
+    let (response, _) = http_bytes::parse_response_header_easy(&buf[..headend])?
+        .ok_or("Something wrong with response HTTP head")?;
+    let mut body = &buf[headend..];
+    let hdrs = response.headers();
+    let chunked = hdrs
+        .get(http::header::TRANSFER_ENCODING)
+        .map(|x| x.as_bytes().eq_ignore_ascii_case(b"chunked"))
+        .unwrap_or(false);
+    if chunked {
+        return Ok((response.status().as_u16(), dechunk(body)?));
+    }
+    if let Some(cl) = hdrs.get(http::header::CONTENT_LENGTH) {
+        let cl: usize = cl.to_str()?.trim().parse()?;
+        if cl < body.len() {
+            body = &body[..cl];
+        }
+    }
+    Ok((response.status().as_u16(), body.to_vec()))
+}
+
+fn dechunk(mut buf: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut ret = vec![];
+    loop {
+        let eol = buf.windows(2).position(|x| x == b"\r\n").ok_or("Trimmed chunked body")?;
+        let sizeline = std::str::from_utf8(&buf[..eol])?;
+        let sizeline = sizeline.split(';').next().unwrap().trim();
+        let size = usize::from_str_radix(sizeline, 16)?;
+        buf = &buf[eol + 2..];
+        if size == 0 {
+            return Ok(ret);
+        }
+        if buf.len() < size {
+            Err("Trimmed chunked body")?;
+        }
+        ret.extend_from_slice(&buf[..size]);
+        buf = &buf[size..];
+        if buf.starts_with(b"\r\n") {
+            buf = &buf[2..];
+        }
+    }
+}
+
+fn one_request(s: &Rc<dyn Specifier>, cp: &ConstructParams, request: Vec<u8>) -> HttpFuture {
+    let timeout = std::time::Duration::from_millis(cp.program_options.webhook_timeout_millis);
+    let l2r = cp.left_to_right.clone();
+    let f = s
+        .construct(cp.clone())
+        .get_only_first_conn(l2r)
+        .and_then(move |p| {
+            let (r, w, _hup) = (p.0, p.1, p.2);
+            ::tokio_io::io::write_all(w, request)
+                .and_then(move |(w, _)| ::tokio_io::io::read_to_end(r, vec![]).map(move |x| (w, x)))
+                .map_err(box_up_err)
+        })
+        .and_then(|(_w, (_r, buf))| futures::future::result(parse_response(&buf)));
+    let f = tokio_timer::Timeout::new(f, timeout)
+        .map_err(|e| e.into_inner().unwrap_or_else(|| "timed out".into()));
+    Box::new(f) as HttpFuture
+}
+
+fn request_with_retries(s: Rc<dyn Specifier>, cp: ConstructParams, request: Vec<u8>, seq: u64) -> ReplyFuture {
+    let retries = cp.program_options.webhook_retries;
+    let delay = cp.program_options.webhook_retry_delay_millis;
+    let wrap_status = cp.program_options.webhook_status;
+    let f = loop_fn(0usize, move |attempt| {
+        one_request(&s, &cp, request.clone()).then(move |res| {
+            let failure = match res {
+                Ok((status, _)) if status >= 500 => format!("status {}", status),
+                Err(ref e) => format!("{}", e),
+                Ok(x) => return Box::new(ok(Loop::Break(x))) as HttpLoopFuture,
+            };
+            if attempt >= retries {
+                return Box::new(futures::future::result(res.map(Loop::Break))) as HttpLoopFuture;
+            }
+            let d = delay.saturating_mul(1 << attempt.min(16));
+            warn!("Webhook request {} failed ({}), retrying in {} ms", seq, failure, d);
+            let t = tokio_timer::Delay::new(std::time::Instant::now() + std::time::Duration::from_millis(d));
+            Box::new(t.map_err(box_up_err).map(move |()| Loop::Continue(attempt + 1))) as HttpLoopFuture
+        })
+    });
+    let f = f.then(move |res| match res {
+        Ok((status, body)) => {
+            info!("Webhook request {} finished with status {}", seq, status);
+            if wrap_status {
+                let j = serde_json::json!({
+                    "status": status,
+                    "body": String::from_utf8_lossy(&body),
+                });
+                Ok(j.to_string().into_bytes())
+            } else {
+                Ok(body)
+            }
+        }
+        Err(e) if wrap_status => {
+            error!("Webhook request {} failed: {}", seq, e);
+            let j = serde_json::json!({
+                "status": 0,
+                "error": e.to_string(),
+            });
+            Ok(j.to_string().into_bytes())
+        }
+        Err(e) => Err(format!("Webhook request {} failed: {}", seq, e).into()),
+    });
+    Box::new(f) as ReplyFuture
+}
+type HttpLoopFuture = Box<dyn Future<Item = Loop<(u16, Vec<u8>), usize>, Error = Box<dyn std::error::Error>>>;
+
+struct WebhookReader(Rc<RefCell<State>>);
+
+impl Read for WebhookReader {
+    fn read(&mut self, b: &mut [u8]) -> Result<usize, IoError> {
+        let mut st = self.0.borrow_mut();
+        if st.debt.is_empty() {
+            if let Some(reply) = st.replies.pop_front() {
+                st.debt = reply;
+            }
+        }
+        if !st.debt.is_empty() {
+            let l = b.len().min(st.debt.len());
+            b[..l].copy_from_slice(&st.debt[..l]);
+            st.debt.drain(..l);
+            return Ok(l);
+        }
+        if let Some(ref e) = st.failed {
+            return Err(IoError::other(e.clone()));
+        }
+        if st.closed && st.inflight.is_empty() {
+            return Ok(0);
+        }
+        st.reader_task = Some(futures::task::current());
+        wouldblock()
+    }
+}
+impl AsyncRead for WebhookReader {}
+
+struct WebhookWriter(Rc<RefCell<State>>);
+
+impl WebhookWriter {
+    fn close(&mut self) {
+        let mut st = self.0.borrow_mut();
+        st.closed = true;
+        st.wake_reader();
+        if let Some(t) = st.driver_task.take() {
+            t.notify();
+        }
+    }
+}
+
+impl Write for WebhookWriter {
+    fn write(&mut self, b: &[u8]) -> Result<usize, IoError> {
+        let mut st = self.0.borrow_mut();
+        if st.failed.is_some() {
+            return Err(IoError::new(std::io::ErrorKind::BrokenPipe, "Webhook request failed"));
+        }
+        if st.inflight.len() >= st.cp.program_options.webhook_concurrency.max(1) {
+            st.writer_task = Some(futures::task::current());
+            return wouldblock();
+        }
+        match st.build_request(b) {
+            Ok(request) => {
+                let f = request_with_retries(st.s.clone(), st.cp.clone(), request, st.seq);
+                st.inflight.push(f);
+                if let Some(t) = st.driver_task.take() {
+                    t.notify();
+                }
+            }
+            Err(e) => error!("Failed to make webhook request: {}", e),
+        }
+        Ok(b.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+impl AsyncWrite for WebhookWriter {
+    /// Finishes after all in-flight requests are completed
+    fn shutdown(&mut self) -> futures::Poll<(), IoError> {
+        self.close();
+        let mut st = self.0.borrow_mut();
+        if !st.inflight.is_empty() {
+            st.writer_task = Some(futures::task::current());
+            return Ok(Async::NotReady);
+        }
+        Ok(Async::Ready(()))
+    }
+}
+impl Drop for WebhookWriter {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+pub fn webhook_peer(s: Rc<dyn Specifier>, cp: ConstructParams) -> BoxedNewPeerFuture {
+    let st = Rc::new(RefCell::new(State {
+        s,
+        cp,
+        seq: 0,
+        inflight: FuturesOrdered::new(),
+        replies: VecDeque::new(),
+        debt: vec![],
+        reader_task: None,
+        writer_task: None,
+        driver_task: None,
+        closed: false,
+        failed: None,
+    }));
+    Box::new(futures::future::lazy(move || {
+        ::tokio_current_thread::spawn(Driver(st.clone()));
+        Ok(Peer::new(WebhookReader(st.clone()), WebhookWriter(st), None))
+    })) as BoxedNewPeerFuture
+}
+
+#[test]
+fn test_webhook_helpers() {
+    assert_eq!(expand_template("/hook", b"a b", 1, true), "/hook");
+    assert_eq!(expand_template("/hook?m={msg}&n={seq}", b"a b/\xC3\xA9\n", 7, true), "/hook?m=a%20b%2F%C3%A9&n=7");
+    assert_eq!(expand_template("text/{msg}", b"plain\r\n", 2, false), "text/plain");
+
+    let (status, body) = parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello, extra").unwrap();
+    assert_eq!((status, &body[..]), (200, &b"hello"[..]));
+    let (status, body) = parse_response(b"HTTP/1.1 404 Not Found\r\n\r\nno").unwrap();
+    assert_eq!((status, &body[..]), (404, &b"no"[..]));
+    let r = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;x=y\r\nde\r\n0\r\n\r\n";
+    assert_eq!(parse_response(r).unwrap().1, b"abcde");
+    assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+
+    assert_eq!(dechunk(b"A\r\n0123456789\r\n0\r\n\r\n").unwrap(), b"0123456789");
+    assert!(dechunk(b"5\r\nabc").is_err());
+    assert!(dechunk(b"zz\r\n").is_err());
+}
@@ -6,13 +6,13 @@ use self::websocket::stream::r#async::Stream as WsStream;
 use self::websocket::ClientBuilder;
 use futures::future::Future;
 
-use std::net::SocketAddr;
 use std::rc::Rc;
 
 use self::websocket::client::Url;
 
 use super::{box_up_err, peer_err, peer_strerr, BoxedNewPeerFuture, Peer, Result};
 
+use super::net_peer::{DnsCache, HostPort};
 use super::ws_peer::PeerForWs;
 use super::{once, ConstructParams, Options, PeerConstructor, Specifier};
 
@@ -23,9 +23,10 @@ pub struct WsClient(pub Url);
 impl Specifier for WsClient {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
         let url = self.0.clone();
-        once(get_ws_client_peer(&url, p.program_options))
+        let dns = p.global(DnsCache::default).clone();
+        once(get_ws_client_peer(&url, p.program_options, &dns))
     }
-    specifier_boilerplate!(noglobalstate singleconnect no_subspec);
+    specifier_boilerplate!(globalstate singleconnect no_subspec);
 }
 specifier_class!(
     name = WsClientClass,
@@ -54,18 +55,19 @@ Example: connect to public WebSocket loopback and copy binary chunks from stdin
 "#
 );
 
-#[cfg(feature = "ssl")]
+#[cfg(any(feature = "ssl", feature = "rustls"))]
 #[derive(Debug, Clone)]
 pub struct WsClientSecure(pub Url);
-#[cfg(feature = "ssl")]
+#[cfg(any(feature = "ssl", feature = "rustls"))]
 impl Specifier for WsClientSecure {
     fn construct(&self, p: ConstructParams) -> PeerConstructor {
         let url = self.0.clone();
-        once(get_ws_client_peer(&url, p.program_options))
+        let dns = p.global(DnsCache::default).clone();
+        once(get_ws_client_peer(&url, p.program_options, &dns))
     }
-    specifier_boilerplate!(noglobalstate singleconnect no_subspec);
+    specifier_boilerplate!(globalstate singleconnect no_subspec);
 }
-#[cfg(feature = "ssl")]
+#[cfg(any(feature = "ssl", feature = "rustls"))]
 specifier_class!(
     name = WsClientSecureClass,
     target = WsClientSecure,
@@ -180,64 +182,66 @@ where
     ) as BoxedNewPeerFuture
 }
 
-pub fn get_ws_client_peer(uri: &Url, opts: Rc<Options>) -> BoxedNewPeerFuture {
+pub fn get_ws_client_peer(uri: &Url, opts: Rc<Options>, dns: &DnsCache) -> BoxedNewPeerFuture {
     info!("get_ws_client_peer");
 
-    #[allow(unused)]
-    let tls_insecure = opts.tls_insecure;
-    #[allow(unused)]
-    let client_ident = opts.client_pkcs12_der.clone();
-    #[allow(unused)]
-    let client_ident_passwd = opts.client_pkcs12_passwd.clone();
+    #[cfg(any(feature = "ssl", feature = "rustls"))]
+    {
+        if uri.scheme() == "wss" {
+            return get_wss_client_peer(uri, opts, dns);
+        }
+    }
 
-    get_ws_client_peer_impl(uri, opts, |before_connect| {
-        #[cfg(feature = "ssl")]
-        let mut builder_ = super::ssl_peer::native_tls::TlsConnector::builder();
-        #[cfg(feature = "ssl")]
-        let builder = builder_
-            .danger_accept_invalid_certs(tls_insecure)
-            .danger_accept_invalid_hostnames(tls_insecure);
-
-        #[cfg(feature = "ssl")]
-        let after_connect = {
-            let identity = if let Some(client_ident) = client_ident {
-                super::ssl_peer::native_tls::Identity::from_pkcs12(
-                    &client_ident,
-                    &client_ident_passwd.unwrap_or("".to_string()),
-                )
-                .map_err(|e| {
-                    error!(
-                        "Unable to parse client identity: {}\nContinuing without a client identity",
-                        e
-                    )
-                })
-                .ok()
-            } else {
-                None
-            };
-
-            let tls_opts = if let Some(client_ident) = identity {
-                debug!("Adding client identity to the TLS connection");
-                Some(builder.identity(client_ident).build()?)
-            } else {
-                Some(builder.build()?)
-            };
-            before_connect.async_connect_with_cb(tls_opts, |addrs| {
-                let addrs : Vec<SocketAddr> = addrs.collect();
-                let r = crate::net_peer::tcp_race(&addrs);
-                let r = r.map_err(|e|{
-                    websocket::WebSocketError::Other(e)
-                });
-                Box::new(r)
-            })
-        };
-        /// FIXME: happy eyeballs without TLS support
-        #[cfg(not(feature = "ssl"))]
-        let after_connect = before_connect.async_connect_insecure();
-        Ok(after_connect)
+    let hp = match uri_host_port(uri, 80) {
+        Ok(x) => x,
+        Err(e) => return e,
+    };
+    let uri = uri.clone();
+    Box::new(
+        crate::net_peer::resolve(&hp, dns, &opts)
+            .and_then(move |addrs| {
+                crate::net_peer::tcp_connect_peer(&addrs, &opts)
+                    .and_then(move |p| get_ws_client_peer_wrapped(&uri, p, opts))
+            }),
+    )
+}
+
+/// Host and port to make TCP connection to for `ws://` or `wss://` URL
+fn uri_host_port(uri: &Url, default_port: u16) -> std::result::Result<HostPort, BoxedNewPeerFuture> {
+    use self::websocket::url::Host;
+    let host = match uri.host() {
+        Some(Host::Domain(x)) => x.to_owned(),
+        Some(Host::Ipv4(x)) => x.to_string(),
+        Some(Host::Ipv6(x)) => x.to_string(),
+        None => return Err(peer_strerr("WebSocket URL has no host")),
+    };
+    Ok(HostPort {
+        host,
+        port: uri.port_or_known_default().unwrap_or(default_port),
     })
 }
 
+/// Do TCP and TLS ourselves, then use `ws-c:`-like path,
+/// so TLS options work the same way as for `tls-connect:` overlay
+#[cfg(any(feature = "ssl", feature = "rustls"))]
+fn get_wss_client_peer(uri: &Url, opts: Rc<Options>, dns: &DnsCache) -> BoxedNewPeerFuture {
+    let hp = match uri_host_port(uri, 443) {
+        Ok(x) => x,
+        Err(e) => return e,
+    };
+    let dom = hp.host.clone();
+    let uri = uri.clone();
+    let opts2 = opts.clone();
+    Box::new(
+        crate::net_peer::resolve(&hp, dns, &opts)
+            .and_then(move |addrs| crate::net_peer::tcp_connect_peer(&addrs, &opts2))
+            .and_then(move |p| {
+                super::ssl_peer::ssl_connect(p, Some(dom), &opts)
+                    .and_then(move |p| get_ws_client_peer_wrapped(&uri, p, opts))
+            }),
+    )
+}
+
 unsafe impl Send for PeerForWs {
     //! https://github.com/cyderize/rust-websocket/issues/168
 }
@@ -1,27 +1,38 @@
 extern crate hyper;
 extern crate websocket;
 
+use self::hyper::status::StatusCode;
 use self::hyper::uri::RequestUri::AbsolutePath;
 
 use self::websocket::WebSocketError;
 use futures::future::{err, Future};
+use futures::Sink;
 
+use std::net::{IpAddr, SocketAddr};
 use std::rc::Rc;
 
 use crate::options::StaticFile;
 
-use self::websocket::server::upgrade::r#async::IntoWs;
+use self::websocket::server::upgrade::r#async::{IntoWs, Upgrade};
 
+use super::ip_filter::IpFilter;
 use super::ws_peer::{PeerForWs};
 use super::{box_up_err, io_other_error, BoxedNewPeerFuture, Peer};
 use super::{ConstructParams, L2rUser, PeerConstructor, Specifier};
 
+/// Set when a WebSocket upgrader is constructed, so that connections over limits
+/// get an HTTP error reply from it instead of being dropped right away
+#[derive(Default)]
+pub struct UpgraderPresent(pub bool);
+
 #[derive(Debug)]
 pub struct WsServer<T: Specifier>(pub T);
 impl<T: Specifier> Specifier for WsServer<T> {
     fn construct(&self, cp: ConstructParams) -> PeerConstructor {
+        cp.global(UpgraderPresent::default).0 = true;
         let restrict_uri = Rc::new(cp.program_options.restrict_uri.clone());
         let serve_static_files = Rc::new(cp.program_options.serve_static_files.clone());
+        let filter = IpFilter::new(&cp);
         let inner = self.0.construct(cp.clone());
         //let l2r = cp.left_to_right;
         inner.map(move |p, l2r| {
@@ -34,6 +45,7 @@ impl<T: Specifier> Specifier for WsServer<T> {
                 cp.program_options.custom_reply_headers.clone(),
                 cp.program_options.clone(),
                 l2r,
+                filter.clone(),
             )
         })
     }
@@ -108,6 +120,23 @@ WebSocket abstract-namespaced UNIX socket server. [A]
 #[path = "http_serve.rs"]
 pub mod http_serve;
 
+/// Like `Upgrade::reject`, but with the given status code instead of 400 Bad Request
+fn reject_with_status(
+    x: Upgrade<PeerForWs>,
+    status: StatusCode,
+) -> impl Future<Item = (), Error = std::io::Error> {
+    let mut parts = tokio_codec::FramedParts::new(x.stream, websocket::r#async::HttpServerCodec);
+    parts.read_buf = x.buffer;
+    tokio_codec::Framed::from_parts(parts)
+        .send(hyper::http::h1::Incoming {
+            version: x.request.version,
+            subject: status,
+            headers: x.headers,
+        })
+        .map(|_| ())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn ws_upgrade_peer(
     inner_peer: Peer,
     restrict_uri: Rc<Option<String>>,
@@ -116,7 +145,21 @@ pub fn ws_upgrade_peer(
     custom_reply_headers: Vec<(String, Vec<u8>)>,
     opts: Rc<super::Options>,
     l2r: L2rUser,
+    filter: IpFilter,
 ) -> BoxedNewPeerFuture {
+    // Trusted reverse proxy, let through by the listener. Actual client is in `X-Forwarded-For`.
+    let via_proxy = match l2r {
+        L2rUser::FillIn(ref y) => y.borrow().client_addr.as_ref().and_then(|a| a.parse::<SocketAddr>().ok()),
+        L2rUser::ReadFrom(_) => None,
+    }
+    .map(|a| a.ip())
+    .filter(|ip| filter.is_active() && filter.is_trusted_proxy(*ip));
+    let reject_status = match l2r {
+        L2rUser::FillIn(ref y) => y.borrow().reject_status,
+        L2rUser::ReadFrom(_) => None,
+    };
+    let mut inner_peer = inner_peer;
+    let hup = inner_peer.2.take();
     let step1 = PeerForWs(inner_peer);
     let step2: Box<
         dyn Future<Item = self::websocket::server::upgrade::r#async::Upgrade<_>, Error = _>,
@@ -132,6 +175,44 @@ pub fn ws_upgrade_peer(
             move |mut x| -> Box<dyn Future<Item = Peer, Error = websocket::WebSocketError>> {
                 info!("Incoming connection to websocket: {}", x.request.subject.1);
 
+                if let Some(status) = reject_status {
+                    return Box::new(
+                        reject_with_status(x, StatusCode::from_u16(status))
+                            .and_then(|_| {
+                                ::futures::future::err(crate::util::simple_err(
+                                    "Rejected WebSocket connection because of connection limits"
+                                        .to_string(),
+                                ))
+                            })
+                            .map_err(|e| websocket::WebSocketError::IoError(io_other_error(e))),
+                    )
+                        as Box<dyn Future<Item = Peer, Error = websocket::WebSocketError>>;
+                }
+
+                if let Some(proxy) = via_proxy {
+                    let client: Option<IpAddr> = match x.request.headers.get_raw("X-Forwarded-For") {
+                        Some(v) => {
+                            let v: Vec<_> = v.iter().map(|l| String::from_utf8_lossy(l)).collect();
+                            filter.forwarded_client(&v.join(","))
+                        }
+                        None => Some(proxy),
+                    };
+                    debug!("Client address {:?} via trusted proxy {}", client, proxy);
+                    if !filter.check(client) {
+                        return Box::new(
+                            reject_with_status(x, StatusCode::Forbidden)
+                                .and_then(|_| {
+                                    ::futures::future::err(crate::util::simple_err(
+                                        "Client address is not permitted by --allow-ip/--deny-ip"
+                                            .to_string(),
+                                    ))
+                                })
+                                .map_err(|e| websocket::WebSocketError::IoError(io_other_error(e))),
+                        )
+                            as Box<dyn Future<Item = Peer, Error = websocket::WebSocketError>>;
+                    }
+                }
+
                 use ::websocket::header::WebSocketProtocol;
 
                 let mut protocol_check = true;
@@ -248,7 +329,7 @@ pub fn ws_upgrade_peer(
                     debug!("{:?}", headers);
                     info!("Upgraded");
                     let close_on_shutdown =  !opts.websocket_dont_close;
-                    super::ws_peer::finish_building_ws_peer(&opts, y, close_on_shutdown, None)
+                    super::ws_peer::finish_building_ws_peer(&opts, y, close_on_shutdown, hup)
                 })) as Box<dyn Future<Item = Peer, Error = websocket::WebSocketError>>
             },
         );
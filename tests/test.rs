@@ -2,6 +2,8 @@ extern crate websocat;
 
 extern crate env_logger;
 extern crate futures;
+#[cfg(unix)]
+extern crate net2;
 extern crate tokio;
 extern crate tokio_timer;
 
@@ -242,3 +244,40 @@ fn abstract_() {
     let prog = prog1.join(prog2);
     run!(core, prog);
 }
+
+#[test]
+#[cfg(unix)]
+fn tcp_reset_ends_unidirectional_session() {
+    use net2::TcpStreamExt;
+    prepare!(core);
+    let idle = std::net::TcpListener::bind("127.0.0.1:45916").unwrap();
+    let resetting = std::net::TcpListener::bind("127.0.0.1:45917").unwrap();
+    let t = std::thread::spawn(move || {
+        let (_a, _) = idle.accept().unwrap();
+        let (b, _) = resetting.accept().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        TcpStreamExt::set_linger(&b, Some(std::time::Duration::from_secs(0))).unwrap();
+        drop(b);
+        // Keep the first connection open, so only the reset can end the session
+        std::thread::sleep(std::time::Duration::from_secs(3));
+    });
+    let prog = wt!(
+        core,
+        "tcp:127.0.0.1:45916",
+        "tcp:127.0.0.1:45917",
+        nodelay,
+        opts = Options {
+            unidirectional: true,
+            ..dflt()
+        },
+        errignore,
+    );
+    let prog = tokio_timer::Timeout::new(prog, std::time::Duration::from_secs(2))
+        .then(|r| match r {
+            Err(ref e) if e.is_elapsed() => panic!("session was not ended by TCP reset"),
+            // Session fails with "Connection reset by peer"
+            _ => Ok(()),
+        });
+    run!(core, prog);
+    t.join().unwrap();
+}